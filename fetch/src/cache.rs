@@ -1,4 +1,11 @@
-use std::io::prelude::Read;
+use std::{
+    fmt::Write as _,
+    io::prelude::Read,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
 
 use bytes::Bytes;
 use rusqlite::{Connection, OptionalExtension, Result, blob::Blob};
@@ -16,6 +23,12 @@ pub enum MediaType {
     Gif = 6,
     Webp = 7,
     Html = 8,
+    Text = 9,
+    Avif = 10,
+    Woff = 11,
+    Woff2 = 12,
+    Ttf = 13,
+    Otf = 14,
 }
 
 impl MediaType {
@@ -26,7 +39,25 @@ impl MediaType {
     pub fn is_image(self) -> bool {
         matches!(
             self,
-            MediaType::Png | MediaType::Jpg | MediaType::Svg | MediaType::Gif | MediaType::Webp
+            MediaType::Png
+                | MediaType::Jpg
+                | MediaType::Svg
+                | MediaType::Gif
+                | MediaType::Webp
+                | MediaType::Avif
+        )
+    }
+
+    /// whether the content is text that has a character encoding
+    pub fn is_text(self) -> bool {
+        matches!(
+            self,
+            MediaType::Xhtml
+                | MediaType::Xml
+                | MediaType::Svg
+                | MediaType::Css
+                | MediaType::Html
+                | MediaType::Text
         )
     }
 
@@ -41,6 +72,12 @@ impl MediaType {
             6 => Self::Gif,
             7 => Self::Webp,
             8 => Self::Html,
+            9 => Self::Text,
+            10 => Self::Avif,
+            11 => Self::Woff,
+            12 => Self::Woff2,
+            13 => Self::Ttf,
+            14 => Self::Otf,
             _ => return None,
         };
         if id != ret as i32 {
@@ -49,18 +86,85 @@ impl MediaType {
         Some(ret)
     }
 
-    pub fn from_mime(s: &str) -> Self {
-        match s {
+    /// the type of a `Content-Type` value, ignoring parameters like `charset`. Generic types
+    /// like `application/octet-stream` are `None`
+    ///
+    /// ```
+    /// # use fetch::MediaType;
+    ///
+    /// assert_eq!(MediaType::try_from_mime("Text/HTML; charset=utf-8"), Some(MediaType::Html));
+    /// assert_eq!(MediaType::try_from_mime("application/octet-stream"), None);
+    /// ```
+    pub fn try_from_mime(s: &str) -> Option<Self> {
+        let s = s.split_once(';').map_or(s, |(x, _params)| x).trim();
+        let ret = match s.to_ascii_lowercase().as_str() {
             "application/xhtml+xml" => MediaType::Xhtml,
-            "application/xml" => MediaType::Xml,
+            "application/xml" | "text/xml" => MediaType::Xml,
             "image/png" => MediaType::Png,
-            "image/jpeg" => MediaType::Jpg,
+            "image/jpeg" | "image/jpg" | "image/pjpeg" => MediaType::Jpg,
             "image/svg+xml" => MediaType::Svg,
             "text/css" => MediaType::Css,
             "image/gif" => MediaType::Gif,
             "image/webp" => MediaType::Webp,
             "text/html" => MediaType::Html,
-            _ => panic!("unknown type {s:?}"),
+            "text/plain" => MediaType::Text,
+            "image/avif" => MediaType::Avif,
+            "font/woff" | "application/font-woff" => MediaType::Woff,
+            "font/woff2" => MediaType::Woff2,
+            "font/ttf" | "application/x-font-ttf" | "font/sfnt" => MediaType::Ttf,
+            "font/otf" | "application/x-font-otf" | "application/vnd.ms-opentype" => MediaType::Otf,
+            _ => return None,
+        };
+        Some(ret)
+    }
+
+    /// guess the type from the start of `data`, for when the server does not say
+    pub fn sniff(data: &[u8]) -> Option<Self> {
+        let magic: &[(&[u8], MediaType)] = &[
+            (b"\x89PNG\r\n\x1a\n", MediaType::Png),
+            (b"\xff\xd8\xff", MediaType::Jpg),
+            (b"GIF87a", MediaType::Gif),
+            (b"GIF89a", MediaType::Gif),
+            (b"wOFF", MediaType::Woff),
+            (b"wOF2", MediaType::Woff2),
+            (b"OTTO", MediaType::Otf),
+            (b"\x00\x01\x00\x00", MediaType::Ttf),
+            (b"true", MediaType::Ttf),
+        ];
+        if let Some((_, ty)) = magic.iter().find(|(m, _)| data.starts_with(m)) {
+            return Some(*ty);
+        }
+        if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+            return Some(MediaType::Webp);
+        }
+        if data.len() >= 12 && &data[4..8] == b"ftyp" && matches!(&data[8..12], b"avif" | b"avis") {
+            return Some(MediaType::Avif);
+        }
+
+        // markup, which may start with a byte order mark and whitespace
+        let head = &data[..data.len().min(1024)];
+        let head = head.strip_prefix(b"\xef\xbb\xbf").unwrap_or(head);
+        let head = String::from_utf8_lossy(head)
+            .trim_start()
+            .to_ascii_lowercase();
+        if head.starts_with("<svg") {
+            Some(MediaType::Svg)
+        } else if head.starts_with("<!doctype html")
+            || head.starts_with("<html")
+            || head.starts_with("<head")
+            || head.starts_with("<body")
+        {
+            Some(MediaType::Html)
+        } else if head.starts_with("<?xml") {
+            if head.contains("<svg") {
+                Some(MediaType::Svg)
+            } else if head.contains("<html") {
+                Some(MediaType::Xhtml)
+            } else {
+                Some(MediaType::Xml)
+            }
+        } else {
+            None
         }
     }
 
@@ -75,6 +179,12 @@ impl MediaType {
             "gif" => MediaType::Gif,
             "webp" => MediaType::Webp,
             "html" => MediaType::Html,
+            "txt" => MediaType::Text,
+            "avif" => MediaType::Avif,
+            "woff" => MediaType::Woff,
+            "woff2" => MediaType::Woff2,
+            "ttf" => MediaType::Ttf,
+            "otf" => MediaType::Otf,
             _ => return None,
         };
         Some(ret)
@@ -91,6 +201,12 @@ impl MediaType {
             MediaType::Gif => "image/gif",
             MediaType::Webp => "image/webp",
             MediaType::Html => "text/html",
+            MediaType::Text => "text/plain",
+            MediaType::Avif => "image/avif",
+            MediaType::Woff => "font/woff",
+            MediaType::Woff2 => "font/woff2",
+            MediaType::Ttf => "font/ttf",
+            MediaType::Otf => "font/otf",
         }
     }
 
@@ -114,39 +230,364 @@ impl MediaType {
             MediaType::Gif => "gif",
             MediaType::Webp => "webp",
             MediaType::Html => "html",
+            MediaType::Text => "txt",
+            MediaType::Avif => "avif",
+            MediaType::Woff => "woff",
+            MediaType::Woff2 => "woff2",
+            MediaType::Ttf => "ttf",
+            MediaType::Otf => "otf",
+        }
+    }
+}
+
+/// what is needed to revalidate and decode a cached response
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EntryMeta {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// when the response was fetched or last revalidated, `None` for old entries
+    pub fetched: Option<SystemTime>,
+    /// the character encoding of a text response, if it was declared or could be detected
+    pub charset: Option<String>,
+}
+
+impl EntryMeta {
+    /// meta for a response fetched just now
+    pub fn now(etag: Option<String>, last_modified: Option<String>) -> Self {
+        EntryMeta {
+            etag,
+            last_modified,
+            fetched: Some(SystemTime::now()),
+            charset: None,
         }
     }
+
+    /// how long ago the entry was fetched, `None` if unknown
+    pub fn age(&self) -> Option<Duration> {
+        // times in the future count as fresh
+        Some(self.fetched?.elapsed().unwrap_or_default())
+    }
+}
+
+pub(crate) fn to_secs(t: SystemTime) -> i64 {
+    t.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+pub(crate) fn from_secs(s: i64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(s.max(0) as u64)
 }
 
 pub struct ObjectCache {
     conn: Connection,
 }
 
-impl ObjectCache {
-    pub fn new(conn: Connection) -> Result<Self> {
-        conn.execute_batch(
-            "
+/// migrations from the previous `user_version` to the next, index `i` migrates from version `i`
+const MIGRATIONS: &[fn(&Connection) -> Result<()>] = &[
+    migrate_legacy,
+    migrate_history,
+    migrate_charset,
+    migrate_cookies,
+    migrate_checkpoints,
+];
+
+/// bring the schema of the cache database up to date. Everything kept in it, not just the fetch
+/// cache, is created here
+pub fn migrate(conn: &Connection) -> Result<()> {
+    let version: usize =
+        conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+    if version > MIGRATIONS.len() {
+        return Err(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_MISMATCH),
+            Some(format!(
+                "cache schema version {version} is newer than the supported {}",
+                MIGRATIONS.len()
+            )),
+        ));
+    }
+    for (v, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.unchecked_transaction()?;
+        migration(&tx)?;
+        tx.pragma_update(None, "user_version", v as i64 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// version 0 to 1: the unversioned schema, created fresh or brought up to date with the
+/// revalidation columns
+fn migrate_legacy(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
 CREATE TABLE IF NOT EXISTS cache_entries (id INTEGER PRIMARY KEY,
                             url TEXT KEY,
                             type INTEGER,
                             content BLOB);
 ",
-        )?;
+    )?;
+    let cols: Vec<String> = conn
+        .prepare("SELECT name FROM pragma_table_info('cache_entries')")?
+        .query_map([], |row| row.get(0))?
+        .collect::<Result<_>>()?;
+    for (col, ty) in [
+        ("etag", "TEXT"),
+        ("last_modified", "TEXT"),
+        ("fetched", "INTEGER"),
+    ] {
+        if !cols.iter().any(|c| c == col) {
+            conn.execute_batch(&format!("ALTER TABLE cache_entries ADD COLUMN {col} {ty}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// version 1 to 2: unique urls, with replaced versions kept in `cache_history`. Of duplicate
+/// rows, the newest stays current and the rest become history
+fn migrate_history(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+ALTER TABLE cache_entries RENAME TO cache_entries_v1;
+CREATE TABLE cache_entries (id INTEGER PRIMARY KEY,
+                            url TEXT NOT NULL UNIQUE,
+                            type INTEGER NOT NULL,
+                            content BLOB NOT NULL,
+                            etag TEXT,
+                            last_modified TEXT,
+                            fetched INTEGER);
+CREATE TABLE cache_history (id INTEGER PRIMARY KEY,
+                            url TEXT NOT NULL,
+                            type INTEGER NOT NULL,
+                            content BLOB NOT NULL,
+                            etag TEXT,
+                            last_modified TEXT,
+                            fetched INTEGER,
+                            replaced INTEGER NOT NULL);
+CREATE INDEX cache_history_url ON cache_history (url);
+INSERT INTO cache_entries (url, type, content, etag, last_modified, fetched)
+    SELECT url, type, content, etag, last_modified, fetched FROM cache_entries_v1
+    WHERE id IN (SELECT max(id) FROM cache_entries_v1 GROUP BY url);
+INSERT INTO cache_history (url, type, content, etag, last_modified, fetched, replaced)
+    SELECT url, type, content, etag, last_modified, fetched, coalesce(fetched, 0)
+    FROM cache_entries_v1
+    WHERE id NOT IN (SELECT max(id) FROM cache_entries_v1 GROUP BY url);
+DROP TABLE cache_entries_v1;
+",
+    )
+}
+
+/// version 2 to 3: the detected charset of text entries
+fn migrate_charset(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+ALTER TABLE cache_entries ADD COLUMN charset TEXT;
+ALTER TABLE cache_history ADD COLUMN charset TEXT;
+",
+    )
+}
+
+/// version 3 to 4: cookies kept between runs, see [`crate::CookieJar`]
+fn migrate_cookies(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+CREATE TABLE cookies (domain TEXT NOT NULL,
+                            host_only INTEGER NOT NULL,
+                            path TEXT NOT NULL,
+                            name TEXT NOT NULL,
+                            value TEXT NOT NULL,
+                            expires INTEGER,
+                            PRIMARY KEY (domain, path, name));
+",
+    )
+}
+
+/// version 4 to 5: how far each content range of a book got, and the pages it went through
+fn migrate_checkpoints(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "
+CREATE TABLE range_checkpoint (spec TEXT NOT NULL,
+                            entry INTEGER NOT NULL,
+                            book TEXT NOT NULL,
+                            url TEXT NOT NULL,
+                            pages INTEGER NOT NULL,
+                            chapters INTEGER NOT NULL,
+                            PRIMARY KEY (spec, entry));
+CREATE INDEX range_checkpoint_book ON range_checkpoint (book);
+CREATE TABLE range_chain (spec TEXT NOT NULL,
+                            entry INTEGER NOT NULL,
+                            n INTEGER NOT NULL,
+                            url TEXT NOT NULL,
+                            PRIMARY KEY (spec, entry, n));
+",
+    )
+}
+
+/// replace the entry for `key`, moving the old copy into the history if the content changed
+fn store(conn: &Connection, key: &str, val: &[u8], ty: MediaType, meta: &EntryMeta) -> Result<()> {
+    let mut stmt = conn.prepare_cached(
+        "INSERT INTO cache_history (url, type, content, etag, last_modified, fetched, charset,
+                                    replaced)
+         SELECT url, type, content, etag, last_modified, fetched, charset, ?3 FROM cache_entries
+         WHERE url=?1 AND content IS NOT ?2",
+    )?;
+    stmt.execute((key, val, to_secs(SystemTime::now())))?;
+    let mut stmt = conn.prepare_cached(
+        "INSERT OR REPLACE INTO cache_entries (url, type, content, etag, last_modified, fetched,
+                                               charset)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+    )?;
+    stmt.execute((
+        key,
+        ty as i64,
+        val,
+        &meta.etag,
+        &meta.last_modified,
+        meta.fetched.map(to_secs),
+        &meta.charset,
+    ))?;
+    Ok(())
+}
+
+/// a current cache entry, without its content
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryInfo {
+    pub url: String,
+    pub ty: MediaType,
+    pub len: usize,
+    pub fetched: Option<SystemTime>,
+}
+
+/// name of the file listing every entry in an exported directory
+const MANIFEST: &str = "manifest.tsv";
+
+/// an earlier copy of a cache entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Version {
+    pub id: i64,
+    pub ty: MediaType,
+    pub len: usize,
+    pub fetched: Option<SystemTime>,
+    /// when a newer copy replaced this one
+    pub replaced: SystemTime,
+}
+
+impl ObjectCache {
+    pub fn new(conn: Connection) -> Result<Self> {
+        migrate(&conn)?;
         Ok(ObjectCache { conn })
     }
 
+    /// store `val` under `key`, replacing any previous entry
     pub fn set(&self, key: &str, val: &[u8], ty: MediaType) -> Result<()> {
+        self.set_with_meta(key, val, ty, &EntryMeta::default())
+    }
+
+    /// like [`set`](Self::set), but also records what is needed for revalidation
+    pub fn set_with_meta(
+        &self,
+        key: &str,
+        val: &[u8],
+        ty: MediaType,
+        meta: &EntryMeta,
+    ) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        store(&tx, key, val, ty, meta)?;
+        tx.commit()
+    }
+
+    /// earlier copies of `key`, newest first
+    pub fn history(&self, key: &str) -> Result<Vec<Version>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT id, type, length(content), fetched, replaced FROM cache_history
+             WHERE url=?1 ORDER BY replaced DESC, id DESC",
+        )?;
+        stmt.query_map([key], |row| {
+            Ok(Version {
+                id: row.get(0)?,
+                ty: MediaType::new(row.get(1)?),
+                len: row.get::<_, i64>(2)? as usize,
+                fetched: row.get::<_, Option<i64>>(3)?.map(from_secs),
+                replaced: from_secs(row.get(4)?),
+            })
+        })?
+        .collect()
+    }
+
+    /// contents of an earlier copy of `key`, by [`Version::id`]
+    pub fn get_version(&self, key: &str, id: i64) -> Result<Option<(MediaType, Bytes)>> {
         let mut stmt = self
             .conn
-            .prepare_cached("INSERT INTO cache_entries (url, type, content) VALUES (?1, ?2, ?3)")?;
-        stmt.execute((key, ty as i64, val))?;
+            .prepare_cached("SELECT type, content FROM cache_history WHERE url=?1 AND id=?2")?;
+        stmt.query_row((key, id), |row| {
+            Ok((
+                MediaType::new(row.get(0)?),
+                Bytes::from(row.get::<_, Vec<u8>>(1)?),
+            ))
+        })
+        .optional()
+    }
+
+    /// make an earlier copy of `key` current again. The current copy goes into the history.
+    /// Returns false if there is no such version
+    pub fn rollback(&self, key: &str, id: i64) -> Result<bool> {
+        let tx = self.conn.unchecked_transaction()?;
+        let old: Option<(i64, Vec<u8>, EntryMeta)> = tx
+            .query_row(
+                "SELECT type, content, etag, last_modified, fetched, charset FROM cache_history
+                 WHERE url=?1 AND id=?2",
+                (key, id),
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        EntryMeta {
+                            etag: row.get(2)?,
+                            last_modified: row.get(3)?,
+                            fetched: row.get::<_, Option<i64>>(4)?.map(from_secs),
+                            charset: row.get(5)?,
+                        },
+                    ))
+                },
+            )
+            .optional()?;
+        let Some((ty, content, meta)) = old else {
+            return Ok(false);
+        };
+        tx.execute("DELETE FROM cache_history WHERE id=?1", [id])?;
+        // history rows always have valid types, they came from the current table
+        store(&tx, key, &content, MediaType::new(ty as i32), &meta)?;
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// revalidation meta and charset of the entry for `key`
+    pub fn meta(&self, key: &str) -> Result<Option<EntryMeta>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT etag, last_modified, fetched, charset FROM cache_entries WHERE url=?1",
+        )?;
+        stmt.query_row([key], |row| {
+            Ok(EntryMeta {
+                etag: row.get(0)?,
+                last_modified: row.get(1)?,
+                fetched: row.get::<_, Option<i64>>(2)?.map(from_secs),
+                charset: row.get(3)?,
+            })
+        })
+        .optional()
+    }
+
+    /// mark the entry for `key` as revalidated just now
+    pub fn touch(&self, key: &str) -> Result<()> {
+        let mut stmt = self
+            .conn
+            .prepare_cached("UPDATE cache_entries SET fetched=?2 WHERE url=?1")?;
+        stmt.execute((key, to_secs(SystemTime::now())))?;
         Ok(())
     }
 
     pub fn get<'a>(&'a self, key: &str) -> Result<Option<(MediaType, Blob<'a>)>> {
         let mut stmt = self
             .conn
-            .prepare_cached("SELECT id, type FROM cache_entries WHERE url=?1 LIMIT 1")?;
+            .prepare_cached("SELECT id, type FROM cache_entries WHERE url=?1")?;
         let id: Option<(i64, i64)> = stmt
             .query_row([key], |row| Ok((row.get(0)?, row.get(1)?)))
             .optional()?;
@@ -158,6 +599,153 @@ CREATE TABLE IF NOT EXISTS cache_entries (id INTEGER PRIMARY KEY,
         Ok(Some((ty, blob)))
     }
 
+    /// every entry with a url starting with `prefix`
+    pub fn list(&self, prefix: &str) -> Result<Vec<EntryInfo>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT url, type, length(content), fetched FROM cache_entries
+             WHERE substr(url, 1, length(?1)) = ?1 ORDER BY url",
+        )?;
+        stmt.query_map([prefix], |row| {
+            Ok(EntryInfo {
+                url: row.get(0)?,
+                ty: MediaType::new(row.get(1)?),
+                len: row.get::<_, i64>(2)? as usize,
+                fetched: row.get::<_, Option<i64>>(3)?.map(from_secs),
+            })
+        })?
+        .collect()
+    }
+
+    /// remove entries with a url starting with `prefix` that were fetched before `cutoff`, and
+    /// their history replaced before it. Entries fetched at an unknown time are kept. Returns the
+    /// number of entries removed
+    pub fn purge_before(&self, prefix: &str, cutoff: SystemTime) -> Result<usize> {
+        let cutoff = to_secs(cutoff);
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM cache_history WHERE substr(url, 1, length(?1)) = ?1 AND replaced < ?2",
+            (prefix, cutoff),
+        )?;
+        let n = tx.execute(
+            "DELETE FROM cache_entries WHERE substr(url, 1, length(?1)) = ?1 AND fetched < ?2",
+            (prefix, cutoff),
+        )?;
+        tx.commit()?;
+        Ok(n)
+    }
+
+    /// give space from removed entries back to the file system
+    pub fn vacuum(&self) -> Result<()> {
+        self.conn.execute_batch("VACUUM")
+    }
+
+    /// write every entry with a url starting with `prefix` into `dir`, one file per entry in a
+    /// directory per host, listed in a manifest. Returns the number of entries written
+    pub fn export(&self, prefix: &str, dir: &Path) -> anyhow::Result<usize> {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("could not create {}", dir.display()))?;
+        let mut manifest = String::new();
+        let entries = self.list(prefix)?;
+        for (i, entry) in entries.iter().enumerate() {
+            let url = url::Url::parse(&entry.url).ok();
+            let host = url.as_ref().and_then(|u| u.host_str()).unwrap_or("_");
+            let file = format!("{host}/{i}.{}", entry.ty.extension());
+            let Some((_, bytes)) = self.get_bytes(&entry.url)? else {
+                continue;
+            };
+            let meta = self.meta(&entry.url)?.unwrap_or_default();
+            std::fs::create_dir_all(dir.join(host))
+                .with_context(|| format!("could not create {}", dir.join(host).display()))?;
+            std::fs::write(dir.join(&file), &bytes)
+                .with_context(|| format!("could not write {file}"))?;
+            let field = |s: Option<&str>| s.unwrap_or("").replace(['\t', '\n'], " ");
+            writeln!(
+                manifest,
+                "{file}\t{}\t{}\t{}\t{}\t{}\t{}",
+                field(Some(&entry.url)),
+                entry.ty as i32,
+                field(meta.etag.as_deref()),
+                field(meta.last_modified.as_deref()),
+                meta.fetched
+                    .map(to_secs)
+                    .map_or(String::new(), |t| t.to_string()),
+                field(meta.charset.as_deref()),
+            )
+            .expect("writing to a string succeeds");
+        }
+        std::fs::write(dir.join(MANIFEST), manifest).context("could not write manifest")?;
+        Ok(entries.len())
+    }
+
+    /// read entries written by [`export`](Self::export). Entries that are newer here than in the
+    /// export are kept. Returns the number of entries imported
+    pub fn import(&self, dir: &Path) -> anyhow::Result<usize> {
+        let manifest = std::fs::read_to_string(dir.join(MANIFEST))
+            .with_context(|| format!("could not read {}", dir.join(MANIFEST).display()))?;
+        let mut n = 0;
+        for (i, line) in manifest.lines().enumerate() {
+            let fields: Vec<&str> = line.split('\t').collect();
+            // exports from before charsets were stored have no charset column
+            let (fields, charset) = match &fields[..] {
+                [rest @ .., charset] if rest.len() == 6 => (rest, *charset),
+                rest => (rest, ""),
+            };
+            let &[file, url, ty, etag, last_modified, fetched] = fields else {
+                anyhow::bail!("manifest line {} is malformed", i + 1);
+            };
+            let ty = ty
+                .parse()
+                .ok()
+                .and_then(MediaType::try_new)
+                .with_context(|| format!("manifest line {} has invalid type {ty}", i + 1))?;
+            let opt = |s: &str| (!s.is_empty()).then(|| s.to_owned());
+            let meta = EntryMeta {
+                etag: opt(etag),
+                last_modified: opt(last_modified),
+                fetched: fetched.parse().ok().map(from_secs),
+                charset: opt(charset),
+            };
+            // the manifest may come from someone else, so it only gets to read from `dir`
+            let path = Path::new(file);
+            anyhow::ensure!(
+                !file.is_empty()
+                    && path
+                        .components()
+                        .all(|c| matches!(c, std::path::Component::Normal(_))),
+                "manifest line {} has a path outside of the export: {file}",
+                i + 1
+            );
+            let existing = self.meta(url)?;
+            if let Some(existing) = existing
+                && existing.fetched.is_some()
+                && existing.fetched >= meta.fetched
+            {
+                continue;
+            }
+            let bytes =
+                std::fs::read(dir.join(file)).with_context(|| format!("could not read {file}"))?;
+            self.set_with_meta(url, &bytes, ty, &meta)?;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    /// remove every entry with a url starting with `prefix` along with its history, returns the
+    /// number of entries removed
+    pub fn purge(&self, prefix: &str) -> Result<usize> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM cache_history WHERE substr(url, 1, length(?1)) = ?1",
+            [prefix],
+        )?;
+        let n = tx.execute(
+            "DELETE FROM cache_entries WHERE substr(url, 1, length(?1)) = ?1",
+            [prefix],
+        )?;
+        tx.commit()?;
+        Ok(n)
+    }
+
     #[allow(dead_code)]
     pub fn get_string(&self, key: &str) -> Result<Option<(MediaType, String)>> {
         let Some((ty, mut blob)) = self.get(key)? else {
@@ -187,6 +775,50 @@ mod tests {
         ObjectCache::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap()
     }
 
+    #[test]
+    fn ids_round_trip() {
+        for id in 0.. {
+            let Some(ty) = MediaType::try_new(id) else {
+                assert_eq!(id, 15, "ids should have no gaps");
+                break;
+            };
+            assert_eq!(MediaType::try_from_mime(ty.mime()), Some(ty));
+            assert_eq!(MediaType::from_extension(ty.extension()), Some(ty));
+        }
+    }
+
+    #[test]
+    fn sniff() {
+        let cases: &[(&[u8], Option<MediaType>)] = &[
+            (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", Some(MediaType::Png)),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", Some(MediaType::Jpg)),
+            (b"GIF89a\x01\0", Some(MediaType::Gif)),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", Some(MediaType::Webp)),
+            (b"\0\0\0\x1cftypavif\0\0\0\0", Some(MediaType::Avif)),
+            (b"wOF2\0\x01", Some(MediaType::Woff2)),
+            (
+                b"\xef\xbb\xbf\n  <!DOCTYPE html><html>",
+                Some(MediaType::Html),
+            ),
+            (
+                b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>",
+                Some(MediaType::Svg),
+            ),
+            (b"<?xml version=\"1.0\"?>\n<svg/>", Some(MediaType::Svg)),
+            (b"<?xml version=\"1.0\"?>\n<rss/>", Some(MediaType::Xml)),
+            (b"just text", None),
+            (b"", None),
+        ];
+        for (data, ty) in cases {
+            assert_eq!(
+                MediaType::sniff(data),
+                *ty,
+                "{:?}",
+                String::from_utf8_lossy(data)
+            );
+        }
+    }
+
     #[test]
     fn it_works() {
         let cache = new_cache();
@@ -206,6 +838,178 @@ mod tests {
         assert_eq!(res.1, "asdfasdf");
     }
 
+    #[test]
+    fn set_replaces() {
+        let cache = new_cache();
+        cache.set("key1", b"old", MediaType::Html).unwrap();
+        cache.set("key1", b"new", MediaType::Html).unwrap();
+        let res = cache.get_string("key1").unwrap().unwrap();
+        assert_eq!(res.1, "new");
+    }
+
+    #[test]
+    fn meta() {
+        let cache = new_cache();
+        cache.set("key1", b"old", MediaType::Html).unwrap();
+        let meta = cache.meta("key1").unwrap().unwrap();
+        assert_eq!(meta, EntryMeta::default());
+        assert_eq!(meta.age(), None);
+
+        let meta = EntryMeta::now(Some("\"abc\"".into()), None);
+        cache
+            .set_with_meta("key1", b"new", MediaType::Html, &meta)
+            .unwrap();
+        let stored = cache.meta("key1").unwrap().unwrap();
+        assert_eq!(stored.etag.as_deref(), Some("\"abc\""));
+        assert!(stored.age().unwrap() < Duration::from_secs(5));
+        assert!(cache.meta("key2").unwrap().is_none());
+    }
+
+    #[test]
+    fn history_and_rollback() {
+        let cache = new_cache();
+        cache.set("key1", b"v1", MediaType::Html).unwrap();
+        cache.set("key1", b"v1", MediaType::Html).unwrap();
+        assert!(cache.history("key1").unwrap().is_empty());
+        cache.set("key1", b"v2", MediaType::Html).unwrap();
+        cache.set("key1", b"v3", MediaType::Html).unwrap();
+        let history = cache.history("key1").unwrap();
+        assert_eq!(history.len(), 2);
+        let (_, v1) = cache.get_version("key1", history[1].id).unwrap().unwrap();
+        assert_eq!(v1, "v1".as_bytes());
+
+        assert!(cache.rollback("key1", history[1].id).unwrap());
+        assert_eq!(cache.get_string("key1").unwrap().unwrap().1, "v1");
+        let history = cache.history("key1").unwrap();
+        let versions: Vec<_> = history
+            .iter()
+            .map(|v| cache.get_version("key1", v.id).unwrap().unwrap().1)
+            .collect();
+        assert_eq!(versions.len(), 2);
+        assert!(versions.contains(&"v2".into()) && versions.contains(&"v3".into()));
+        assert!(!cache.rollback("key1", 12345).unwrap());
+    }
+
+    #[test]
+    fn old_schema() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE cache_entries (id INTEGER PRIMARY KEY, url TEXT KEY, type INTEGER,
+             content BLOB);
+             INSERT INTO cache_entries (url, type, content) VALUES ('key1', 8, 'older');
+             INSERT INTO cache_entries (url, type, content) VALUES ('key1', 8, 'old');",
+        )
+        .unwrap();
+        let cache = ObjectCache::new(conn).unwrap();
+        // the newest duplicate wins
+        assert_eq!(cache.get_string("key1").unwrap().unwrap().1, "old");
+        assert_eq!(cache.history("key1").unwrap().len(), 1);
+        cache.touch("key1").unwrap();
+        assert!(cache.meta("key1").unwrap().unwrap().fetched.is_some());
+    }
+
+    #[test]
+    fn newer_schema() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", 100).unwrap();
+        assert!(ObjectCache::new(conn).is_err());
+    }
+
+    #[test]
+    fn list_and_purge() {
+        let cache = new_cache();
+        cache
+            .set("https://a.com/1", b"one", MediaType::Html)
+            .unwrap();
+        cache
+            .set("https://a.com/2", b"two!", MediaType::Png)
+            .unwrap();
+        cache
+            .set("https://b.com/1", b"three", MediaType::Html)
+            .unwrap();
+        let a: Vec<_> = cache
+            .list("https://a.com/")
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.url, e.ty, e.len))
+            .collect();
+        assert_eq!(
+            a,
+            [
+                ("https://a.com/1".to_owned(), MediaType::Html, 3),
+                ("https://a.com/2".to_owned(), MediaType::Png, 4),
+            ]
+        );
+        assert_eq!(cache.list("").unwrap().len(), 3);
+        assert_eq!(cache.purge("https://a.com/").unwrap(), 2);
+        assert_eq!(cache.list("").unwrap().len(), 1);
+        assert!(cache.get("https://b.com/1").unwrap().is_some());
+    }
+
+    #[test]
+    fn purge_before() {
+        let cache = new_cache();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let old = EntryMeta {
+            fetched: Some(SystemTime::now() - 10 * day),
+            ..EntryMeta::default()
+        };
+        cache.set("unknown", b"a", MediaType::Html).unwrap();
+        cache
+            .set_with_meta("old", b"b", MediaType::Html, &old)
+            .unwrap();
+        cache
+            .set_with_meta("new", b"c", MediaType::Html, &EntryMeta::now(None, None))
+            .unwrap();
+        cache
+            .set_with_meta("other/old", b"d", MediaType::Html, &old)
+            .unwrap();
+        let cutoff = SystemTime::now() - day;
+        assert_eq!(cache.purge_before("other/", cutoff).unwrap(), 1);
+        assert_eq!(cache.purge_before("", cutoff).unwrap(), 1);
+        let left: Vec<_> = cache.list("").unwrap().into_iter().map(|e| e.url).collect();
+        assert_eq!(left, ["new", "unknown"]);
+        cache.vacuum().unwrap();
+    }
+
+    #[test]
+    fn export_import() {
+        let dir = std::env::temp_dir().join(format!("wn3-export-{}", std::process::id()));
+        let a = new_cache();
+        let meta = EntryMeta {
+            charset: Some("Shift_JIS".into()),
+            ..EntryMeta::now(Some("\"x\"".into()), None)
+        };
+        a.set_with_meta("https://a.com/1", b"one", MediaType::Html, &meta)
+            .unwrap();
+        a.set("https://b.com/img", b"png", MediaType::Png).unwrap();
+        assert_eq!(a.export("", &dir).unwrap(), 2);
+        assert!(dir.join("a.com").is_dir());
+
+        let b = new_cache();
+        assert_eq!(b.import(&dir).unwrap(), 2);
+        assert_eq!(b.get_string("https://a.com/1").unwrap().unwrap().1, "one");
+        let imported = b.meta("https://a.com/1").unwrap().unwrap();
+        assert_eq!(imported.etag, meta.etag);
+        assert_eq!(imported.charset, meta.charset);
+        let (ty, _) = b.get_bytes("https://b.com/img").unwrap().unwrap();
+        assert_eq!(ty, MediaType::Png);
+        // nothing newer to import
+        assert_eq!(b.import(&dir).unwrap(), 1);
+
+        for file in ["/etc/passwd", "../secret", "a.com/../../secret", ""] {
+            let ty = MediaType::Html as i32;
+            let line = format!("{file}\thttps://c.com/1\t{ty}\t\t\t\t\n");
+            std::fs::write(dir.join(MANIFEST), line).unwrap();
+            let e = new_cache().import(&dir).unwrap_err();
+            assert!(
+                e.to_string().contains("outside of the export"),
+                "{file}: {e}"
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
     #[test]
     fn use_url() {
         let cache = new_cache();
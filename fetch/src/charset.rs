@@ -0,0 +1,110 @@
+//! finding the character encoding of a page and decoding it
+
+use anyhow::{Result, bail};
+use encoding_rs::{Encoding, UTF_8};
+use log::warn;
+
+/// how far into a document `<meta charset>` is looked for, as in the HTML prescan
+const PRESCAN_LEN: usize = 1024;
+
+/// the `charset` parameter of a `Content-Type` value
+pub fn from_content_type(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, val) = param.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("charset")
+            .then(|| val.trim().trim_matches(['"', '\'']))
+    })
+}
+
+/// the charset given by a `<meta charset>` or `<meta http-equiv="Content-Type">` near the start
+/// of `data`
+fn from_meta(data: &[u8]) -> Option<&'static Encoding> {
+    let head = &data[..data.len().min(PRESCAN_LEN)];
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    head.match_indices("<meta").find_map(|(at, _)| {
+        let tag = &head[at..];
+        let tag = &tag[..tag.find('>').unwrap_or(tag.len())];
+        let rest = &tag[tag.find("charset")? + "charset".len()..];
+        let rest = rest.trim_start().strip_prefix('=')?.trim_start();
+        let rest = rest.trim_start_matches(['"', '\'']);
+        let end = rest
+            .find(|c: char| c == '"' || c == '\'' || c == ';' || c.is_whitespace())
+            .unwrap_or(rest.len());
+        Encoding::for_label(&rest.as_bytes()[..end])
+    })
+}
+
+/// the encoding of `data`, trying the `Content-Type` charset, then `<meta charset>`, then a
+/// byte order mark
+pub fn detect(declared: Option<&str>, data: &[u8]) -> Option<&'static Encoding> {
+    if let Some(label) = declared {
+        match Encoding::for_label(label.as_bytes()) {
+            Some(enc) => return Some(enc),
+            None => warn!("unknown charset {label:?}, looking for another"),
+        }
+    }
+    from_meta(data).or_else(|| Encoding::for_bom(data).map(|(enc, _)| enc))
+}
+
+/// decode `data` as `charset`, or whatever [`detect`] finds. Without either it has to be UTF-8
+pub fn decode(data: &[u8], charset: Option<&str>) -> Result<String> {
+    let Some(enc) = detect(charset, data) else {
+        let Ok(s) = std::str::from_utf8(data) else {
+            bail!("not valid utf-8, and no charset was given")
+        };
+        return Ok(s.to_owned());
+    };
+    let (s, _, had_errors) = enc.decode(data);
+    if had_errors && enc != UTF_8 {
+        warn!("some characters are not valid {}", enc.name());
+    } else if had_errors {
+        bail!("not valid utf-8")
+    }
+    Ok(s.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_type() {
+        assert_eq!(
+            from_content_type("text/html; Charset=\"Shift_JIS\""),
+            Some("Shift_JIS")
+        );
+        assert_eq!(
+            from_content_type("text/html;charset=euc-jp"),
+            Some("euc-jp")
+        );
+        assert_eq!(from_content_type("text/html"), None);
+    }
+
+    #[test]
+    fn detects() {
+        let meta = b"<html><head><meta charset='Shift_JIS'></head>";
+        assert_eq!(detect(None, meta), Some(encoding_rs::SHIFT_JIS));
+        let http_equiv =
+            b"<META HTTP-EQUIV=\"Content-Type\" CONTENT=\"text/html; charset=EUC-JP\">";
+        assert_eq!(detect(None, http_equiv), Some(encoding_rs::EUC_JP));
+        // the header wins
+        assert_eq!(detect(Some("gbk"), meta), Some(encoding_rs::GBK));
+        assert_eq!(detect(Some("nonsense"), meta), Some(encoding_rs::SHIFT_JIS));
+        assert_eq!(detect(None, b"\xfe\xff\0<\0p"), Some(encoding_rs::UTF_16BE));
+        assert_eq!(detect(None, b"<p>plain</p>"), None);
+    }
+
+    #[test]
+    fn decodes() {
+        // "日本語" in Shift_JIS
+        let sjis = b"<meta charset=shift_jis><p>\x93\xfa\x96\x7b\x8c\xea</p>";
+        assert_eq!(
+            decode(sjis, None).unwrap(),
+            "<meta charset=shift_jis><p>日本語</p>"
+        );
+        assert_eq!(decode(b"\x93\xfa", Some("shift_jis")).unwrap(), "日");
+        assert_eq!(decode("日本".as_bytes(), None).unwrap(), "日本");
+        assert!(decode(b"\x93\xfa", None).is_err());
+    }
+}
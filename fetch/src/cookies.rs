@@ -0,0 +1,238 @@
+//! cookies set by servers, kept in the cache database so sessions outlive a run
+
+use std::time::{Duration, SystemTime};
+
+use rusqlite::{Connection, Result};
+use url::Url;
+
+use crate::cache::{from_secs, migrate, to_secs};
+
+pub struct CookieJar {
+    conn: Connection,
+}
+
+/// one parsed `Set-Cookie`
+#[derive(Debug, PartialEq, Eq)]
+struct Cookie {
+    domain: String,
+    /// the cookie had no `Domain`, so it is only sent to the host that set it
+    host_only: bool,
+    path: String,
+    name: String,
+    value: String,
+    /// `None` for session cookies, which are kept until they are replaced
+    expires: Option<SystemTime>,
+}
+
+impl Cookie {
+    /// parse a `Set-Cookie` value sent by `url`. Cookies for other sites are rejected
+    fn parse(url: &Url, s: &str, now: SystemTime) -> Option<Self> {
+        let host = url.host_str()?.to_ascii_lowercase();
+        let mut attrs = s.split(';');
+        let (name, value) = attrs.next()?.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty() {
+            return None;
+        }
+        let mut cookie = Cookie {
+            domain: host.clone(),
+            host_only: true,
+            path: default_path(url),
+            name: name.to_owned(),
+            value: value.trim().to_owned(),
+            expires: None,
+        };
+        let mut max_age = None;
+        for attr in attrs {
+            let (key, val) = attr.split_once('=').unwrap_or((attr, ""));
+            let val = val.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "domain" if !val.is_empty() => {
+                    let domain = val.trim_start_matches('.').to_ascii_lowercase();
+                    if !domain_matches(&host, &domain) {
+                        return None;
+                    }
+                    cookie.domain = domain;
+                    cookie.host_only = false;
+                }
+                "path" if val.starts_with('/') => cookie.path = val.to_owned(),
+                "max-age" => max_age = val.parse::<i64>().ok(),
+                "expires" => {
+                    cookie.expires = cookie
+                        .expires
+                        .or_else(|| crate::retry::parse_http_date(val))
+                }
+                _ => (),
+            }
+        }
+        // max-age wins over expires. One too long to represent is kept for the session
+        if let Some(max_age) = max_age {
+            cookie.expires = match u64::try_from(max_age) {
+                Ok(secs) => now.checked_add(Duration::from_secs(secs)),
+                Err(_) => Some(SystemTime::UNIX_EPOCH),
+            };
+        }
+        Some(cookie)
+    }
+}
+
+/// the directory of the url's path, used when a cookie has no `Path`
+fn default_path(url: &Url) -> String {
+    match url.path().rfind('/') {
+        Some(0) | None => "/".to_owned(),
+        Some(i) => url.path()[..i].to_owned(),
+    }
+}
+
+/// whether `host` is `domain` or one of its subdomains
+pub(crate) fn domain_matches(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|rest| rest.ends_with('.'))
+}
+
+/// whether a cookie for `cookie_path` is sent for `path`
+fn path_matches(path: &str, cookie_path: &str) -> bool {
+    path.strip_prefix(cookie_path)
+        .is_some_and(|rest| rest.is_empty() || cookie_path.ends_with('/') || rest.starts_with('/'))
+}
+
+impl CookieJar {
+    pub fn new(conn: Connection) -> Result<Self> {
+        migrate(&conn)?;
+        Ok(CookieJar { conn })
+    }
+
+    /// remember the cookie in a `Set-Cookie` sent by `url`, or forget it if it has expired
+    pub fn store(&self, url: &Url, set_cookie: &str) -> Result<()> {
+        let now = SystemTime::now();
+        let Some(cookie) = Cookie::parse(url, set_cookie, now) else {
+            log::debug!("ignoring cookie {set_cookie:?} from {url}");
+            return Ok(());
+        };
+        if cookie.expires.is_some_and(|t| t <= now) {
+            self.conn.execute(
+                "DELETE FROM cookies WHERE domain=?1 AND path=?2 AND name=?3",
+                (&cookie.domain, &cookie.path, &cookie.name),
+            )?;
+            return Ok(());
+        }
+        self.conn.execute(
+            "INSERT OR REPLACE INTO cookies (domain, host_only, path, name, value, expires)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (
+                &cookie.domain,
+                cookie.host_only,
+                &cookie.path,
+                &cookie.name,
+                &cookie.value,
+                cookie.expires.map(to_secs),
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// `(name, value)` of every unexpired cookie to send to `url`, longer paths first
+    pub fn cookies_for(&self, url: &Url) -> Result<Vec<(String, String)>> {
+        let Some(host) = url.host_str() else {
+            return Ok(Vec::new());
+        };
+        let host = host.to_ascii_lowercase();
+        let now = SystemTime::now();
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT domain, host_only, path, name, value, expires FROM cookies
+             ORDER BY length(path) DESC",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, bool>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, Option<i64>>(5)?.map(from_secs),
+            ))
+        })?;
+        let mut ret = Vec::new();
+        for row in rows {
+            let (domain, host_only, path, name, value, expires) = row?;
+            let domain_ok = if host_only {
+                host == domain
+            } else {
+                domain_matches(&host, &domain)
+            };
+            if domain_ok && path_matches(url.path(), &path) && expires.is_none_or(|t| t > now) {
+                ret.push((name, value));
+            }
+        }
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jar() -> CookieJar {
+        CookieJar::new(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn parse() {
+        let url: Url = "https://www.example.com/novel/1".parse().unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let c = Cookie::parse(
+            &url,
+            "sid=abc; Path=/; Domain=.example.com; Max-Age=60",
+            now,
+        )
+        .unwrap();
+        assert_eq!(c.domain, "example.com");
+        assert!(!c.host_only);
+        assert_eq!(c.path, "/");
+        assert_eq!(c.expires, Some(now + Duration::from_secs(60)));
+        let c = Cookie::parse(&url, "a=b", now).unwrap();
+        assert_eq!(
+            (c.domain.as_str(), c.path.as_str()),
+            ("www.example.com", "/novel")
+        );
+        assert!(c.host_only);
+        // for another site
+        assert!(Cookie::parse(&url, "a=b; Domain=other.com", now).is_none());
+        assert!(Cookie::parse(&url, "a=b; Domain=ample.com", now).is_none());
+        // too far ahead to represent, so kept for the session
+        let c = Cookie::parse(
+            &url,
+            "a=b; Expires=Sun, 06 Nov 300000000000 08:49:37 GMT",
+            now,
+        );
+        assert_eq!(c.unwrap().expires, None);
+    }
+
+    #[test]
+    fn store_and_send() {
+        let jar = jar();
+        let url: Url = "https://noc.example.com/n1/".parse().unwrap();
+        jar.store(&url, "over18=yes; Domain=example.com; Path=/")
+            .unwrap();
+        jar.store(&url, "session=1").unwrap();
+        let names = |u: &str| -> Vec<String> {
+            let url: Url = u.parse().unwrap();
+            jar.cookies_for(&url)
+                .unwrap()
+                .into_iter()
+                .map(|(n, _)| n)
+                .collect()
+        };
+        assert_eq!(names("https://noc.example.com/n1/2"), ["session", "over18"]);
+        assert_eq!(names("https://example.com/"), ["over18"]);
+        assert_eq!(names("https://noc.example.com/n2/"), ["over18"]);
+        assert!(names("https://other.com/").is_empty());
+
+        // expiring removes it
+        jar.store(&url, "over18=; Domain=example.com; Path=/; Max-Age=0")
+            .unwrap();
+        assert_eq!(names("https://example.com/"), Vec::<String>::new());
+    }
+}
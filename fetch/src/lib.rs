@@ -1,30 +1,197 @@
 use std::{
+    collections::HashMap,
+    path::PathBuf,
     sync::{Arc, Mutex},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
-use anyhow::{Context, Result, anyhow, bail};
+use anyhow::{Context, Result, anyhow, bail, ensure};
 use bytes::Bytes;
-use log::{info, trace};
-use ratelimit::wait_your_turn;
+use log::{debug, info, trace, warn};
+use ratelimit::{hold_off, wait_your_turn};
 
 mod cache;
-pub use cache::MediaType;
-use cache::ObjectCache;
+mod charset;
+mod cookies;
+pub use cache::{EntryMeta, MediaType, ObjectCache, migrate};
+pub use cookies::CookieJar;
 use url::Url;
 
+mod prefetch;
+use prefetch::{InFlight, Prefetcher};
 mod ratelimit;
+mod retry;
+pub use retry::RetryPolicy;
+mod robots;
+pub use robots::Robots;
 
 /// Delay between two requests being sent to the same domain
 ///
 /// 65 is a good respectful default (60 seems to cause a sort of race)
-const CRAWL_DELAY: Duration = Duration::from_secs(15);
+pub const CRAWL_DELAY: Duration = Duration::from_secs(15);
+
+/// redirects followed for one request before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// sent when no other user agent is configured
+pub const DEFAULT_USER_AGENT: &str = "wn-scraper3/0.0.1 (+https://github.com/gfaster)";
+
+/// what is sent along with each request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpConfig {
+    pub user_agent: String,
+    /// extra headers by host, also sent to its subdomains
+    pub headers: HashMap<String, Vec<(String, String)>>,
+    /// cookies by host, also sent to its subdomains. These win over cookies set by the server
+    pub cookies: HashMap<String, Vec<(String, String)>>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            user_agent: DEFAULT_USER_AGENT.to_owned(),
+            headers: HashMap::new(),
+            cookies: HashMap::new(),
+        }
+    }
+}
+
+impl HttpConfig {
+    /// the product token matched against `User-agent` lines in robots.txt
+    fn robots_agent(&self) -> &str {
+        self.user_agent
+            .split(['/', ' '])
+            .next()
+            .unwrap_or(&self.user_agent)
+    }
+
+    /// the entries of `by_host` for `host` and the domains it is under
+    fn for_host<'a>(
+        by_host: &'a HashMap<String, Vec<(String, String)>>,
+        host: &'a str,
+    ) -> impl Iterator<Item = &'a (String, String)> {
+        by_host
+            .iter()
+            .filter(move |(domain, _)| cookies::domain_matches(host, domain))
+            .flat_map(|(_, pairs)| pairs)
+    }
+}
+
+/// how politely to crawl each host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrawlConfig {
+    /// delay for hosts without one of their own
+    pub default_delay: Duration,
+    /// delays for particular hosts, these win over robots.txt
+    pub delays: HashMap<String, Duration>,
+    /// whether to fetch robots.txt and obey its `Disallow` and `Crawl-delay`
+    pub robots: bool,
+    pub retry: RetryPolicy,
+}
+
+impl Default for CrawlConfig {
+    fn default() -> Self {
+        CrawlConfig {
+            default_delay: CRAWL_DELAY,
+            delays: HashMap::new(),
+            robots: false,
+            retry: RetryPolicy::default(),
+        }
+    }
+}
+
+impl CrawlConfig {
+    /// the delay between requests to `host`, given its robots.txt
+    pub fn delay_for(&self, host: &str, robots: Option<&Robots>) -> Duration {
+        self.delays
+            .get(host)
+            .copied()
+            .or_else(|| robots?.crawl_delay)
+            .unwrap_or(self.default_delay)
+    }
+}
+
+/// where the cache database lives
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheLocation {
+    File(PathBuf),
+    /// nothing is kept once the connection closes
+    Memory,
+}
+
+impl CacheLocation {
+    /// `$XDG_CACHE_HOME/wn3/cache.db`, falling back to `~/.cache/wn3/cache.db`
+    pub fn default_path() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CACHE_HOME")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".cache")))?;
+        Some(base.join("wn3").join("cache.db"))
+    }
+
+    /// `:memory:` is [`CacheLocation::Memory`], anything else is a path
+    pub fn from_arg(s: &str) -> Self {
+        match s {
+            ":memory:" => CacheLocation::Memory,
+            path => CacheLocation::File(path.into()),
+        }
+    }
+
+    /// open a connection, creating the parent directories of a file
+    pub fn open(&self) -> Result<rusqlite::Connection> {
+        match self {
+            CacheLocation::File(path) => {
+                if let Some(parent) = path.parent()
+                    && !parent.as_os_str().is_empty()
+                {
+                    std::fs::create_dir_all(parent)
+                        .with_context(|| format!("could not create {}", parent.display()))?;
+                }
+                rusqlite::Connection::open(path)
+                    .with_context(|| format!("could not open cache {}", path.display()))
+            }
+            CacheLocation::Memory => Ok(rusqlite::Connection::open_in_memory()?),
+        }
+    }
+}
+
+/// when a cached response is checked with the server using a conditional request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Revalidate {
+    /// cached responses are used forever
+    #[default]
+    Never,
+    /// revalidate responses that were fetched longer ago than this
+    OlderThan(Duration),
+    Always,
+}
+
+impl Revalidate {
+    fn is_due(self, meta: &EntryMeta) -> bool {
+        match self {
+            Revalidate::Never => false,
+            Revalidate::OlderThan(max) => meta.age().is_none_or(|age| age > max),
+            Revalidate::Always => true,
+        }
+    }
+}
 
 #[derive(Clone)]
 pub struct FetchContext {
     cache: Arc<Mutex<cache::ObjectCache>>,
+    /// should be built with `.redirects(0)`. Redirects are followed here instead, so that every
+    /// hop gets the cookies and headers of its own host and its `Set-Cookie`s are kept
     client: ureq::Agent,
+    /// parsed robots.txt by origin, so each is only parsed once
+    robots: Arc<Mutex<HashMap<String, Arc<Robots>>>>,
+    inflight: Arc<InFlight>,
+    cookies: Option<Arc<Mutex<CookieJar>>>,
+    /// `None` for the workers themselves
+    prefetch: Option<Arc<Prefetcher>>,
     pub offline: bool,
+    pub revalidate: Revalidate,
+    pub crawl: CrawlConfig,
+    pub http: HttpConfig,
 }
 
 impl FetchContext {
@@ -36,7 +203,14 @@ impl FetchContext {
         Ok(FetchContext {
             cache: Arc::new(Mutex::new(ObjectCache::new(conn)?)),
             client,
+            robots: Arc::default(),
+            inflight: Arc::default(),
+            cookies: None,
+            prefetch: None,
             offline,
+            revalidate: Revalidate::Never,
+            crawl: CrawlConfig::default(),
+            http: HttpConfig::default(),
         })
     }
 
@@ -44,10 +218,42 @@ impl FetchContext {
         Ok(FetchContext {
             cache: Arc::new(Mutex::new(ObjectCache::new(conn)?)),
             client,
+            robots: Arc::default(),
+            inflight: Arc::default(),
+            cookies: None,
+            prefetch: None,
             offline: false,
+            revalidate: Revalidate::Never,
+            crawl: CrawlConfig::default(),
+            http: HttpConfig::default(),
         })
     }
 
+    /// keep cookies set by servers in `jar` and send them back. Without a jar, only the cookies
+    /// in [`HttpConfig::cookies`] are sent
+    pub fn set_cookie_jar(&mut self, jar: CookieJar) {
+        self.cookies = Some(Arc::new(Mutex::new(jar)));
+    }
+
+    /// fetch urls passed to [`prefetch`](Self::prefetch) on `workers` threads. The workers use
+    /// the settings of this context as they are now. Does nothing when offline
+    pub fn start_prefetch(&mut self, workers: usize) {
+        if workers == 0 || self.offline {
+            return;
+        }
+        let mut worker_cx = self.clone();
+        worker_cx.prefetch = None;
+        self.prefetch = Some(Arc::new(Prefetcher::new(&worker_cx, workers)));
+    }
+
+    /// queue urls that will be needed soon to be fetched in the background, if
+    /// [`start_prefetch`](Self::start_prefetch) was called
+    pub fn prefetch(&self, urls: impl IntoIterator<Item = Url>) {
+        if let Some(prefetch) = &self.prefetch {
+            prefetch.push(urls);
+        }
+    }
+
     /// gets url from store, but will not touch network
     pub fn fetch_local(&self, url: &str) -> Result<(MediaType, Bytes)> {
         if let Some(bytes) = self
@@ -64,6 +270,41 @@ impl FetchContext {
     }
 
     pub fn fetch(&self, url: &Url) -> Result<(MediaType, Bytes)> {
+        self.fetch_with(url, self.revalidate)
+    }
+
+    /// like [`fetch`](Self::fetch), but decoded using the charset found when the page was
+    /// fetched
+    pub fn fetch_text(&self, url: &Url) -> Result<(MediaType, String)> {
+        self.fetch_text_with(url, self.revalidate)
+    }
+
+    /// like [`fetch_text`](Self::fetch_text), with a revalidation policy for just this call
+    pub fn fetch_text_with(
+        &self,
+        url: &Url,
+        revalidate: Revalidate,
+    ) -> Result<(MediaType, String)> {
+        let (ty, bytes) = self.fetch_with(url, revalidate)?;
+        let meta = self
+            .cache
+            .lock()
+            .unwrap()
+            .meta(url.as_str())
+            .context("db access failed")?;
+        let charset = meta.and_then(|m| m.charset);
+        let text = charset::decode(&bytes, charset.as_deref())?;
+        Ok((ty, text))
+    }
+
+    /// like [`fetch`](Self::fetch), but always revalidates the cached copy.
+    /// When offline, this is the same as `fetch`
+    pub fn refetch(&self, url: &Url) -> Result<(MediaType, Bytes)> {
+        self.fetch_with(url, Revalidate::Always)
+    }
+
+    /// like [`fetch`](Self::fetch), with a revalidation policy for just this call
+    pub fn fetch_with(&self, url: &Url, revalidate: Revalidate) -> Result<(MediaType, Bytes)> {
         if url.scheme() == "file" {
             let path = url
                 .to_file_path()
@@ -73,37 +314,57 @@ impl FetchContext {
                 .context("no extension")?
                 .to_str()
                 .context("path not utf-8")?;
-            let ty = MediaType::from_extension(ext)
-                .with_context(|| format!("extension {ext} is invalid"))?;
             let data: Bytes = std::fs::read(&path)
                 .with_context(|| format!("could not read {}", path.display()))?
                 .into();
+            let ty = MediaType::from_extension(ext)
+                .or_else(|| MediaType::sniff(&data))
+                .with_context(|| format!("extension {ext} is invalid"))?;
             return Ok((ty, data));
         }
-        if let Some(bytes) = self.cache.lock().unwrap().get_bytes(url.as_str()).unwrap() {
+        let cached = {
+            let cache = self.cache.lock().unwrap();
+            let bytes = cache.get_bytes(url.as_str()).context("db access failed")?;
+            let meta = cache.meta(url.as_str()).context("db access failed")?;
+            drop(cache);
+            bytes.map(|b| (b, meta.unwrap_or_default()))
+        };
+        if let Some((bytes, meta)) = &cached
+            && (self.offline || !revalidate.is_due(meta))
+        {
             trace!("{url} found in cache");
-            return Ok(bytes);
+            return Ok(bytes.clone());
         }
+        let Some(_claim) = self.inflight.claim(url) else {
+            // it was fetched by someone else in the meantime, or they failed and it is our turn
+            return self.fetch_with(url, Revalidate::Never);
+        };
+        self.fetch_remote(url, cached)
+    }
+
+    /// make a request, conditional if there is a cached copy
+    fn fetch_remote(
+        &self,
+        url: &Url,
+        cached: Option<((MediaType, Bytes), EntryMeta)>,
+    ) -> Result<(MediaType, Bytes)> {
         if self.offline {
             bail!("cannot fetch {url} because offline is enabled")
         }
-        let domain = url.domain().unwrap();
-        trace!("getting in line to access {}", domain);
-
-        wait_your_turn(domain, CRAWL_DELAY);
-        info!("fetching url {url}");
-
-        let res = self.client.request_url("GET", url).call();
-        let resp = match res {
-            Ok(succ) => succ,
-            Err(e) => {
-                return Err(e.into());
-            }
-        };
-        let Some(resp_ty) = resp.header("Content-Type") else {
-            bail!("TODO: handle no content-type")
-        };
-        let resp_ty = MediaType::from_mime(resp_ty.split_once(';').map_or(resp_ty, |(x, _rem)| x));
+        let resp = self.call_following_redirects(url, cached.as_ref().map(|(_, meta)| meta))?;
+        if resp.status() == 304 {
+            let Some((bytes, _)) = cached else {
+                bail!("got 304 Not Modified for {url}, but it is not cached")
+            };
+            debug!("{url} was not modified");
+            self.cache.lock().unwrap().touch(url.as_str())?;
+            return Ok(bytes);
+        }
+        let mut meta = EntryMeta::now(
+            resp.header("ETag").map(str::to_owned),
+            resp.header("Last-Modified").map(str::to_owned),
+        );
+        let declared = resp.header("Content-Type").map(str::to_owned);
         let bytes: Bytes = {
             use std::io::prelude::*;
 
@@ -113,16 +374,215 @@ impl FetchContext {
             reader.read_to_end(&mut buf).context("failed to get body")?;
             buf.into()
         };
+        // servers are often vague (`application/octet-stream`) or silent about the type
+        let resp_ty = match declared.as_deref().and_then(MediaType::try_from_mime) {
+            Some(ty) => ty,
+            None => MediaType::sniff(&bytes).with_context(|| match &declared {
+                Some(ty) => format!("{url} has unsupported content type {ty:?}"),
+                None => format!("{url} has no content type, and it could not be guessed"),
+            })?,
+        };
+        if declared.is_none() {
+            debug!("{url} has no content type, guessed {resp_ty:?}");
+        }
+        if resp_ty.is_text() {
+            let declared = declared.as_deref().and_then(charset::from_content_type);
+            meta.charset = charset::detect(declared, &bytes).map(|enc| enc.name().to_owned());
+        }
         self.cache
             .lock()
             .unwrap()
-            .set(url.as_str(), &bytes, resp_ty)?;
+            .set_with_meta(url.as_str(), &bytes, resp_ty, &meta)?;
 
-        trace!("completed request to {domain:?}");
+        trace!("completed request to {url}");
 
         Ok((resp_ty, bytes))
     }
 
+    /// [`Self::call_with_retries`] for `url` and every url it redirects to
+    fn call_following_redirects(
+        &self,
+        url: &Url,
+        cached: Option<&EntryMeta>,
+    ) -> Result<ureq::Response> {
+        let mut url = url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let domain = url.host_str().context("url has no host")?;
+            let robots = self.robots_for(&url)?;
+            if let Some(robots) = &robots {
+                let path = &url[url::Position::BeforePath..url::Position::AfterQuery];
+                ensure!(robots.allows(path), "{url} is disallowed by robots.txt");
+            }
+            let resp = self.call_with_retries(&url, domain, robots.as_deref(), cached)?;
+            if !matches!(resp.status(), 301 | 302 | 303 | 307 | 308) {
+                return Ok(resp);
+            }
+            let location = resp
+                .header("Location")
+                .with_context(|| format!("{url} redirects without a location"))?;
+            let next = url
+                .join(location)
+                .with_context(|| format!("{url} redirects to invalid url {location:?}"))?;
+            debug!("{url} redirects to {next}");
+            url = next;
+        }
+        bail!("gave up at {url} after {MAX_REDIRECTS} redirects")
+    }
+
+    /// send the request, conditional if there is `cached` meta, and retry transient failures
+    /// as [`CrawlConfig::retry`] allows. Earlier attempts are listed in the error
+    fn call_with_retries(
+        &self,
+        url: &Url,
+        domain: &str,
+        robots: Option<&Robots>,
+        cached: Option<&EntryMeta>,
+    ) -> Result<ureq::Response> {
+        let policy = self.crawl.retry;
+        let mut history = Vec::new();
+        let give_up = |e: ureq::Error, history: &[String]| {
+            let e = anyhow::Error::from(e);
+            if history.is_empty() {
+                e
+            } else {
+                e.context(format!(
+                    "gave up on {url} after {} attempts ({})",
+                    history.len() + 1,
+                    history.join("; ")
+                ))
+            }
+        };
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            trace!("getting in line to access {}", domain);
+            wait_your_turn(domain, self.crawl.delay_for(domain, robots));
+            let mut req = self
+                .client
+                .request_url("GET", url)
+                .set("User-Agent", &self.http.user_agent);
+            for (name, value) in HttpConfig::for_host(&self.http.headers, domain) {
+                req = req.set(name, value);
+            }
+            if let Some(cookie) = self.cookie_header(url, domain)? {
+                req = req.set("Cookie", &cookie);
+            }
+            if let Some(meta) = cached {
+                info!("revalidating url {url}");
+                if let Some(etag) = &meta.etag {
+                    req = req.set("If-None-Match", etag);
+                }
+                if let Some(modified) = &meta.last_modified {
+                    req = req.set("If-Modified-Since", modified);
+                }
+            } else {
+                info!("fetching url {url}");
+            }
+
+            let res = req.call();
+            if let Ok(resp) | Err(ureq::Error::Status(_, resp)) = &res {
+                self.store_cookies(url, resp)?;
+            }
+            let e = match res {
+                Ok(resp) => return Ok(resp),
+                Err(e) => e,
+            };
+            if !retry::is_transient(&e) || attempt >= policy.attempts {
+                return Err(give_up(e, &history));
+            }
+            let asked = match &e {
+                ureq::Error::Status(429 | 503, resp) => resp
+                    .header("Retry-After")
+                    .and_then(|v| retry::retry_after(v, SystemTime::now())),
+                _ => None,
+            };
+            let wait = match asked {
+                Some(wait) if wait > policy.max_backoff => {
+                    return Err(give_up(e, &history).context(format!(
+                        "{domain} asked to wait {}s, longer than the maximum backoff",
+                        wait.as_secs()
+                    )));
+                }
+                Some(wait) => wait,
+                None => policy.backoff_after(attempt),
+            };
+            warn!(
+                "attempt {attempt} at {url} failed ({e}), retrying in {:.0}s",
+                wait.as_secs_f32()
+            );
+            history.push(format!("attempt {attempt}: {e}"));
+            // every request to the domain waits, not just this one
+            hold_off(domain, wait);
+        }
+    }
+
+    /// the `Cookie` header for a request to `url`
+    fn cookie_header(&self, url: &Url, domain: &str) -> Result<Option<String>> {
+        let mut cookies: Vec<(String, String)> = HttpConfig::for_host(&self.http.cookies, domain)
+            .cloned()
+            .collect();
+        if let Some(jar) = &self.cookies {
+            let stored = jar.lock().unwrap().cookies_for(url)?;
+            for (name, value) in stored {
+                if !cookies.iter().any(|(n, _)| *n == name) {
+                    cookies.push((name, value));
+                }
+            }
+        }
+        if cookies.is_empty() {
+            return Ok(None);
+        }
+        let header = cookies
+            .iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ");
+        Ok(Some(header))
+    }
+
+    fn store_cookies(&self, url: &Url, resp: &ureq::Response) -> Result<()> {
+        let Some(jar) = &self.cookies else {
+            return Ok(());
+        };
+        let jar = jar.lock().unwrap();
+        for set_cookie in resp.all("Set-Cookie") {
+            jar.store(url, set_cookie)?;
+        }
+        drop(jar);
+        Ok(())
+    }
+
+    /// the robots.txt for the origin of `url`, if robots.txt is obeyed. It is fetched (and
+    /// cached) like any other page, and a missing one allows everything
+    fn robots_for(&self, url: &Url) -> Result<Option<Arc<Robots>>> {
+        if !self.crawl.robots || url.path() == "/robots.txt" {
+            return Ok(None);
+        }
+        let origin = url.origin().ascii_serialization();
+        if let Some(robots) = self.robots.lock().unwrap().get(&origin) {
+            return Ok(Some(robots.clone()));
+        }
+        let robots_url = url.join("/robots.txt")?;
+        let robots = match self.fetch(&robots_url) {
+            Ok((_, bytes)) => {
+                Robots::parse(&String::from_utf8_lossy(&bytes), self.http.robots_agent())
+            }
+            Err(e)
+                if e.downcast_ref::<ureq::Error>()
+                    .is_some_and(|e| matches!(e, ureq::Error::Status(400..500, _))) =>
+            {
+                debug!("{robots_url} is missing, so everything is allowed");
+                // cache it as empty so it is not asked for again
+                self.manual_set_cache(&robots_url, b"", MediaType::Text)?;
+                Robots::default()
+            }
+            Err(e) => return Err(e.context(format!("could not get {robots_url}"))),
+        };
+        let robots = Arc::new(robots);
+        self.robots.lock().unwrap().insert(origin, robots.clone());
+        Ok(Some(robots))
+    }
+
     pub fn manual_set_cache(&self, url: &Url, contents: &[u8], ty: MediaType) -> Result<()> {
         self.cache.lock().unwrap().set(url.as_str(), contents, ty)?;
         Ok(())
@@ -161,4 +621,341 @@ mod tests {
         assert_eq!(ty, MediaType::Html);
         assert_eq!(res, contents.as_bytes());
     }
+
+    #[test]
+    fn cache_location() {
+        assert_eq!(CacheLocation::from_arg(":memory:"), CacheLocation::Memory);
+        let dir = std::env::temp_dir().join(format!("wn3-location-{}", std::process::id()));
+        let path = dir.join("nested").join("cache.db");
+        let loc = CacheLocation::from_arg(path.to_str().unwrap());
+        FetchContext::new(loc.open().unwrap(), ureq::agent()).unwrap();
+        assert!(path.is_file());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn revalidate_due() {
+        let day = Duration::from_secs(24 * 60 * 60);
+        let unknown = EntryMeta::default();
+        let fresh = EntryMeta::now(None, None);
+        let old = EntryMeta {
+            fetched: Some(std::time::SystemTime::now() - 3 * day),
+            ..EntryMeta::default()
+        };
+        assert!(!Revalidate::Never.is_due(&old));
+        assert!(Revalidate::Always.is_due(&fresh));
+        let policy = Revalidate::OlderThan(2 * day);
+        assert!(!policy.is_due(&fresh));
+        assert!(policy.is_due(&old));
+        assert!(policy.is_due(&unknown));
+    }
+
+    #[test]
+    fn offline_refetch_uses_cache() {
+        let a = FetchContext::new_cfg(
+            rusqlite::Connection::open_in_memory().unwrap(),
+            ureq::agent(),
+            true,
+        )
+        .unwrap();
+        let url: Url = "https://localhost".parse().unwrap();
+        a.manual_set_cache(&url, b"old", MediaType::Html).unwrap();
+        a.manual_set_cache(&url, b"new", MediaType::Html).unwrap();
+        let (_, res) = a.refetch(&url).unwrap();
+        assert_eq!(res, "new".as_bytes());
+    }
+
+    /// answer each request on localhost with `respond(path, headers)`, returning the base url
+    /// and the paths requested so far
+    fn serve_with<R: AsRef<[u8]>>(
+        mut respond: impl FnMut(&str, &str) -> R + Send + 'static,
+    ) -> (Url, Arc<Mutex<Vec<String>>>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}/", listener.local_addr().unwrap());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let log = seen.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split(' ').nth(1).unwrap_or("").to_owned();
+                let mut headers = String::new();
+                while reader.read_line(&mut headers).unwrap() > 2 {}
+                log.lock().unwrap().push(path.clone());
+                stream.write_all(respond(&path, &headers).as_ref()).unwrap();
+            }
+        });
+        (base.parse().unwrap(), seen)
+    }
+
+    /// serve `pages` as `(path, content type, body)`, anything else is a 404
+    fn serve(pages: &'static [(&str, &str, &str)]) -> (Url, Arc<Mutex<Vec<String>>>) {
+        serve_with(|path, _| match pages.iter().find(|(p, _, _)| *p == path) {
+            Some((_, ty, body)) => ok(ty, body),
+            None => status("404 Not Found", ""),
+        })
+    }
+
+    fn ok(ty: &str, body: impl AsRef<[u8]>) -> Vec<u8> {
+        let body = body.as_ref();
+        let mut resp = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {ty}\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        resp.extend(body);
+        resp
+    }
+
+    /// an empty response, `headers` are each followed by `\r\n`
+    fn status(status: &str, headers: &str) -> Vec<u8> {
+        format!("HTTP/1.1 {status}\r\n{headers}Content-Length: 0\r\nConnection: close\r\n\r\n")
+            .into_bytes()
+    }
+
+    fn polite_fetch() -> FetchContext {
+        let mut a = FetchContext::new(
+            rusqlite::Connection::open_in_memory().unwrap(),
+            ureq::AgentBuilder::new().redirects(0).build(),
+        )
+        .unwrap();
+        a.crawl = CrawlConfig {
+            default_delay: Duration::ZERO,
+            delays: HashMap::new(),
+            robots: true,
+            retry: RetryPolicy {
+                attempts: 3,
+                backoff: Duration::from_millis(1),
+                max_backoff: Duration::from_secs(1),
+            },
+        };
+        a
+    }
+
+    #[test]
+    fn obeys_robots() {
+        let (base, seen) = serve(&[
+            (
+                "/robots.txt",
+                "text/plain",
+                "User-agent: *\nDisallow: /private\nCrawl-delay: 0\n",
+            ),
+            ("/page", "text/html", "<p>hi</p>"),
+        ]);
+        let a = polite_fetch();
+        let (ty, body) = a.fetch(&base.join("page").unwrap()).unwrap();
+        assert_eq!(ty, MediaType::Html);
+        assert_eq!(body, "<p>hi</p>".as_bytes());
+        let err = a.fetch(&base.join("private/1").unwrap()).unwrap_err();
+        assert!(
+            err.to_string().contains("disallowed by robots.txt"),
+            "{err}"
+        );
+        assert_eq!(*seen.lock().unwrap(), ["/robots.txt", "/page"]);
+
+        // robots.txt is cached along with the pages
+        let (ty, _) = a
+            .fetch_local(base.join("robots.txt").unwrap().as_str())
+            .unwrap();
+        assert_eq!(ty, MediaType::Text);
+    }
+
+    #[test]
+    fn missing_robots_allows_all() {
+        let (base, seen) = serve(&[("/private/1", "text/html", "<p>hi</p>")]);
+        let a = polite_fetch();
+        a.fetch(&base.join("private/1").unwrap()).unwrap();
+        assert_eq!(*seen.lock().unwrap(), ["/robots.txt", "/private/1"]);
+        let (_, body) = a
+            .fetch_local(base.join("robots.txt").unwrap().as_str())
+            .unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn retries_transient() {
+        let mut n = 0;
+        let (base, seen) = serve_with(move |path, _| {
+            n += 1;
+            match (path, n) {
+                ("/robots.txt", _) => status("404 Not Found", ""),
+                (_, 2) => status("503 Service Unavailable", "Retry-After: 0\r\n"),
+                (_, 3) => status("500 Internal Server Error", ""),
+                _ => ok("text/html", "<p>hi</p>"),
+            }
+        });
+        let a = polite_fetch();
+        let (_, body) = a.fetch(&base.join("page").unwrap()).unwrap();
+        assert_eq!(body, "<p>hi</p>".as_bytes());
+        assert_eq!(seen.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn vague_content_type() {
+        let (base, _) = serve_with(|path, _| match path {
+            "/image" => ok("application/octet-stream", "GIF89a"),
+            "/page" => "HTTP/1.1 200 OK\r\nContent-Length: 15\r\nConnection: close\r\n\r\n\
+                        <!DOCTYPE html>"
+                .into(),
+            "/doc" => ok("application/pdf", "%PDF-1.4"),
+            _ => status("404 Not Found", ""),
+        });
+        let mut a = polite_fetch();
+        a.crawl.robots = false;
+        let (ty, _) = a.fetch(&base.join("image").unwrap()).unwrap();
+        assert_eq!(ty, MediaType::Gif);
+        let (ty, _) = a.fetch(&base.join("page").unwrap()).unwrap();
+        assert_eq!(ty, MediaType::Html);
+        let err = a.fetch(&base.join("doc").unwrap()).unwrap_err();
+        assert!(
+            err.to_string().contains("unsupported content type"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn decodes_charset() {
+        let (base, _) = serve_with(|path, _| {
+            // "日本語" in Shift_JIS
+            let body: &[u8] = b"<p>\x93\xfa\x96\x7b\x8c\xea</p>";
+            let ty = match path {
+                "/header" => "text/html; charset=Shift_JIS",
+                _ => "text/html",
+            };
+            ok(ty, body)
+        });
+        let mut a = polite_fetch();
+        a.crawl.robots = false;
+        let url = base.join("header").unwrap();
+        let (_, text) = a.fetch_text(&url).unwrap();
+        assert_eq!(text, "<p>日本語</p>");
+        let meta = a.cache.lock().unwrap().meta(url.as_str()).unwrap().unwrap();
+        assert_eq!(meta.charset.as_deref(), Some("Shift_JIS"));
+
+        // nothing says what it is
+        let err = a.fetch_text(&base.join("none").unwrap()).unwrap_err();
+        assert!(err.to_string().contains("not valid utf-8"), "{err}");
+    }
+
+    #[test]
+    fn prefetches_once() {
+        let (base, seen) = serve(&[("/1", "text/html", "one"), ("/2", "text/html", "two")]);
+        let mut a = polite_fetch();
+        a.crawl.robots = false;
+        a.start_prefetch(2);
+        let urls = ["1", "2", "1"].map(|p| base.join(p).unwrap());
+        a.prefetch(urls.clone());
+        for url in &urls {
+            a.fetch(url).unwrap();
+        }
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+        assert_eq!(seen, ["/1", "/2"]);
+    }
+
+    #[test]
+    fn sends_cookies_and_headers() {
+        let (base, _) = serve_with(|path, headers| match path {
+            "/login" => "HTTP/1.1 200 OK\r\nSet-Cookie: session=abc; Path=/\r\n\
+                         Content-Type: text/plain\r\nContent-Length: 0\r\n\
+                         Connection: close\r\n\r\n"
+                .into(),
+            // echo the request headers back
+            _ => ok("text/plain", headers),
+        });
+        let mut a = polite_fetch();
+        a.crawl.robots = false;
+        a.set_cookie_jar(CookieJar::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap());
+        a.http.user_agent = "tester/1.0".into();
+        let pair = |k: &str, v: &str| vec![(k.to_owned(), v.to_owned())];
+        a.http
+            .headers
+            .insert("127.0.0.1".into(), pair("X-Test", "1"));
+        a.http
+            .cookies
+            .insert("127.0.0.1".into(), pair("over18", "yes"));
+        a.http.cookies.insert("other.com".into(), pair("nope", "1"));
+
+        a.fetch(&base.join("login").unwrap()).unwrap();
+        let (_, echo) = a.fetch(&base.join("echo").unwrap()).unwrap();
+        let echo = String::from_utf8_lossy(&echo).to_ascii_lowercase();
+        assert!(echo.contains("user-agent: tester/1.0\r\n"), "{echo}");
+        assert!(echo.contains("x-test: 1\r\n"), "{echo}");
+        assert!(
+            echo.contains("cookie: over18=yes; session=abc\r\n"),
+            "{echo}"
+        );
+    }
+
+    #[test]
+    fn cookies_through_redirects() {
+        let (base, seen) = serve_with(|path, headers| match path {
+            "/login" => status(
+                "302 Found",
+                "Set-Cookie: session=abc; Path=/\r\nLocation: /home\r\n",
+            ),
+            "/loop" => status("301 Moved Permanently", "Location: /loop\r\n"),
+            _ => ok("text/plain", headers),
+        });
+        let mut a = polite_fetch();
+        a.crawl.robots = false;
+        a.set_cookie_jar(CookieJar::new(rusqlite::Connection::open_in_memory().unwrap()).unwrap());
+        let login = base.join("login").unwrap();
+        let (_, echo) = a.fetch(&login).unwrap();
+        let echo = String::from_utf8_lossy(&echo).to_ascii_lowercase();
+        assert!(echo.contains("cookie: session=abc\r\n"), "{echo}");
+        assert_eq!(*seen.lock().unwrap(), ["/login", "/home"]);
+        // cached under the url that was asked for
+        a.fetch_local(login.as_str()).unwrap();
+
+        let err = a.fetch(&base.join("loop").unwrap()).unwrap_err();
+        assert!(err.to_string().contains("after 5 redirects"), "{err}");
+    }
+
+    #[test]
+    fn gives_up() {
+        let (base, seen) = serve_with(|path, _| match path {
+            "/busy" => status("429 Too Many Requests", "Retry-After: 3600\r\n"),
+            "/broken" => status("502 Bad Gateway", ""),
+            _ => status("404 Not Found", ""),
+        });
+        let mut a = polite_fetch();
+        a.crawl.robots = false;
+
+        let err = a.fetch(&base.join("broken").unwrap()).unwrap_err();
+        let msg = format!("{err:#}");
+        assert!(msg.contains("after 3 attempts"), "{msg}");
+        assert!(msg.contains("attempt 2: "), "{msg}");
+        assert_eq!(seen.lock().unwrap().len(), 3);
+
+        // client errors are not retried
+        let err = a.fetch(&base.join("missing").unwrap()).unwrap_err();
+        assert!(!format!("{err:#}").contains("attempts"), "{err:#}");
+        assert_eq!(seen.lock().unwrap().len(), 4);
+
+        let err = a.fetch(&base.join("busy").unwrap()).unwrap_err();
+        assert!(err.to_string().contains("asked to wait 3600s"), "{err}");
+        assert_eq!(seen.lock().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn host_delay_beats_robots() {
+        let mut cfg = CrawlConfig::default();
+        let robots = Robots::parse("User-agent: *\nCrawl-delay: 3", "wn-scraper3");
+        assert_eq!(cfg.delay_for("a.com", None), CRAWL_DELAY);
+        assert_eq!(
+            cfg.delay_for("a.com", Some(&robots)),
+            Duration::from_secs(3)
+        );
+        cfg.delays.insert("a.com".into(), Duration::from_secs(1));
+        assert_eq!(
+            cfg.delay_for("a.com", Some(&robots)),
+            Duration::from_secs(1)
+        );
+    }
 }
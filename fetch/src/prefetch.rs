@@ -0,0 +1,185 @@
+//! fetching urls that will be needed soon on a few worker threads. Each host is served by at
+//! most one worker at a time, so its crawl delay still holds while different hosts are fetched
+//! concurrently
+
+use std::{
+    collections::{BTreeMap, HashSet, VecDeque},
+    sync::{Arc, Condvar, Mutex},
+};
+
+use log::debug;
+use url::Url;
+
+use crate::FetchContext;
+
+#[derive(Default)]
+struct Queue {
+    /// urls waiting to be fetched, by host
+    waiting: BTreeMap<String, VecDeque<Url>>,
+    /// hosts a worker is fetching from
+    busy: HashSet<String>,
+    /// every url ever queued, so each is only fetched once
+    seen: HashSet<Url>,
+    closed: bool,
+}
+
+impl Queue {
+    fn push(&mut self, url: Url) {
+        let Some(host) = url.host_str() else { return };
+        let host = host.to_owned();
+        if self.seen.insert(url.clone()) {
+            self.waiting.entry(host).or_default().push_back(url);
+        }
+    }
+
+    /// the next url from a host no worker is fetching from, marking the host busy
+    fn take(&mut self) -> Option<(String, Url)> {
+        let host = self
+            .waiting
+            .keys()
+            .find(|host| !self.busy.contains(*host))?
+            .clone();
+        let urls = self.waiting.get_mut(&host).expect("host was just found");
+        let url = urls.pop_front().expect("hosts without urls are removed");
+        if urls.is_empty() {
+            self.waiting.remove(&host);
+        }
+        self.busy.insert(host.clone());
+        Some((host, url))
+    }
+}
+
+type Shared = Arc<(Mutex<Queue>, Condvar)>;
+
+pub(crate) struct Prefetcher {
+    queue: Shared,
+}
+
+impl Prefetcher {
+    /// start `workers` threads fetching with `fetch`, which should not prefetch itself
+    pub fn new(fetch: &FetchContext, workers: usize) -> Self {
+        let queue = Shared::default();
+        for _ in 0..workers {
+            let queue = queue.clone();
+            let fetch = fetch.clone();
+            std::thread::spawn(move || work(&queue, &fetch));
+        }
+        Prefetcher { queue }
+    }
+
+    pub fn push(&self, urls: impl IntoIterator<Item = Url>) {
+        let (lock, cvar) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        for url in urls {
+            if matches!(url.scheme(), "http" | "https") {
+                queue.push(url);
+            }
+        }
+        drop(queue);
+        cvar.notify_all();
+    }
+}
+
+impl Drop for Prefetcher {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.queue;
+        let mut queue = lock.lock().unwrap();
+        queue.closed = true;
+        queue.waiting.clear();
+        drop(queue);
+        cvar.notify_all();
+    }
+}
+
+/// urls being fetched right now, so that fetching one twice at once makes a single request
+#[derive(Default)]
+pub(crate) struct InFlight {
+    urls: Mutex<HashSet<Url>>,
+    done: Condvar,
+}
+
+impl InFlight {
+    /// claim `url` until the returned guard is dropped. If it is already claimed, wait until it
+    /// is done and return `None`
+    pub fn claim(&self, url: &Url) -> Option<Claim<'_>> {
+        let mut urls = self.urls.lock().unwrap();
+        if urls.insert(url.clone()) {
+            drop(urls);
+            return Some(Claim {
+                inflight: self,
+                url: url.clone(),
+            });
+        }
+        while urls.contains(url) {
+            urls = self.done.wait(urls).unwrap();
+        }
+        drop(urls);
+        None
+    }
+}
+
+pub(crate) struct Claim<'a> {
+    inflight: &'a InFlight,
+    url: Url,
+}
+
+impl Drop for Claim<'_> {
+    fn drop(&mut self) {
+        self.inflight.urls.lock().unwrap().remove(&self.url);
+        self.inflight.done.notify_all();
+    }
+}
+
+fn work(queue: &Shared, fetch: &FetchContext) {
+    let (lock, cvar) = &**queue;
+    loop {
+        let (host, url) = {
+            let mut queue = lock.lock().unwrap();
+            loop {
+                if queue.closed {
+                    return;
+                }
+                if let Some(job) = queue.take() {
+                    break job;
+                }
+                queue = cvar.wait(queue).unwrap();
+            }
+        };
+        // whoever needs it will report the error
+        if let Err(e) = fetch.fetch(&url) {
+            debug!("prefetching {url} failed: {e:#}");
+        }
+        lock.lock().unwrap().busy.remove(&host);
+        cvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_worker_per_host() {
+        let mut q = Queue::default();
+        for u in [
+            "https://a.com/1",
+            "https://a.com/2",
+            "https://b.com/1",
+            "https://a.com/1",
+        ] {
+            q.push(u.parse().unwrap());
+        }
+        let (a, a1) = q.take().unwrap();
+        assert_eq!(a1.as_str(), "https://a.com/1");
+        let (b, _) = q.take().unwrap();
+        assert_eq!(b, "b.com");
+        // a.com is busy
+        assert_eq!(q.take(), None);
+        q.busy.remove(&a);
+        let (_, a2) = q.take().unwrap();
+        assert_eq!(a2.as_str(), "https://a.com/2");
+        q.busy.clear();
+        // a.com/1 was already queued once
+        assert_eq!(q.take(), None);
+    }
+}
@@ -1,6 +1,5 @@
 use std::{
     collections::BTreeMap,
-    ops::DerefMut,
     sync::{Arc, Mutex as StdMutex},
     time::{Duration, Instant},
 };
@@ -13,49 +12,92 @@ type Rls = StdMutex<BTreeMap<Box<str>, RateLimit>>;
 
 static LIMITS: Rls = Rls::new(BTreeMap::new());
 
+struct Interval {
+    /// when the last request was or will be let through, `None` before the first
+    last: Option<Instant>,
+    period: Duration,
+    /// no request is let through before this, e.g. because of a `Retry-After`
+    not_before: Option<Instant>,
+}
+
 #[derive(Clone)]
 struct RateLimit {
-    interval: Arc<StdMutex<(Instant, Duration)>>,
+    interval: Arc<StdMutex<Interval>>,
 }
 
 impl RateLimit {
     pub fn new(period: Duration) -> Self {
         Self {
-            interval: Arc::new(StdMutex::new((Instant::now() - period, period))),
+            interval: Arc::new(StdMutex::new(Interval {
+                last: None,
+                period,
+                not_before: None,
+            })),
         }
     }
 
-    pub fn acquire(&self) {
-        let mut lock = self.interval.lock().unwrap();
-        let &mut (ref mut last, dur) = lock.deref_mut();
+    /// take the next turn and sleep until it comes. The turn is taken before sleeping, so
+    /// other threads are not held up while waiting
+    pub fn acquire(&self, period: Duration) {
+        let mut interval = self.interval.lock().unwrap();
+        // the period can change once robots.txt is known
+        interval.period = period;
         let now = Instant::now();
-        let elapsed = now.duration_since(*last);
-        if elapsed < dur {
-            let wait = dur - elapsed;
+        let ready = interval
+            .last
+            .map(|l| l + interval.period)
+            .max(interval.not_before)
+            .map_or(now, |ready| ready.max(now));
+        interval.last = Some(ready);
+        drop(interval);
+        let wait = ready - now;
+        if !wait.is_zero() {
             info!("waiting for {:.2} seconds", wait.as_secs_f32());
             std::thread::sleep(wait);
-            *last = Instant::now();
-        } else {
-            *last = now;
         }
-        drop(lock)
     }
 }
 
-fn get_limiter(s: &str, default_period: Duration) -> RateLimit {
-    let mut lock = LIMITS.lock().unwrap();
-    if let Some(l) = lock.get(s) {
-        l.clone()
-    } else {
-        let l = RateLimit::new(default_period);
-        lock.insert(s.into(), l.clone());
-        l
-    }
+fn get_limiter(s: &str, period: Duration) -> RateLimit {
+    LIMITS
+        .lock()
+        .unwrap()
+        .entry(s.into())
+        .or_insert_with(|| RateLimit::new(period))
+        .clone()
+}
+
+/// wait until `period` has passed since the last request to `s`
+pub fn wait_your_turn(s: &str, period: Duration) {
+    get_limiter(s, period).acquire(period)
 }
 
-pub fn wait_your_turn(s: &str, default_period: Duration) {
-    get_limiter(s, default_period).acquire()
+/// make the next request to `s` wait at least `wait` from now
+pub fn hold_off(s: &str, wait: Duration) {
+    // the period is set by the next `wait_your_turn`
+    let limiter = get_limiter(s, Duration::ZERO);
+    let mut interval = limiter.interval.lock().unwrap();
+    interval.not_before = interval.not_before.max(Some(Instant::now() + wait));
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hosts_wait_apart() {
+        let period = Duration::from_millis(300);
+        wait_your_turn("a.test", period);
+        let start = Instant::now();
+        let waiting = std::thread::spawn(move || {
+            wait_your_turn("a.test", period);
+            wait_your_turn("a.test", period);
+        });
+        std::thread::sleep(Duration::from_millis(50));
+        // not held up by the thread waiting for a.test
+        wait_your_turn("b.test", period);
+        assert!(start.elapsed() < Duration::from_millis(250));
+        waiting.join().unwrap();
+        assert!(start.elapsed() >= 2 * period - Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,141 @@
+//! deciding when a failed request is worth repeating, and how long to wait first
+
+use std::time::{Duration, SystemTime};
+
+/// how often, and how patiently, failed requests are retried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// attempts in total, including the first
+    pub attempts: u32,
+    /// wait before the first retry, doubled for every retry after it
+    pub backoff: Duration,
+    /// the longest wait between two attempts, also the longest `Retry-After` that is honored
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 4,
+            backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// the wait after failed attempt number `attempt` (1-based)
+    pub fn backoff_after(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.backoff
+            .checked_mul(factor)
+            .map_or(self.max_backoff, |d| d.min(self.max_backoff))
+    }
+}
+
+/// whether an error could go away by trying again
+pub fn is_transient(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+        ureq::Error::Transport(t) => matches!(
+            t.kind(),
+            ureq::ErrorKind::Dns
+                | ureq::ErrorKind::ConnectionFailed
+                | ureq::ErrorKind::Io
+                | ureq::ErrorKind::ProxyConnect
+        ),
+    }
+}
+
+/// the wait asked for by a `Retry-After` header, either in seconds or as an HTTP date
+pub fn retry_after(val: &str, now: SystemTime) -> Option<Duration> {
+    let val = val.trim();
+    if let Ok(secs) = val.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = parse_http_date(val)?;
+    Some(at.duration_since(now).unwrap_or_default())
+}
+
+/// parse an IMF-fixdate like `Sun, 06 Nov 1994 08:49:37 GMT`, the only format servers should
+/// send. Dates that can't be represented give `None`
+pub(crate) fn parse_http_date(s: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (_weekday, rest) = s.split_once(", ")?;
+    let mut parts = rest.split(' ');
+    let day: u64 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let year: u64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|x| x.parse::<u64>().ok());
+    let (h, m, sec) = (time.next()??, time.next()??, time.next()??);
+    if parts.next()? != "GMT" || parts.next().is_some() || year < 1970 {
+        return None;
+    }
+    if !(1..=31).contains(&day) || h > 23 || m > 59 || sec > 60 {
+        return None;
+    }
+    // days since the epoch of a proleptic gregorian date, from Howard Hinnant's `days_from_civil`
+    let (y, mo) = if month <= 2 {
+        (year - 1, month + 9)
+    } else {
+        (year, month - 3)
+    };
+    let era = y / 400;
+    let yoe = y % 400;
+    let doy = (153 * mo + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era
+        .checked_mul(146_097)?
+        .checked_add(doe)?
+        .checked_sub(719_468)?;
+    let secs = days
+        .checked_mul(86400)?
+        .checked_add(h * 3600 + m * 60 + sec)?;
+    SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles() {
+        let p = RetryPolicy {
+            attempts: 10,
+            backoff: Duration::from_secs(2),
+            max_backoff: Duration::from_secs(10),
+        };
+        let waits: Vec<_> = (1..=5).map(|a| p.backoff_after(a).as_secs()).collect();
+        assert_eq!(waits, [2, 4, 8, 10, 10]);
+        assert_eq!(p.backoff_after(u32::MAX), Duration::from_secs(10));
+    }
+
+    #[test]
+    fn retry_after_header() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_770);
+        assert_eq!(retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_after("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::from_secs(7))
+        );
+        // already passed
+        assert_eq!(
+            retry_after("Sat, 05 Nov 1994 08:49:37 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("soon", now), None);
+        assert_eq!(retry_after("Sun, 06 Nov 1994 08:49:37 PST", now), None);
+        assert_eq!(
+            retry_after("Sun, 06 Nov 300000000000 08:49:37 GMT", now),
+            None
+        );
+        assert_eq!(
+            retry_after(&format!("Sun, 06 Nov {} 08:49:37 GMT", u64::MAX), now),
+            None
+        );
+        assert_eq!(retry_after("Sun, 00 Nov 1994 08:49:37 GMT", now), None);
+    }
+}
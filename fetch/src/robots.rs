@@ -0,0 +1,168 @@
+//! just enough of robots.txt (RFC 9309) to stay out of disallowed paths, plus the non-standard
+//! `Crawl-delay`
+
+use std::time::Duration;
+
+/// the rules of the group that applies to us
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Robots {
+    /// `(allow, pattern)`
+    rules: Vec<(bool, String)>,
+    pub crawl_delay: Option<Duration>,
+}
+
+impl Robots {
+    /// parse robots.txt, using the group for `agent` if there is one and `*` otherwise
+    pub fn parse(txt: &str, agent: &str) -> Self {
+        let agent = agent.to_ascii_lowercase();
+        let mut ours = None;
+        let mut any = None;
+        // agents of the group being read, and whether its rules have started
+        let mut agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+        let mut group = Robots::default();
+        let mut finish = |agents: &[String], group: Robots| {
+            if agents
+                .iter()
+                .any(|a| !a.is_empty() && a != "*" && agent.starts_with(a.as_str()))
+            {
+                ours.get_or_insert(group);
+            } else if agents.iter().any(|a| a == "*") {
+                any.get_or_insert(group);
+            }
+        };
+        for line in txt.lines() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let Some((key, val)) = line.split_once(':') else {
+                continue;
+            };
+            let val = val.trim();
+            match key.trim().to_ascii_lowercase().as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        finish(&agents, std::mem::take(&mut group));
+                        agents.clear();
+                        in_rules = false;
+                    }
+                    agents.push(val.to_ascii_lowercase());
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    // an empty disallow allows everything
+                    if !val.is_empty() {
+                        group
+                            .rules
+                            .push((key.trim().eq_ignore_ascii_case("allow"), val.into()));
+                    }
+                }
+                "crawl-delay" => {
+                    in_rules = true;
+                    group.crawl_delay = val
+                        .parse::<f64>()
+                        .ok()
+                        .and_then(|d| Duration::try_from_secs_f64(d).ok());
+                }
+                _ => (),
+            }
+        }
+        finish(&agents, group);
+        ours.or(any).unwrap_or_default()
+    }
+
+    /// whether `path` (with its query) may be fetched. The longest matching rule wins, and
+    /// allow wins ties
+    pub fn allows(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|(_, pat)| pattern_matches(pat, path))
+            .max_by_key(|(allow, pat)| (pat.len(), *allow))
+            .is_none_or(|(allow, _)| *allow)
+    }
+}
+
+/// match with `*` as any run of characters and a trailing `$` anchoring the end
+fn pattern_matches(pat: &str, path: &str) -> bool {
+    let (pat, anchored) = match pat.strip_suffix('$') {
+        Some(p) => (p, true),
+        None => (pat, false),
+    };
+    let parts: Vec<&str> = pat.split('*').collect();
+    let Some(mut rest) = path.strip_prefix(parts[0]) else {
+        return false;
+    };
+    let Some((last, middle)) = parts[1..].split_last() else {
+        return !anchored || rest.is_empty();
+    };
+    for part in middle {
+        let Some(at) = rest.find(part) else {
+            return false;
+        };
+        rest = &rest[at + part.len()..];
+    }
+    if anchored {
+        rest.ends_with(last)
+    } else {
+        rest.contains(last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TXT: &str = "
+# comment
+User-agent: *
+Disallow: /private/
+Allow: /private/open
+Crawl-delay: 2.5
+
+User-agent: wn-scraper3
+User-agent: other
+Disallow: /search
+Disallow: /*.php$
+Crawl-delay: 1
+";
+
+    #[test]
+    fn groups() {
+        let any = Robots::parse(TXT, "somebot");
+        assert_eq!(any.crawl_delay, Some(Duration::from_millis(2500)));
+        assert!(!any.allows("/private/x"));
+        assert!(any.allows("/private/open/x"));
+        assert!(any.allows("/search"));
+
+        let ours = Robots::parse(TXT, "wn-scraper3/0.0.1");
+        assert_eq!(ours.crawl_delay, Some(Duration::from_secs(1)));
+        assert!(ours.allows("/private/x"));
+        assert!(!ours.allows("/search?q=1"));
+        assert!(!ours.allows("/a/index.php"));
+        assert!(ours.allows("/a/index.php?x"));
+    }
+
+    #[test]
+    fn empty() {
+        let r = Robots::parse("User-agent: *\nDisallow:\n", "bot");
+        assert!(r.allows("/"));
+        assert_eq!(Robots::parse("", "bot"), Robots::default());
+    }
+
+    #[test]
+    fn bad_crawl_delay() {
+        for delay in ["1e30", "-1", "NaN", "inf", "soon"] {
+            let r = Robots::parse(&format!("User-agent: *\nCrawl-delay: {delay}\n"), "bot");
+            assert_eq!(r.crawl_delay, None, "{delay}");
+        }
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(pattern_matches("/a", "/abc"));
+        assert!(pattern_matches("/a*c", "/abbc/d"));
+        assert!(pattern_matches("/a$", "/a"));
+        assert!(!pattern_matches("/a$", "/ab"));
+        assert!(pattern_matches("/*.html$", "/x/y.html"));
+        assert!(!pattern_matches("/*.html$", "/x/y.htmlx"));
+        assert!(!pattern_matches("/b", "/abc"));
+    }
+}
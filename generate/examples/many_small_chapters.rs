@@ -1,4 +1,16 @@
-use generate::{chapter::ChapterBuilder, epub::EpubBuilder};
+//! builds a book out of many parsed pages and prints the peak resident set size, to keep an eye
+//! on memory use. Pass `leak` to keep every page's document alive for as long as the book, as
+//! chapters borrowing from it used to require
+//!
+//! ```sh
+//! cargo run --release --example many_small_chapters [leak]
+//! ```
+
+use generate::{
+    chapter::{Chapter, ChapterBuilder},
+    epub::EpubBuilder,
+};
+use scraper::{Html, Selector};
 
 const LOREM: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit, sed do eiusmod tempor
 incididunt ut labore et dolore magna aliqua. Ut enim ad minim veniam, quis nostrud exercitation
@@ -6,7 +18,43 @@ ullamco laboris nisi ut aliquip ex ea commodo consequat. Duis aute irure dolor i
 voluptate velit esse cillum dolore eu fugiat nulla pariatur. Excepteur sint occaecat cupidatat non
 proident, sunt in culpa qui officia deserunt mollit anim id est laborum.";
 
+/// a page with a bit of the markup real sites wrap their chapters in
+fn page(i: usize) -> String {
+    let mut s = format!("<html><head><title>Chapter {i}</title></head><body><nav>");
+    for n in 0..20 {
+        s += &format!("<a class=\"nav-link\" href=\"/chapter/{n}\">Chapter {n}</a>");
+    }
+    s += "</nav><div class=\"entry-content\">";
+    for _ in 0..4 {
+        s += &format!("<p class=\"text\">{LOREM}</p>");
+    }
+    s + "</div></body></html>"
+}
+
+/// the chapter in `html`, borrowing its text
+fn chapter(i: usize, html: &Html) -> Chapter<'_> {
+    let p = Selector::parse("div.entry-content > p").unwrap();
+    let mut ch = ChapterBuilder::new();
+    ch.preserve_line_feeds(true)
+        .title_set(format!("Chapter {i}"));
+    for el in html.select(&p) {
+        for text in el.text() {
+            ch.add_text(text);
+        }
+        ch.paragraph_finish();
+    }
+    ch.finish().unwrap().swap_remove(0)
+}
+
+/// `VmHWM` of this process, in KiB
+fn peak_rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    line.split_whitespace().nth(1)?.parse().ok()
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let leak = std::env::args().nth(1).is_some_and(|a| a == "leak");
     let mut b = EpubBuilder::new();
     b.add_identifier(generate::epub::IdentifierType::Adhoc, "example")
         .set_title("A book with many chapters")
@@ -14,13 +62,13 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .set_chunk_size(10_000);
 
     for i in 0..5_000 {
-        let mut ch = ChapterBuilder::new();
-        ch.preserve_line_feeds(true)
-            .title_set(format!("Chapter {i}"));
-
-        ch.add_text(LOREM);
-
-        b.add_chapter(ch.finish().unwrap().swap_remove(0));
+        let html = Html::parse_document(&page(i));
+        let ch = if leak {
+            chapter(i, Box::leak(Box::new(html)))
+        } else {
+            chapter(i, &html).into_owned()
+        };
+        b.add_chapter(ch);
     }
 
     let out = std::fs::OpenOptions::new()
@@ -30,5 +78,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .truncate(true)
         .open("many_small.epub")?;
     b.finish(out)?;
+    match peak_rss() {
+        Some(kib) => println!("peak RSS: {} MiB", kib / 1024),
+        None => println!("peak RSS is only known on Linux"),
+    }
     Ok(())
 }
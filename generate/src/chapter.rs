@@ -1,5 +1,5 @@
 use ahash::{HashMap, HashMapExt};
-use anyhow::{Context, ensure};
+use anyhow::{Context, anyhow, ensure};
 use log::{log_enabled, warn};
 use ser::SerChapter;
 use url::Url;
@@ -24,9 +24,105 @@ enum MajorElement<'a> {
     ImageResolved(Rc<ResolvedImage>),
     SceneSep(Box<str>),
     HorizLine,
+    List {
+        kind: ListKind,
+        items: Vec<ListItem<'a>>,
+    },
+    Table(Box<Table<'a>>),
+}
+
+/// kind of list, as in `<ol>` or `<ul>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ListKind {
+    #[default]
+    Unordered,
+    Ordered,
+}
+
+/// a single `<li>`, which can contain any major element (including more lists)
+#[derive(Debug)]
+struct ListItem<'a>(Vec<MajorElement<'a>>);
+
+#[derive(Debug, Default)]
+struct Table<'a> {
+    /// `<caption>`, or any other stray text inside of the table
+    caption: Vec<InlineElement<'a>>,
+    head: Vec<TableRow<'a>>,
+    body: Vec<TableRow<'a>>,
+}
+
+impl Table<'_> {
+    fn rows(&self) -> impl Iterator<Item = &TableRow<'_>> {
+        self.head.iter().chain(&self.body)
+    }
+
+    fn into_owned(self) -> Table<'static> {
+        let rows = |rows: Vec<TableRow>| {
+            rows.into_iter()
+                .map(|row| TableRow(row.0.into_iter().map(TableCell::into_owned).collect()))
+                .collect()
+        };
+        Table {
+            caption: inline_into_owned(self.caption),
+            head: rows(self.head),
+            body: rows(self.body),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TableRow<'a>(Vec<TableCell<'a>>);
+
+/// the largest spans, as in the HTML spec
+const MAX_COLSPAN: u32 = 1000;
+const MAX_ROWSPAN: u32 = 65534;
+
+#[derive(Debug)]
+struct TableCell<'a> {
+    /// `<th>` instead of `<td>`
+    header: bool,
+    colspan: u32,
+    rowspan: u32,
+    elms: Vec<InlineElement<'a>>,
+}
+
+impl TableCell<'_> {
+    fn into_owned(self) -> TableCell<'static> {
+        TableCell {
+            header: self.header,
+            colspan: self.colspan,
+            rowspan: self.rowspan,
+            elms: inline_into_owned(self.elms),
+        }
+    }
+}
+
+/// a table that is currently being built by [`ChapterBuilder`]
+#[derive(Debug, Default)]
+struct TableFrame<'a> {
+    table: Table<'a>,
+    /// `(is head, cells)`
+    row: Option<(bool, Vec<TableCell<'a>>)>,
+    /// cell without content, the content is in `current_p`
+    cell: Option<TableCell<'a>>,
+    /// separators and images met inside the table, which can only hold inline elements, so
+    /// they go after it
+    after: Vec<MajorElement<'a>>,
+    /// nested tables are flattened into the outer table's cells
+    depth: u32,
 }
 
-impl MajorElement<'_> {
+/// a list that is currently being built by [`ChapterBuilder`]
+#[derive(Debug)]
+struct ListFrame<'a> {
+    kind: ListKind,
+    items: Vec<ListItem<'a>>,
+    /// the complete elements of the enclosing scope
+    outer: Vec<MajorElement<'a>>,
+    in_item: bool,
+}
+
+impl<'a> MajorElement<'a> {
     /// approx printed size in bytes
     fn size(&self) -> usize {
         match self {
@@ -35,6 +131,72 @@ impl MajorElement<'_> {
             MajorElement::ImageResolved(_) => 64,
             MajorElement::SceneSep(l) => 8 + l.len(),
             MajorElement::HorizLine => 8,
+            MajorElement::List { items, .. } => {
+                items.iter().flat_map(|i| &i.0).map(|e| e.size() + 8).sum()
+            }
+            MajorElement::Table(t) => t
+                .rows()
+                .flat_map(|r| &r.0)
+                .map(|c| c.elms.iter().map(|e| e.size()).sum::<usize>() + 16)
+                .sum(),
+        }
+    }
+
+    fn into_owned(self) -> MajorElement<'static> {
+        match self {
+            MajorElement::Paragraph { style, elms } => MajorElement::Paragraph {
+                style,
+                elms: inline_into_owned(elms),
+            },
+            MajorElement::Image(id) => MajorElement::Image(id),
+            MajorElement::ImageResolved(img) => MajorElement::ImageResolved(img),
+            MajorElement::SceneSep(sep) => MajorElement::SceneSep(sep),
+            MajorElement::HorizLine => MajorElement::HorizLine,
+            MajorElement::List { kind, items } => MajorElement::List {
+                kind,
+                items: items
+                    .into_iter()
+                    .map(|item| ListItem(item.0.into_iter().map(Self::into_owned).collect()))
+                    .collect(),
+            },
+            MajorElement::Table(t) => MajorElement::Table(Box::new(t.into_owned())),
+        }
+    }
+
+    /// calls `f` on self and every nested major element
+    fn visit_mut(&mut self, f: &mut impl FnMut(&mut Self)) {
+        f(self);
+        if let MajorElement::List { items, .. } = self {
+            for el in items.iter_mut().flat_map(|i| &mut i.0) {
+                el.visit_mut(f);
+            }
+        }
+    }
+
+    /// calls `f` on every inline element, including those in nested major elements
+    fn visit_inline_mut(&mut self, f: &mut impl FnMut(&mut InlineElement<'a>)) {
+        match self {
+            MajorElement::Paragraph { elms, .. } => elms.iter_mut().for_each(f),
+            MajorElement::List { items, .. } => {
+                for el in items.iter_mut().flat_map(|i| &mut i.0) {
+                    el.visit_inline_mut(f);
+                }
+            }
+            MajorElement::Table(t) => {
+                let Table {
+                    caption,
+                    head,
+                    body,
+                } = &mut **t;
+                caption.iter_mut().for_each(&mut *f);
+                for cell in head.iter_mut().chain(body).flat_map(|r| &mut r.0) {
+                    cell.elms.iter_mut().for_each(&mut *f);
+                }
+            }
+            MajorElement::Image(_)
+            | MajorElement::ImageResolved(_)
+            | MajorElement::SceneSep(_)
+            | MajorElement::HorizLine => (),
         }
     }
 }
@@ -42,7 +204,18 @@ impl MajorElement<'_> {
 #[derive(Debug, Clone)]
 pub struct Link<'a> {
     pub href: Url,
-    pub text: Cow<'a, str>,
+    /// the linked text, which may be styled but holds no further links
+    pub elms: Vec<InlineElement<'a>>,
+}
+
+impl Link<'_> {
+    fn into_owned(self: Rc<Self>) -> Rc<Link<'static>> {
+        let Link { href, elms } = Rc::unwrap_or_clone(self);
+        Rc::new(Link {
+            href,
+            elms: inline_into_owned(elms),
+        })
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -50,9 +223,36 @@ pub enum InlineElement<'a> {
     EnableStyles(SpanStyle),
     DisableStyles(SpanStyle),
     ExternalLink(Rc<Link<'a>>),
+    /// link that points inside of the book if `href` is the source of one of its chapters, see
+    /// [`Chapter::resolve_links`]
+    ChapterLink {
+        link: Rc<Link<'a>>,
+        /// `chunk_N.xhtml#chapter-M`, once resolved
+        internal: Option<Rc<str>>,
+    },
     Text(&'a str),
     TextOwned(Box<str>),
     LineFeed,
+    NoteRef(NoteId),
+}
+
+/// footnote `n` (1-based) of a chapter
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoteId {
+    chapter: u32,
+    n: u32,
+}
+
+impl NoteId {
+    /// id of the footnote
+    fn note_anchor(self) -> String {
+        format!("chapter-{}-note-{}", self.chapter, self.n)
+    }
+
+    /// id of the reference to the footnote
+    fn ref_anchor(self) -> String {
+        format!("chapter-{}-noteref-{}", self.chapter, self.n)
+    }
 }
 
 impl InlineElement<'_> {
@@ -64,11 +264,36 @@ impl InlineElement<'_> {
             InlineElement::Text(t) => t.len(),
             InlineElement::LineFeed => 6,
             InlineElement::TextOwned(t) => t.len(),
-            InlineElement::ExternalLink(l) => l.href.as_str().len() + l.text.len() + 8,
+            InlineElement::NoteRef(_) => 96,
+            InlineElement::ExternalLink(l) | InlineElement::ChapterLink { link: l, .. } => {
+                l.href.as_str().len() + l.elms.iter().map(InlineElement::size).sum::<usize>() + 8
+            }
         }
     }
 }
 
+impl InlineElement<'_> {
+    fn into_owned(self) -> InlineElement<'static> {
+        match self {
+            InlineElement::EnableStyles(s) => InlineElement::EnableStyles(s),
+            InlineElement::DisableStyles(s) => InlineElement::DisableStyles(s),
+            InlineElement::ExternalLink(link) => InlineElement::ExternalLink(link.into_owned()),
+            InlineElement::ChapterLink { link, internal } => InlineElement::ChapterLink {
+                link: link.into_owned(),
+                internal,
+            },
+            InlineElement::Text(t) => InlineElement::TextOwned(t.into()),
+            InlineElement::TextOwned(t) => InlineElement::TextOwned(t),
+            InlineElement::LineFeed => InlineElement::LineFeed,
+            InlineElement::NoteRef(id) => InlineElement::NoteRef(id),
+        }
+    }
+}
+
+fn inline_into_owned(elms: Vec<InlineElement<'_>>) -> Vec<InlineElement<'static>> {
+    elms.into_iter().map(InlineElement::into_owned).collect()
+}
+
 impl<'a> From<Cow<'a, str>> for InlineElement<'a> {
     fn from(value: Cow<'a, str>) -> Self {
         match value {
@@ -82,15 +307,106 @@ impl<'a> From<Cow<'a, str>> for InlineElement<'a> {
 pub struct Chapter<'a> {
     id: u32,
     title: Box<str>,
+    /// url the chapter was parsed from
+    source: Option<Url>,
     pub(crate) rsc: Vec<Rc<ResolvedImage>>,
     p: Vec<MajorElement<'a>>,
+    notes: Vec<Footnote<'a>>,
 }
 
-impl Chapter<'_> {
+/// body of a footnote, referenced by [`InlineElement::NoteRef`]
+#[derive(Debug)]
+struct Footnote<'a>(Vec<InlineElement<'a>>);
+
+/// a footnote that is currently being built by [`ChapterBuilder`]
+#[derive(Debug)]
+struct NoteFrame<'a> {
+    /// the paragraph the footnote is referenced in
+    outer: Vec<InlineElement<'a>>,
+    style: SpanStyle,
+    /// footnotes inside of footnotes are flattened
+    depth: u32,
+}
+
+impl<'a> Chapter<'a> {
     pub fn title(&self) -> &str {
         &self.title
     }
 
+    pub fn source(&self) -> Option<&Url> {
+        self.source.as_ref()
+    }
+
+    /// copy the text borrowed from the document the chapter was parsed from, so that the
+    /// document can be dropped
+    pub fn into_owned(self) -> Chapter<'static> {
+        Chapter {
+            id: self.id,
+            title: self.title,
+            source: self.source,
+            rsc: self.rsc,
+            p: self.p.into_iter().map(MajorElement::into_owned).collect(),
+            notes: self
+                .notes
+                .into_iter()
+                .map(|note| Footnote(inline_into_owned(note.0)))
+                .collect(),
+        }
+    }
+
+    /// id of the footnote at index `i`
+    fn note_id(&self, i: usize) -> NoteId {
+        NoteId {
+            chapter: self.id,
+            n: i as u32 + 1,
+        }
+    }
+
+    /// points chapter links at the internal href of their target. `targets` is keyed by chapter
+    /// source without a fragment. Links to anything else are left as is.
+    pub(crate) fn resolve_links(&mut self, targets: &HashMap<Url, Rc<str>>) {
+        let mut resolve = |el: &mut InlineElement| {
+            let InlineElement::ChapterLink { link, internal } = el else {
+                return;
+            };
+            let mut href = link.href.clone();
+            href.set_fragment(None);
+            *internal = targets.get(&href).cloned();
+        };
+        for el in &mut self.p {
+            el.visit_inline_mut(&mut resolve);
+        }
+        for note in &mut self.notes {
+            note.0.iter_mut().for_each(&mut resolve);
+        }
+    }
+
+    /// whether a chapter link to the site this chapter came from was left pointing outside of the
+    /// book, which a chapter added later could still be the target of
+    pub(crate) fn may_link_ahead(&mut self) -> bool {
+        let Some(source) = &self.source else {
+            return false;
+        };
+        let mut ahead = false;
+        let mut check = |el: &mut InlineElement| {
+            if let InlineElement::ChapterLink {
+                link,
+                internal: None,
+            } = el
+            {
+                ahead |= link.href.scheme() == source.scheme()
+                    && link.href.host_str() == source.host_str();
+            }
+        };
+        for el in &mut self.p {
+            el.visit_inline_mut(&mut check);
+        }
+        for note in &mut self.notes {
+            note.0.iter_mut().for_each(&mut check);
+        }
+        ahead
+    }
+
     pub fn id(&self) -> impl Display {
         struct D(u32);
         impl Display for D {
@@ -113,17 +429,29 @@ impl Chapter<'_> {
     pub fn size(&self) -> usize {
         self.p
             .iter()
-            .filter_map(|e| {
-                if let MajorElement::Paragraph { elms, .. } = e {
-                    Some(elms)
-                } else {
-                    None
-                }
+            .filter(|e| {
+                matches!(
+                    e,
+                    MajorElement::Paragraph { .. }
+                        | MajorElement::List { .. }
+                        | MajorElement::Table(_)
+                )
             })
-            .map(|elms| elms.iter().map(|e| e.size()).sum::<usize>() + 8)
+            .map(|e| e.size() + 8)
             .sum::<usize>()
+            + self
+                .notes
+                .iter()
+                .flat_map(|n| &n.0)
+                .map(|e| e.size() + 8)
+                .sum::<usize>()
             + 64
     }
+
+    /// bytes of the images the chapter uses
+    pub fn resources_size(&self) -> usize {
+        self.rsc.iter().map(|r| r.data.len()).sum()
+    }
 }
 
 /// Builder for [`Chapter`].
@@ -133,6 +461,7 @@ impl Chapter<'_> {
 pub struct ChapterBuilder<'a> {
     id: u32,
     pub title: Option<Box<str>>,
+    source: Option<Url>,
     pub paragraph_style: ParagraphStyle,
     pub span_style: SpanStyle,
 
@@ -143,6 +472,8 @@ pub struct ChapterBuilder<'a> {
     pub(crate) resources_resolved: HashMap<ImageId, Rc<ResolvedImage>>,
 
     current_p: Vec<InlineElement<'a>>,
+    /// where in `current_p` the link started by [`Self::link_start`] begins
+    link: Option<usize>,
 
     /// I might want to reconsider the concept of a major element entirely. It produces a ton of
     /// cache misses. It doesn't end up being a ton of cycles, but it accounts for about half of
@@ -152,6 +483,14 @@ pub struct ChapterBuilder<'a> {
     /// will be more error prone
     complete_p: Vec<MajorElement<'a>>,
 
+    /// lists that have been started but not finished, innermost last
+    lists: Vec<ListFrame<'a>>,
+
+    table: Option<TableFrame<'a>>,
+
+    notes: Vec<Footnote<'a>>,
+    note: Option<NoteFrame<'a>>,
+
     complete_ch: Vec<Chapter<'a>>,
 }
 
@@ -196,6 +535,18 @@ impl<'a> Default for ChapterBuilder<'a> {
     }
 }
 
+/// the url of an image source, relative to `base`, the page it is on
+fn resource_url(base: Option<&Url>, src: &str) -> anyhow::Result<Url> {
+    let url = match base {
+        Some(base) => base.join(src),
+        // yet another thing I didn't know was possible: implicit protocol, seen in the
+        // wild at https://ncode.syosetu.com/n1217et/1/
+        None if src.starts_with("//") => Url::parse(&format!("https:{src}")),
+        None => Url::parse(src),
+    };
+    url.with_context(|| format!("failed to parse url {src:?}"))
+}
+
 impl<'a> ChapterBuilder<'a> {
     pub fn new() -> Self {
         use std::sync::atomic::*;
@@ -203,11 +554,17 @@ impl<'a> ChapterBuilder<'a> {
         Self {
             id: ID_CNT.fetch_add(1, Ordering::Relaxed),
             title: Default::default(),
+            source: None,
             paragraph_style: Default::default(),
             span_style: Default::default(),
             span_style_actual: Default::default(),
             current_p: Default::default(),
+            link: None,
             complete_p: Default::default(),
+            lists: Vec::new(),
+            table: None,
+            notes: Vec::new(),
+            note: None,
             preserve_line_feeds: false,
             resources_unresolved: HashMap::new(),
             resources_resolved: HashMap::new(),
@@ -220,6 +577,12 @@ impl<'a> ChapterBuilder<'a> {
         self
     }
 
+    /// url the chapter is parsed from, used to point links between chapters inside of the book
+    pub fn source_set(&mut self, url: Url) -> &mut Self {
+        self.source = Some(url);
+        self
+    }
+
     pub fn preserve_line_feeds(&mut self, enable: bool) -> &mut Self {
         self.preserve_line_feeds = enable;
         self
@@ -256,12 +619,21 @@ impl<'a> ChapterBuilder<'a> {
     }
 
     /// completes the paragraph, implicitly resets style. no-op if no spans have been added.
+    ///
+    /// Inside of a table or footnote, this only separates paragraphs with a line break, since
+    /// they can only hold inline elements.
     pub fn paragraph_finish(&mut self) -> &mut Self {
         self.span_style_reset();
         self.span_style_actualize();
         if self.current_p.is_empty() {
             return self;
         }
+        if self.inline_only() {
+            if !matches!(self.current_p.last(), Some(InlineElement::LineFeed)) {
+                self.current_p.push(InlineElement::LineFeed);
+            }
+            return self;
+        }
         let spans = std::mem::take(&mut self.current_p);
         let style = std::mem::take(&mut self.paragraph_style);
         self.complete_p
@@ -269,25 +641,340 @@ impl<'a> ChapterBuilder<'a> {
         self
     }
 
-    /// adds a horizontal separator (`<hr>`). Implicitly completes the paragraph
+    /// adds a horizontal separator (`<hr>`). Implicitly completes the paragraph. Inside of a
+    /// table it goes after the table
     pub fn add_separator(&mut self) -> &mut Self {
         self.paragraph_finish();
-        self.complete_p.push(MajorElement::HorizLine);
+        self.push_major(MajorElement::HorizLine);
         self
     }
 
-    /// adds a scene separator with optional heading. Implicitly completes the paragraph
+    /// adds a scene separator with optional heading. Implicitly completes the paragraph. Inside
+    /// of a table it goes after the table
     pub fn add_scene_sep(&mut self, scene: impl Into<Box<str>>) -> &mut Self {
         self.paragraph_finish();
-        self.complete_p.push(MajorElement::SceneSep(scene.into()));
+        self.push_major(MajorElement::SceneSep(scene.into()));
+        self
+    }
+
+    /// adds a complete element, or holds it until the end of the table if there is one
+    fn push_major(&mut self, el: MajorElement<'a>) {
+        match &mut self.table {
+            Some(t) => t.after.push(el),
+            None => self.complete_p.push(el),
+        }
+    }
+
+    /// drops the current paragraph if it's only whitespace. Used between list items, since the
+    /// whitespace there is just HTML formatting
+    fn discard_whitespace(&mut self) {
+        let is_ws = |e: &InlineElement| match e {
+            InlineElement::Text(t) => t.trim().is_empty(),
+            InlineElement::TextOwned(t) => t.trim().is_empty(),
+            InlineElement::EnableStyles(_) | InlineElement::DisableStyles(_) => true,
+            _ => false,
+        };
+        if self.current_p.iter().all(is_ws) {
+            self.current_p.clear();
+        }
+    }
+
+    /// starts a new list, which may be nested in the current list item. Implicitly completes the
+    /// paragraph. Lists inside of tables are flattened into line breaks
+    pub fn list_start(&mut self, kind: ListKind) -> &mut Self {
+        if self.inline_only() {
+            return self.paragraph_finish();
+        }
+        if self.lists.last().is_some_and(|l| !l.in_item) {
+            // `<ul><ul>..</ul></ul>` is technically invalid but it's still out there
+            self.list_item_start();
+        }
+        self.paragraph_finish();
+        let outer = std::mem::take(&mut self.complete_p);
+        self.lists.push(ListFrame {
+            kind,
+            items: Vec::new(),
+            outer,
+            in_item: false,
+        });
+        self
+    }
+
+    /// starts a new item in the current list, completing the previous item if needed. If there
+    /// is no list, an unordered list is started.
+    pub fn list_item_start(&mut self) -> &mut Self {
+        if self.inline_only() {
+            return self.paragraph_finish();
+        }
+        match self.lists.last() {
+            None => {
+                self.list_start(ListKind::Unordered);
+            }
+            Some(l) if l.in_item => {
+                self.list_item_finish();
+            }
+            Some(_) => (),
+        }
+        self.discard_whitespace();
+        self.paragraph_finish();
+        if !self.complete_p.is_empty() {
+            // content between items gets its own item
+            let stray = ListItem(std::mem::take(&mut self.complete_p));
+            self.lists.last_mut().unwrap().items.push(stray);
+        }
+        self.lists.last_mut().unwrap().in_item = true;
+        self
+    }
+
+    /// completes the current list item. No-op if there is no list.
+    pub fn list_item_finish(&mut self) -> &mut Self {
+        if self.inline_only() {
+            return self.paragraph_finish();
+        }
+        if self.lists.is_empty() {
+            return self;
+        }
+        self.paragraph_finish();
+        let item = ListItem(std::mem::take(&mut self.complete_p));
+        let list = self.lists.last_mut().unwrap();
+        list.in_item = false;
+        if !item.0.is_empty() {
+            list.items.push(item);
+        }
+        self
+    }
+
+    /// completes the current list (and item, if any). Empty lists are dropped. No-op if there is
+    /// no list.
+    pub fn list_finish(&mut self) -> &mut Self {
+        if self.inline_only() {
+            return self.paragraph_finish();
+        }
+        if self.lists.is_empty() {
+            return self;
+        }
+        self.discard_whitespace();
+        self.list_item_finish();
+        let ListFrame {
+            kind, items, outer, ..
+        } = self.lists.pop().unwrap();
+        self.complete_p = outer;
+        if !items.is_empty() {
+            self.complete_p.push(MajorElement::List { kind, items });
+        }
+        self
+    }
+
+    /// whether only inline elements can be added right now
+    fn inline_only(&self) -> bool {
+        self.table.is_some() || self.note.is_some()
+    }
+
+    /// moves text outside of table cells to the caption
+    fn table_stray(&mut self) {
+        self.span_style_reset();
+        self.span_style_actualize();
+        self.discard_whitespace();
+        let Some(t) = &mut self.table else {
+            return;
+        };
+        if self.current_p.is_empty() {
+            return;
+        }
+        if !t.table.caption.is_empty() {
+            t.table.caption.push(InlineElement::LineFeed);
+        }
+        t.table.caption.append(&mut self.current_p);
+        trim_blank(&mut t.table.caption);
+    }
+
+    /// starts a table. Tables can only hold inline elements, so paragraphs inside of cells are
+    /// separated by line breaks. Tables inside of tables are flattened. Implicitly completes the
+    /// paragraph
+    pub fn table_start(&mut self) -> &mut Self {
+        if let Some(t) = &mut self.table {
+            t.depth += 1;
+            return self;
+        }
+        if self.note.is_some() {
+            // cells will just be text
+            return self.paragraph_finish();
+        }
+        self.paragraph_finish();
+        self.table = Some(TableFrame::default());
+        self
+    }
+
+    /// starts a row in the table head (`<thead>`) or body, completing the previous row if
+    /// needed. No-op if there is no table.
+    pub fn table_row_start(&mut self, head: bool) -> &mut Self {
+        match &self.table {
+            Some(t) if t.depth == 0 => (),
+            _ => return self,
+        }
+        self.table_row_finish();
+        self.table_stray();
+        self.table.as_mut().unwrap().row = Some((head, Vec::new()));
         self
     }
 
-    /// adds an image, inline with page flow. Implicitly completes the paragraph
+    /// starts a cell in the current row, starting a row if needed. `colspan` and `rowspan` are
+    /// clamped to at least 1 and at most what HTML allows. No-op if there is no table.
+    pub fn table_cell_start(&mut self, header: bool, colspan: u32, rowspan: u32) -> &mut Self {
+        let Some(t) = &self.table else {
+            return self;
+        };
+        if t.depth > 0 {
+            // flatten nested tables by separating cells with spaces
+            if t.cell.is_some() && !self.current_p.is_empty() {
+                self.add_text(" ");
+            }
+            return self;
+        }
+        if t.cell.is_some() {
+            self.table_cell_finish();
+        }
+        if self.table.as_ref().unwrap().row.is_none() {
+            self.table_row_start(false);
+        }
+        self.table_stray();
+        self.table.as_mut().unwrap().cell = Some(TableCell {
+            header,
+            colspan: colspan.clamp(1, MAX_COLSPAN),
+            rowspan: rowspan.clamp(1, MAX_ROWSPAN),
+            elms: Vec::new(),
+        });
+        self
+    }
+
+    /// completes the current cell. No-op if there is no cell.
+    pub fn table_cell_finish(&mut self) -> &mut Self {
+        match &self.table {
+            Some(t) if t.depth == 0 && t.cell.is_some() => (),
+            _ => return self,
+        }
+        self.span_style_reset();
+        self.span_style_actualize();
+        let t = self.table.as_mut().unwrap();
+        let mut cell = t.cell.take().unwrap();
+        cell.elms = std::mem::take(&mut self.current_p);
+        trim_blank(&mut cell.elms);
+        t.row.get_or_insert((false, Vec::new())).1.push(cell);
+        self
+    }
+
+    /// completes the current row (and cell, if any). No-op if there is no row.
+    pub fn table_row_finish(&mut self) -> &mut Self {
+        self.table_cell_finish();
+        let Some(t) = &mut self.table else {
+            return self;
+        };
+        if t.depth > 0 {
+            return self;
+        }
+        let Some((head, cells)) = t.row.take() else {
+            return self;
+        };
+        if cells.is_empty() {
+            return self;
+        }
+        if head {
+            t.table.head.push(TableRow(cells));
+        } else {
+            t.table.body.push(TableRow(cells));
+        }
+        self
+    }
+
+    /// completes the current table. Tables without any cells are dropped. No-op if there is no
+    /// table.
+    pub fn table_finish(&mut self) -> &mut Self {
+        match &mut self.table {
+            None => return self,
+            Some(t) if t.depth > 0 => {
+                t.depth -= 1;
+                return self;
+            }
+            Some(_) => (),
+        }
+        self.table_row_finish();
+        self.table_stray();
+        let TableFrame { table, after, .. } = self.table.take().unwrap();
+        if table.rows().next().is_some() {
+            self.complete_p.push(MajorElement::Table(Box::new(table)));
+        } else if !table.caption.is_empty() {
+            // not really a table, so keep the text
+            self.complete_p.push(MajorElement::Paragraph {
+                style: ParagraphStyle::default(),
+                elms: table.caption,
+            });
+        }
+        self.complete_p.extend(after);
+        self
+    }
+
+    /// starts a footnote referenced at the current position. Everything added until
+    /// [`Self::footnote_finish`] is the body of the footnote, which can only hold inline elements.
+    /// Footnotes inside of footnotes are flattened.
+    pub fn footnote_start(&mut self) -> &mut Self {
+        if let Some(n) = &mut self.note {
+            n.depth += 1;
+            return self;
+        }
+        let style = self.span_style;
+        self.span_style_reset();
+        self.span_style_actualize();
+        let id = NoteId {
+            chapter: self.id,
+            n: self.notes.len() as u32 + 1,
+        };
+        self.current_p.push(InlineElement::NoteRef(id));
+        let outer = std::mem::take(&mut self.current_p);
+        self.note = Some(NoteFrame {
+            outer,
+            style,
+            depth: 0,
+        });
+        self
+    }
+
+    /// completes the current footnote. Empty footnotes are dropped along with their reference.
+    /// No-op if there is no footnote.
+    pub fn footnote_finish(&mut self) -> &mut Self {
+        match &mut self.note {
+            None => return self,
+            Some(n) if n.depth > 0 => {
+                n.depth -= 1;
+                return self;
+            }
+            Some(_) => (),
+        }
+        self.span_style_reset();
+        self.span_style_actualize();
+        let NoteFrame { outer, style, .. } = self.note.take().unwrap();
+        let mut body = std::mem::replace(&mut self.current_p, outer);
+        trim_blank(&mut body);
+        if body.is_empty() {
+            let r = self.current_p.pop();
+            debug_assert!(matches!(r, Some(InlineElement::NoteRef(_))));
+        } else {
+            self.notes.push(Footnote(body));
+        }
+        self.span_style = style;
+        self
+    }
+
+    /// adds a footnote that is only text, referenced at the current position
+    pub fn add_footnote(&mut self, body: impl Into<Cow<'a, str>>) -> &mut Self {
+        self.footnote_start().add_text(body).footnote_finish()
+    }
+
+    /// adds an image, inline with page flow. Implicitly completes the paragraph. Inside of a
+    /// table it goes after the table
     pub fn add_image(&mut self, img: impl Into<Image>) -> &mut Self {
         self.paragraph_finish();
         let img: Image = img.into();
-        self.complete_p.push(MajorElement::Image(img.id()));
+        self.push_major(MajorElement::Image(img.id()));
         self.resources_unresolved.insert(Arc::clone(img.url()), img);
         self
     }
@@ -303,45 +990,42 @@ impl<'a> ChapterBuilder<'a> {
         // PERF: we don't deduplicate here, but probably should?
         self.resources_resolved
             .reserve(self.resources_unresolved.len());
+        // images on different hosts can be fetched at the same time
+        store.prefetch(
+            self.resources_unresolved
+                .keys()
+                .filter_map(|src| resource_url(self.source.as_ref(), src).ok()),
+        );
         for (url, img) in std::mem::take(&mut self.resources_unresolved) {
             if self.resources_resolved.contains_key(&img.id()) {
                 continue;
             }
-            let url: Url = {
-                // yet another thing I didn't know was possible: implicit protocol, seen in the
-                // wild at https://ncode.syosetu.com/n1217et/1/
-                let mut buf: String;
-                let url: &str = if url.starts_with("//") {
-                    buf = String::with_capacity("https:".len() + url.len());
-                    buf.push_str("https:");
-                    buf.push_str(&url);
-                    &buf
-                } else if url.starts_with("/") {
-                    todo!("absolute path urls in resource resolution")
-                } else {
-                    &url
-                };
-                url.try_into()
-                    .with_context(|| format!("failed to parse url {url:?}"))?
-            };
+            let url = resource_url(self.source.as_ref(), &url)?;
             let (ty, bytes) = store.fetch(&url).context("failed fetching resource")?;
             ensure!(ty.is_image(), "resolved type {ty:?} is not an image");
             let img = img.resolve_with(ty, bytes);
             self.resources_resolved.insert(img.id(), Rc::new(img));
         }
         // TODO: don't merge same url -> multiple alts
-        for el in &mut self.complete_p {
-            let MajorElement::Image(id) = el else {
-                continue;
-            };
-            *el = MajorElement::ImageResolved(
-                self.resources_resolved
-                    .get(id)
-                    .context("image was added without registration")?
-                    .clone(),
-            );
+        let mut res = Ok(());
+        let outer = self.lists.iter_mut().flat_map(|l| {
+            l.outer
+                .iter_mut()
+                .chain(l.items.iter_mut().flat_map(|i| &mut i.0))
+        });
+        for el in outer.chain(&mut self.complete_p) {
+            el.visit_mut(&mut |el| {
+                let MajorElement::Image(id) = el else {
+                    return;
+                };
+                let Some(img) = self.resources_resolved.get(id) else {
+                    res = Err(anyhow!("image was added without registration"));
+                    return;
+                };
+                *el = MajorElement::ImageResolved(img.clone());
+            });
         }
-        Ok(())
+        res
     }
 
     /// note: content will not be trimmed
@@ -351,6 +1035,54 @@ impl<'a> ChapterBuilder<'a> {
         self
     }
 
+    /// adds a link to somewhere outside of the book
+    pub fn add_link(&mut self, href: Url, text: impl Into<Cow<'a, str>>) -> &mut Self {
+        self.link_start().add_text(text).link_finish(href, false)
+    }
+
+    /// adds a link that points inside of the book if `href` turns out to be the source of one of
+    /// its chapters, and outside otherwise
+    pub fn add_chapter_link(&mut self, href: Url, text: impl Into<Cow<'a, str>>) -> &mut Self {
+        self.link_start().add_text(text).link_finish(href, true)
+    }
+
+    /// starts a link around the text added until [`Self::link_finish`]
+    pub fn link_start(&mut self) -> &mut Self {
+        self.span_style_actualize();
+        self.link = Some(self.current_p.len());
+        self
+    }
+
+    /// puts the text added since [`Self::link_start`] in a link to `href`, which is a chapter
+    /// link like [`Self::add_chapter_link`] if `chapter` is set. Text that did not stay in one
+    /// paragraph, or that has footnotes, is left as it is
+    pub fn link_finish(&mut self, href: Url, chapter: bool) -> &mut Self {
+        let Some(start) = self.link.take() else {
+            return self;
+        };
+        self.span_style_actualize();
+        if start >= self.current_p.len() {
+            return self;
+        }
+        let is_inline = |e: &InlineElement| !matches!(e, InlineElement::NoteRef(_));
+        if !self.current_p[start..].iter().all(is_inline) {
+            return self;
+        }
+        let link = Rc::new(Link {
+            href,
+            elms: self.current_p.split_off(start),
+        });
+        self.current_p.push(if chapter {
+            InlineElement::ChapterLink {
+                link,
+                internal: None,
+            }
+        } else {
+            InlineElement::ExternalLink(link)
+        });
+        self
+    }
+
     pub fn add_line_break(&mut self) -> &mut Self {
         self.span_style_actualize();
         self.current_p.push(InlineElement::LineFeed);
@@ -374,6 +1106,15 @@ impl<'a> ChapterBuilder<'a> {
     ///
     /// Note that calling `finish` immediately after is an error
     pub fn finish_reuse(&mut self) -> Result<(), ChapterBuilderError> {
+        while self.note.is_some() {
+            self.footnote_finish();
+        }
+        while self.table.is_some() {
+            self.table_finish();
+        }
+        while !self.lists.is_empty() {
+            self.list_finish();
+        }
         self.paragraph_finish();
         let mut ch = std::mem::take(self);
         let error = ChapterBuilderError {
@@ -389,6 +1130,8 @@ impl<'a> ChapterBuilder<'a> {
             id: ch.id,
             p: ch.complete_p,
             title,
+            source: ch.source,
+            notes: ch.notes,
             rsc: ch.resources_resolved.into_values().collect(),
         };
         if log_enabled!(log::Level::Warn) {
@@ -400,12 +1143,30 @@ impl<'a> ChapterBuilder<'a> {
                 );
             }
         }
+        // the next chapter is most likely from the same page
+        self.source = ret.source.clone();
         ch.complete_ch.push(ret);
         self.complete_ch = ch.complete_ch;
         Ok(())
     }
 }
 
+/// removes line feeds and whitespace from both ends, which is just HTML formatting in tables and
+/// footnotes
+fn trim_blank(elms: &mut Vec<InlineElement>) {
+    let is_blank = |e: &InlineElement| match e {
+        InlineElement::Text(t) => t.trim().is_empty(),
+        InlineElement::TextOwned(t) => t.trim().is_empty(),
+        InlineElement::LineFeed => true,
+        _ => false,
+    };
+    while elms.last().is_some_and(is_blank) {
+        elms.pop();
+    }
+    let start = elms.iter().take_while(|e| is_blank(e)).count();
+    elms.drain(..start);
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -430,6 +1191,26 @@ mod test {
         assert_eq!(chapter[0].xml().to_string(), expected);
     }
 
+    #[test]
+    fn into_owned() {
+        let doc = String::from("borrowed text");
+        let link: Url = "https://example.com/".parse().unwrap();
+        let mut builder = ChapterBuilder::new();
+        builder
+            .title_set("owned")
+            .add_text(&doc[..8])
+            .add_link(link.clone(), &doc[9..])
+            .add_footnote(&doc[..])
+            .list_start(ListKind::Unordered)
+            .list_item_start()
+            .add_text(&doc[9..]);
+        let ch = builder.finish().unwrap().swap_remove(0);
+        let expected = ch.xml().to_string();
+        let ch = ch.into_owned();
+        drop(doc);
+        assert_eq!(ch.xml().to_string(), expected);
+    }
+
     #[test]
     fn multiple_paragraphs() {
         let mut builder = ChapterBuilder::new();
@@ -504,4 +1285,299 @@ mod test {
             paragraph 3";
         assert_eq!(chapter[0].md().to_string(), expected);
     }
+
+    fn nested_list() -> Vec<Chapter<'static>> {
+        let mut builder = ChapterBuilder::new();
+        builder
+            .title_set("lists")
+            .add_text("before")
+            .list_start(ListKind::Unordered)
+            .list_item_start()
+            .add_text("one")
+            .list_item_start()
+            .add_text("two")
+            .list_start(ListKind::Ordered)
+            .list_item_start()
+            .add_text("two.one")
+            .list_item_start()
+            .add_text_styled("two.two", SpanStyle::bold())
+            .list_finish()
+            .list_finish()
+            .add_text("after");
+        builder.finish().unwrap()
+    }
+
+    #[test]
+    fn lists_xml() {
+        let chapter = nested_list();
+        let expected = format!(
+            "\
+            <section epub:type=\"chapter\" id=\"{}\">\n\
+            <h2>lists</h2>\n\
+            <p>before</p>\n\
+            <ul>\n\
+            <li>one</li>\n\
+            <li>\n\
+            <p>two</p>\n\
+            <ol>\n\
+            <li>two.one</li>\n\
+            <li><b>two.two</b></li>\n\
+            </ol>\n\
+            </li>\n\
+            </ul>\n\
+            <p>after</p>\n\
+            </section>",
+            chapter[0].id()
+        );
+        assert_eq!(chapter[0].xml().to_string(), expected);
+    }
+
+    #[test]
+    fn lists_md() {
+        let chapter = nested_list();
+        let expected = "\
+            # lists\n\n\
+            before\n\n\
+            - one\n\
+            - two\n\n\
+            \x20 1. two.one\n\
+            \x20 2. **two.two**\n\n\
+            after";
+        assert_eq!(chapter[0].md().to_string(), expected);
+    }
+
+    #[test]
+    fn list_whitespace_and_empty() {
+        let mut builder = ChapterBuilder::new();
+        builder
+            .title_set("ws")
+            .list_start(ListKind::Unordered)
+            .add_text("\n  ")
+            .list_item_start()
+            .add_text("item")
+            .list_item_finish()
+            .add_text("\n")
+            .list_finish()
+            .list_start(ListKind::Ordered)
+            .list_finish();
+        let chapter = builder.finish().unwrap();
+        assert_eq!(chapter[0].md().to_string(), "# ws\n\n- item");
+    }
+
+    fn spanned_table() -> Vec<Chapter<'static>> {
+        let mut builder = ChapterBuilder::new();
+        builder
+            .title_set("tables")
+            .table_start()
+            .add_text("Party")
+            .table_row_start(true)
+            .table_cell_start(true, 1, 1)
+            .add_text("Name")
+            .table_cell_start(true, 2, 1)
+            .add_text("Stats")
+            .table_row_start(false)
+            .table_cell_start(false, 1, 2)
+            .add_text("Aria")
+            .table_cell_start(false, 1, 1)
+            .add_text("HP")
+            .table_cell_start(false, 1, 1)
+            .add_text_styled("10", SpanStyle::bold())
+            .table_row_start(false)
+            .table_cell_start(false, 1, 1)
+            .add_text("MP")
+            .paragraph_finish()
+            .add_text("a|b")
+            .table_cell_start(false, 1, 1)
+            .add_text("4")
+            .table_finish();
+        builder.finish().unwrap()
+    }
+
+    #[test]
+    fn tables_xml() {
+        let chapter = spanned_table();
+        let expected = format!(
+            "\
+            <section epub:type=\"chapter\" id=\"{}\">\n\
+            <h2>tables</h2>\n\
+            <table>\n\
+            <caption>Party</caption>\n\
+            <thead>\n\
+            <tr><th>Name</th><th colspan=\"2\">Stats</th></tr>\n\
+            </thead>\n\
+            <tbody>\n\
+            <tr><td rowspan=\"2\">Aria</td><td>HP</td><td><b>10</b></td></tr>\n\
+            <tr><td>MP<br />\na|b</td><td>4</td></tr>\n\
+            </tbody>\n\
+            </table>\n\
+            </section>",
+            chapter[0].id()
+        );
+        assert_eq!(chapter[0].xml().to_string(), expected);
+    }
+
+    #[test]
+    fn tables_md() {
+        let chapter = spanned_table();
+        let expected = "\
+            # tables\n\n\
+            Party\n\n\
+            | Name | Stats |  |\n\
+            | --- | --- | --- |\n\
+            | Aria | HP | **10** |\n\
+            |  | MP a\\|b | 4 |";
+        assert_eq!(chapter[0].md().to_string(), expected);
+    }
+
+    #[test]
+    fn resource_urls() {
+        let page = Url::parse("https://example.com/novel/1/").unwrap();
+        let url = |src| resource_url(Some(&page), src).unwrap().to_string();
+        assert_eq!(
+            url("/wp-content/a.png"),
+            "https://example.com/wp-content/a.png"
+        );
+        assert_eq!(url("img/b.png"), "https://example.com/novel/1/img/b.png");
+        assert_eq!(
+            url("//cdn.example.com/c.png"),
+            "https://cdn.example.com/c.png"
+        );
+        assert_eq!(url("https://other.com/d.png"), "https://other.com/d.png");
+        let url = |src| resource_url(None, src).map(|u| u.to_string());
+        assert_eq!(
+            url("//cdn.example.com/c.png").unwrap(),
+            "https://cdn.example.com/c.png"
+        );
+        assert!(url("/a.png").is_err());
+    }
+
+    #[test]
+    fn table_huge_spans() {
+        let mut builder = ChapterBuilder::new();
+        builder
+            .title_set("spans")
+            .table_start()
+            .table_cell_start(false, u32::MAX, u32::MAX)
+            .add_text("a")
+            .table_row_start(false)
+            .table_cell_start(false, 1, 1)
+            .add_text("b")
+            .table_finish();
+        let chapter = builder.finish().unwrap();
+        let xml = chapter[0].xml().to_string();
+        assert!(xml.contains(r#"<td colspan="1000" rowspan="65534">a</td>"#));
+        // the rowspan does not make rows of its own
+        let md = chapter[0].md().to_string();
+        assert_eq!(md.lines().filter(|l| l.starts_with('|')).count(), 3);
+    }
+
+    #[test]
+    fn table_separator_after() {
+        let mut builder = ChapterBuilder::new();
+        builder
+            .title_set("separated")
+            .table_start()
+            .table_cell_start(false, 1, 1)
+            .add_text("a")
+            .add_separator()
+            .table_cell_start(false, 1, 1)
+            .add_text("b")
+            .table_finish();
+        let chapter = builder.finish().unwrap();
+        let xml = chapter[0].xml().to_string();
+        let hr = xml.find("<hr />").unwrap();
+        assert!(xml.find("</table>").unwrap() < hr);
+        assert_eq!(xml.matches("<td>").count(), 2);
+    }
+
+    #[test]
+    fn table_empty() {
+        let mut builder = ChapterBuilder::new();
+        builder
+            .title_set("empty")
+            .table_start()
+            .add_text("\n")
+            .table_row_start(false)
+            .table_row_finish()
+            .table_finish()
+            .table_start()
+            .add_text("layout only")
+            .table_finish();
+        let chapter = builder.finish().unwrap();
+        assert_eq!(chapter[0].md().to_string(), "# empty\n\nlayout only");
+    }
+
+    #[test]
+    fn links() {
+        let mut builder = ChapterBuilder::new();
+        builder
+            .title_set("links")
+            .add_link(
+                Url::parse("https://example.com/?a=1&b=\"2\"").unwrap(),
+                "<wiki>",
+            )
+            .add_text(" ")
+            .add_chapter_link(Url::parse("https://example.com/2#top").unwrap(), "next")
+            .add_text(" ")
+            .add_chapter_link(Url::parse("https://example.com/3").unwrap(), "later");
+        let mut chapter = builder.finish().unwrap();
+        let mut targets = HashMap::new();
+        targets.insert(
+            Url::parse("https://example.com/2").unwrap(),
+            "chunk_1.xhtml#chapter-2".into(),
+        );
+        chapter[0].resolve_links(&targets);
+        let expected = format!(
+            "\
+            <section epub:type=\"chapter\" id=\"{}\">\n\
+            <h2>links</h2>\n\
+            <p><a href=\"https://example.com/?a=1&amp;b=%222%22\">&lt;wiki&gt;</a> \
+            <a href=\"chunk_1.xhtml#chapter-2\">next</a> \
+            <a href=\"https://example.com/3\">later</a></p>\n\
+            </section>",
+            chapter[0].id()
+        );
+        assert_eq!(chapter[0].xml().to_string(), expected);
+    }
+
+    #[test]
+    fn footnotes() {
+        let mut builder = ChapterBuilder::new();
+        builder
+            .title_set("notes")
+            .span_style_set(SpanStyle::italic())
+            .add_text("a pun")
+            .footnote_start()
+            .add_text("first")
+            .paragraph_finish()
+            .add_text("second")
+            .footnote_finish()
+            .add_text(" here")
+            .footnote_start()
+            .add_text(" ")
+            .footnote_finish()
+            .span_style_reset()
+            .add_footnote("<b>");
+        let chapter = builder.finish().unwrap();
+        let id = chapter[0].id().to_string();
+        let expected = format!(
+            "\
+            <section epub:type=\"chapter\" id=\"{id}\">\n\
+            <h2>notes</h2>\n\
+            <p><i>a pun</i><sup><a epub:type=\"noteref\" role=\"doc-noteref\" \
+            id=\"{id}-noteref-1\" href=\"#{id}-note-1\">1</a></sup><i> here</i>\
+            <sup><a epub:type=\"noteref\" role=\"doc-noteref\" \
+            id=\"{id}-noteref-2\" href=\"#{id}-note-2\">2</a></sup></p>\n\
+            <aside epub:type=\"footnote\" role=\"doc-footnote\" id=\"{id}-note-1\">\
+            <p><a href=\"#{id}-noteref-1\">1.</a> first<br />\nsecond</p></aside>\n\
+            <aside epub:type=\"footnote\" role=\"doc-footnote\" id=\"{id}-note-2\">\
+            <p><a href=\"#{id}-noteref-2\">2.</a> &lt;b&gt;</p></aside>\n\
+            </section>"
+        );
+        assert_eq!(chapter[0].xml().to_string(), expected);
+        assert_eq!(
+            chapter[0].md().to_string(),
+            "# notes\n\n*a pun*[^1]* here*[^2]\n\n[^1]: first second\n\n[^2]: <b>"
+        );
+    }
 }
@@ -2,7 +2,10 @@ use std::fmt::Display;
 
 use crate::{
     Chapter,
-    chapter::{EscapeMd, InlineElement, MajorElement, MapDispJoin, NopDisplay, ParagraphMode},
+    chapter::{
+        EscapeMd, InlineElement, ListKind, MajorElement, MapDispJoin, NopDisplay, ParagraphMode,
+        Table,
+    },
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -16,10 +19,17 @@ impl super::SerChapter for Md {
 struct MdChapter<'a>(&'a Chapter<'a>);
 impl Display for MdChapter<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let Chapter { title, p, .. } = self.0;
+        let Chapter {
+            title, p, notes, ..
+        } = self.0;
         let title = EscapeMd(title);
         writeln!(f, "# {title}\n")?;
-        p.map_disp_join("\n\n", |p| MdMajor(p)).fmt(f)
+        p.map_disp_join("\n\n", |p| MdMajor(p)).fmt(f)?;
+        for (i, note) in notes.iter().enumerate() {
+            let body = note.0.map_disp_join(NopDisplay, |el| MdInline(el));
+            write!(f, "\n\n[^{}]: {body}", i + 1)?;
+        }
+        Ok(())
     }
 }
 
@@ -46,7 +56,11 @@ impl Display for MdInline<'_> {
                 write!(f, "{disp}")
             }
             InlineElement::LineFeed => write!(f, " "),
-            InlineElement::ExternalLink(l) => write!(f, "[{}]({})", EscapeMd(&l.text), l.href),
+            InlineElement::NoteRef(id) => write!(f, "[^{}]", id.n),
+            InlineElement::ExternalLink(l) | InlineElement::ChapterLink { link: l, .. } => {
+                let text = l.elms.map_disp_join(NopDisplay, |e| MdInline(e));
+                write!(f, "[{text}]({})", l.href)
+            }
         }
     }
 }
@@ -73,7 +87,101 @@ impl Display for MdMajor<'_> {
                     writeln!(f, "### ◇ {s} ◇", s = EscapeMd(s))
                 }
             }
+            MajorElement::List { kind, items } => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str("\n")?;
+                    }
+                    let marker = match kind {
+                        ListKind::Unordered => "- ".to_owned(),
+                        ListKind::Ordered => format!("{}. ", i + 1),
+                    };
+                    // nested content is indented to line up with the marker
+                    let content = item.0.map_disp_join("\n\n", |p| MdMajor(p)).to_string();
+                    let indent = " ".repeat(marker.len());
+                    for (j, line) in content.trim_end().lines().enumerate() {
+                        if j == 0 {
+                            write!(f, "{marker}{line}")?;
+                        } else if line.is_empty() {
+                            f.write_str("\n")?;
+                        } else {
+                            write!(f, "\n{indent}{line}")?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            MajorElement::Table(t) => MdTable(t).fmt(f),
             MajorElement::Image(_) => todo!(),
         }
     }
 }
+
+/// pipe table. Markdown has no spans, so spanned cells are padded with empty cells, and the first
+/// row always becomes the header
+struct MdTable<'a>(&'a Table<'a>);
+impl Display for MdTable<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let table = self.0;
+        // lay the cells out on a grid, `None` is a slot taken by a rowspan from above
+        let mut grid: Vec<Vec<Option<String>>> = Vec::new();
+        let rows = table.rows().count();
+        for (r, row) in table.rows().enumerate() {
+            if grid.len() <= r {
+                grid.push(Vec::new());
+            }
+            let mut c = 0;
+            for cell in &row.0 {
+                while grid[r].get(c).is_some_and(Option::is_none) {
+                    c += 1;
+                }
+                let text = cell
+                    .elms
+                    .map_disp_join(NopDisplay, |el| MdInline(el))
+                    .to_string()
+                    .replace('|', "\\|");
+                // like in HTML, rowspans stop at the last row
+                for dr in 0..(cell.rowspan as usize).min(rows - r) {
+                    if grid.len() <= r + dr {
+                        grid.push(Vec::new());
+                    }
+                    let line = &mut grid[r + dr];
+                    for dc in 0..cell.colspan as usize {
+                        let slot = match (dr, dc) {
+                            (0, 0) => Some(text.clone()),
+                            (0, _) => Some(String::new()),
+                            _ => None,
+                        };
+                        if line.len() <= c + dc {
+                            line.resize(c + dc + 1, Some(String::new()));
+                        }
+                        line[c + dc] = slot;
+                    }
+                }
+                c += cell.colspan as usize;
+            }
+        }
+        let width = grid.iter().map(Vec::len).max().unwrap_or(0);
+        let caption = table.caption.map_disp_join(NopDisplay, |el| MdInline(el));
+        if !table.caption.is_empty() {
+            writeln!(f, "{caption}\n")?;
+        }
+        for (r, line) in grid.iter().enumerate() {
+            if r > 0 {
+                f.write_str("\n")?;
+            }
+            f.write_str("|")?;
+            for c in 0..width {
+                let text = line.get(c).and_then(Option::as_deref).unwrap_or("");
+                write!(f, " {text} |")?;
+            }
+            if r == 0 {
+                f.write_str("\n|")?;
+                for _ in 0..width {
+                    f.write_str(" --- |")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
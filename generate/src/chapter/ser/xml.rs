@@ -3,7 +3,10 @@ use std::fmt::Display;
 
 use crate::{
     Chapter,
-    chapter::{EscapeBody, InlineElement, MajorElement, ParagraphMode},
+    chapter::{
+        EscapeAttr, EscapeBody, InlineElement, ListItem, ListKind, MajorElement, ParagraphMode,
+        Table, TableRow,
+    },
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -17,7 +20,9 @@ impl super::SerChapter for Xml {
 struct XmlChapter<'a>(&'a Chapter<'a>);
 impl Display for XmlChapter<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let Chapter { title, p, .. } = self.0;
+        let Chapter {
+            title, p, notes, ..
+        } = self.0;
         let title = EscapeBody(title).surround_tag("h2");
         writeln!(
             f,
@@ -26,6 +31,17 @@ impl Display for XmlChapter<'_> {
         )?;
         writeln!(f, "{title}")?;
         writeln!(f, "{}", p.map_disp_join('\n', |p| XmlMajor(p)))?;
+        for (i, note) in notes.iter().enumerate() {
+            let id = self.0.note_id(i);
+            let body = note.0.map_disp_join(NopDisplay, |e| XmlInline(e));
+            writeln!(
+                f,
+                r##"<aside epub:type="footnote" role="doc-footnote" id="{}"><p><a href="#{}">{}.</a> {body}</p></aside>"##,
+                id.note_anchor(),
+                id.ref_anchor(),
+                i + 1,
+            )?;
+        }
         write!(f, "</section>")
     }
 }
@@ -61,8 +77,24 @@ impl Display for XmlInline<'_> {
             InlineElement::LineFeed => {
                 writeln!(f, "<br />")?;
             }
+            InlineElement::NoteRef(id) => {
+                write!(
+                    f,
+                    r##"<sup><a epub:type="noteref" role="doc-noteref" id="{}" href="#{}">{}</a></sup>"##,
+                    id.ref_anchor(),
+                    id.note_anchor(),
+                    id.n,
+                )?;
+            }
             InlineElement::ExternalLink(l) => {
-                write!(f, r#"<a href="{}">{}</a>"#, l.href, l.text)?;
+                let href = EscapeAttr(l.href.as_str());
+                let text = l.elms.map_disp_join(NopDisplay, |e| XmlInline(e));
+                write!(f, r#"<a href="{href}">{text}</a>"#)?;
+            }
+            InlineElement::ChapterLink { link, internal } => {
+                let href = EscapeAttr(internal.as_deref().unwrap_or(link.href.as_str()));
+                let text = link.elms.map_disp_join(NopDisplay, |e| XmlInline(e));
+                write!(f, r#"<a href="{href}">{text}</a>"#)?;
             }
         };
         Ok(())
@@ -91,7 +123,88 @@ impl Display for XmlMajor<'_> {
                     .surround(r#"<h3 class="scene-sep">"#, "</h3>")
                     .fmt(f)
             }
+            MajorElement::List { kind, items } => {
+                let tag = match kind {
+                    ListKind::Unordered => "ul",
+                    ListKind::Ordered => "ol",
+                };
+                writeln!(f, "<{tag}>")?;
+                for item in items {
+                    writeln!(f, "{}", XmlListItem(item))?;
+                }
+                write!(f, "</{tag}>")
+            }
+            MajorElement::Table(t) => XmlTable(t).fmt(f),
             MajorElement::Image(_) => todo!(),
         }
     }
 }
+
+struct XmlTable<'a>(&'a Table<'a>);
+impl Display for XmlTable<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Table {
+            caption,
+            head,
+            body,
+        } = self.0;
+        writeln!(f, "<table>")?;
+        if !caption.is_empty() {
+            let caption = caption.map_disp_join(NopDisplay, |e| XmlInline(e));
+            writeln!(f, "{}", caption.surround_tag("caption"))?;
+        }
+        if !head.is_empty() {
+            writeln!(f, "<thead>")?;
+            for row in head {
+                writeln!(f, "{}", XmlTableRow(row))?;
+            }
+            writeln!(f, "</thead>")?;
+        }
+        if !body.is_empty() {
+            writeln!(f, "<tbody>")?;
+            for row in body {
+                writeln!(f, "{}", XmlTableRow(row))?;
+            }
+            writeln!(f, "</tbody>")?;
+        }
+        write!(f, "</table>")
+    }
+}
+
+struct XmlTableRow<'a>(&'a TableRow<'a>);
+impl Display for XmlTableRow<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<tr>")?;
+        for cell in &self.0.0 {
+            let tag = if cell.header { "th" } else { "td" };
+            write!(f, "<{tag}")?;
+            if cell.colspan > 1 {
+                write!(f, r#" colspan="{}""#, cell.colspan)?;
+            }
+            if cell.rowspan > 1 {
+                write!(f, r#" rowspan="{}""#, cell.rowspan)?;
+            }
+            let elms = cell.elms.map_disp_join(NopDisplay, |e| XmlInline(e));
+            write!(f, ">{elms}</{tag}>")?;
+        }
+        f.write_str("</tr>")
+    }
+}
+
+struct XmlListItem<'a>(&'a ListItem<'a>);
+impl Display for XmlListItem<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &*self.0.0 {
+            // don't wrap simple items in `<p>`
+            [MajorElement::Paragraph { style, elms }] if style.mode == ParagraphMode::Normal => {
+                elms.map_disp_join(NopDisplay, |e| XmlInline(e))
+                    .surround_tag("li")
+                    .fmt(f)
+            }
+            _ => (self.0.0)
+                .map_disp_join('\n', |p| XmlMajor(p))
+                .surround("<li>\n", "\n</li>")
+                .fmt(f),
+        }
+    }
+}
@@ -3,5 +3,5 @@ pub(crate) mod package;
 mod xml;
 
 pub use book::Compression;
-pub use book::EpubBuilder;
+pub use book::{EpubBuilder, EpubStream};
 pub use package::{IdentifierType, ManifestItem, ManifestProperties};
@@ -1,7 +1,7 @@
-use ahash::{HashMap, HashMapExt};
+use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
 use anyhow::{Context, Result};
 use fetch::FetchContext;
-use log::error;
+use log::{debug, error};
 use std::{
     collections::hash_map::Entry,
     io::{self, BufWriter, prelude::*},
@@ -166,6 +166,12 @@ impl<'a> EpubBuilder<'a> {
         self
     }
 
+    /// mark the book as volume `position` (starting at 1) of the series `name`
+    pub fn set_collection(&mut self, name: impl Into<Box<str>>, position: usize) -> &mut Self {
+        self.opf.collection = Some((name.into(), position));
+        self
+    }
+
     pub fn add_identifier(
         &mut self,
         ty: IdentifierType,
@@ -189,31 +195,17 @@ impl<'a> EpubBuilder<'a> {
 
     pub fn finish(mut self, mut w: impl Read + Write + Seek) -> io::Result<()> {
         w.seek(io::SeekFrom::Start(0))?;
-        let w = BufWriter::new(w);
         assert!(!self.chapters.is_empty());
 
-        let mut zip = ZipWriter::new(w);
-        let stored =
-            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
-        let method = match self.compression {
-            Compression::Store => zip::CompressionMethod::Stored,
-            Compression::Deflate => zip::CompressionMethod::Deflated,
-        };
-        let compressed = SimpleFileOptions::default().compression_method(method);
-        zip.start_file("mimetype", stored)?;
-        zip.write_all(b"application/epub+zip")?;
-        zip.add_directory("EPUB", stored)?;
-        zip.add_directory("EPUB/css", stored)?;
-        zip.add_directory("META-INF", stored)?;
+        let (stored, compressed) = self.zip_options();
+        let mut zip = start_zip(BufWriter::new(w), stored)?;
         if !self.additional_resources.is_empty() {
             zip.add_directory("EPUB/assets", stored)?;
         }
-        zip.start_file("META-INF/container.xml", stored)?;
-        zip.write_all(CONTAINER_XML.as_bytes())?;
 
         // chunks here are just splitting the chapters into small enough files. I have this being a
         // little silly here because I want to check later if too-small chapters cause problems.
-        let chunks: Vec<_> = self
+        let chunk_lens: Vec<_> = self
             .chapters
             // .iter()
             // .map(|ch| std::slice::from_ref(ch))
@@ -234,7 +226,10 @@ impl<'a> EpubBuilder<'a> {
                     }
                 }
             })
+            .map(<[_]>::len)
             .collect();
+        self.resolve_links(&chunk_lens);
+        let chunks = split_chunks(&self.chapters, &chunk_lens);
 
         self.opf.manifest.push(ManifestItem::new("css/epub.css"));
 
@@ -249,30 +244,74 @@ impl<'a> EpubBuilder<'a> {
         }
 
         for (_id, rsc) in self.additional_resources {
-            let file = format!("EPUB/{}", rsc.src());
-            zip.start_file(file, compressed)?;
-            let mut item = ManifestItem::new_explicit(rsc.src().to_string(), rsc.media_type);
-            if self.cover.as_ref().is_some_and(|c| c.id() == rsc.id()) {
-                item.props |= ManifestProperties::COVER_IMAGE;
-            }
+            let cover = self.cover.as_ref().is_some_and(|c| c.id() == rsc.id());
+            let item = write_resource(&mut zip, &rsc, cover, compressed)?;
             self.opf.manifest.push(item);
-            zip.write_all(&rsc.data)?;
         }
 
-        let spec = self.opf.finish().map_err(|e| error!("{e:?}")).unwrap();
-
-        zip.start_file("EPUB/nav.xhtml", compressed)?;
-        write_nav(&mut zip, spec.native_title(), &chunks, &self.sections, lang)?;
+        let nav: Vec<_> = chapter_hrefs(&chunks)
+            .map(|(href, ch)| (href, ch.title().into()))
+            .collect();
+        write_end(zip, self.opf, &nav, &self.sections, (stored, compressed))
+    }
 
-        zip.start_file("EPUB/css/epub.css", compressed)?;
-        zip.write_all(include_str!("../../epub.css").as_bytes())?;
+    /// start writing the book to `w`, putting chapters into it as they are added rather than
+    /// keeping every one of them until the end. Everything but sections and chapters has to be
+    /// set before this
+    pub fn stream<W: Write + Seek>(mut self, mut w: W) -> io::Result<EpubStream<'a, W>> {
+        assert!(
+            self.chapters.is_empty(),
+            "chapters were added before streaming"
+        );
+        w.seek(io::SeekFrom::Start(0))?;
+        let (stored, _) = self.zip_options();
+        let zip = start_zip(BufWriter::new(w), stored)?;
+        self.opf.manifest.push(ManifestItem::new("css/epub.css"));
+        self.opf.manifest.push(ManifestItem::new("nav.xhtml"));
+        let mut stream = EpubStream {
+            book: self,
+            zip,
+            chunk_size: 0,
+            chunks: 0,
+            nav: Vec::new(),
+            targets: HashMap::new(),
+            held: Vec::new(),
+            written: HashSet::new(),
+        };
+        // the cover
+        stream.write_resources()?;
+        Ok(stream)
+    }
+}
 
-        zip.start_file("EPUB/package.opf", stored)?;
-        spec.write(&mut zip)?;
-        let mut w = zip.finish()?;
-        w.flush()?;
+impl EpubBuilder<'_> {
+    /// `(stored, compressed)`
+    fn zip_options(&self) -> (SimpleFileOptions, SimpleFileOptions) {
+        let stored =
+            SimpleFileOptions::default().compression_method(zip::CompressionMethod::Stored);
+        let method = match self.compression {
+            Compression::Store => zip::CompressionMethod::Stored,
+            Compression::Deflate => zip::CompressionMethod::Deflated,
+        };
+        (
+            stored,
+            SimpleFileOptions::default().compression_method(method),
+        )
+    }
 
-        Ok(())
+    /// points links between chapters to where the target chapter ends up
+    fn resolve_links(&mut self, chunk_lens: &[usize]) {
+        let mut targets: HashMap<Url, Rc<str>> = HashMap::new();
+        for (href, ch) in chapter_hrefs(&split_chunks(&self.chapters, chunk_lens)) {
+            let Some(src) = ch.source() else {
+                continue;
+            };
+            // multiple chapters from one page should point to the first
+            targets.entry(src.clone()).or_insert_with(|| href.into());
+        }
+        for ch in &mut self.chapters {
+            ch.resolve_links(&targets);
+        }
     }
 }
 
@@ -282,6 +321,206 @@ impl<'a> Default for EpubBuilder<'a> {
     }
 }
 
+/// an epub that chapters are written to as they are added, made by [`EpubBuilder::stream`].
+/// Only the chapters of the chunk being filled are kept, along with chunks that link to pages
+/// of their own site that are not in the book yet, in case those turn up later
+/// chunks [`EpubStream`] keeps waiting for the chapters they link to. Beyond this the oldest is
+/// written, with links to chapters not added yet pointing outside of the book
+const MAX_HELD_CHUNKS: usize = 16;
+
+pub struct EpubStream<'a, W: Write + Seek> {
+    /// metadata, and the chapters of the chunk being filled
+    book: EpubBuilder<'a>,
+    zip: ZipWriter<BufWriter<W>>,
+    /// approximate size of the chunk being filled
+    chunk_size: usize,
+    /// chunks named so far
+    chunks: usize,
+    /// `(href, title)` of every chapter in a named chunk
+    nav: Vec<(String, Box<str>)>,
+    /// where the chapters of each source ended up
+    targets: HashMap<Url, Rc<str>>,
+    /// chunks waiting for the chapters they link to, by number, oldest first
+    held: Vec<(usize, Vec<Chapter<'a>>)>,
+    /// resources already in the zip
+    written: HashSet<ImageId>,
+}
+
+impl<'a, W: Write + Seek> EpubStream<'a, W> {
+    pub fn add_section(&mut self, title: impl AsRef<str>) -> &mut Self {
+        let at = self.nav.len() + self.book.chapters.len();
+        self.book.sections.push((title.as_ref().into(), at));
+        self
+    }
+
+    pub fn add_chapter(&mut self, chapter: Chapter<'a>) -> io::Result<&mut Self> {
+        // chunked the same way as in `EpubBuilder::finish`
+        if !self.book.chapters.is_empty() && self.chunk_size >= self.book.chunk_size {
+            self.finish_chunk()?;
+        }
+        self.chunk_size += chapter.size();
+        self.book.add_chapter(chapter);
+        Ok(self)
+    }
+
+    pub fn extend_chapters(
+        &mut self,
+        chapters: impl IntoIterator<Item = Chapter<'a>>,
+    ) -> io::Result<&mut Self> {
+        for ch in chapters {
+            self.add_chapter(ch)?;
+        }
+        Ok(self)
+    }
+
+    /// write the rest of the chapters, then the table of contents and the package document
+    pub fn finish(mut self) -> io::Result<()> {
+        if !self.book.chapters.is_empty() {
+            self.finish_chunk()?;
+        }
+        assert!(!self.nav.is_empty());
+        for (i, mut chapters) in std::mem::take(&mut self.held) {
+            for ch in &mut chapters {
+                ch.resolve_links(&self.targets);
+            }
+            self.write_chunk(i, &chapters)?;
+        }
+        let options = self.book.zip_options();
+        write_end(
+            self.zip,
+            self.book.opf,
+            &self.nav,
+            &self.book.sections,
+            options,
+        )
+    }
+
+    /// name the chunk being filled, and write it unless it has to be held
+    fn finish_chunk(&mut self) -> io::Result<()> {
+        let chapters = std::mem::take(&mut self.book.chapters);
+        self.chunk_size = 0;
+        let i = self.chunks;
+        self.chunks += 1;
+        for ch in &chapters {
+            let href = format!("chunk_{i}.xhtml#{id}", id = ch.id());
+            if let Some(src) = ch.source() {
+                // multiple chapters from one page should point to the first
+                self.targets
+                    .entry(src.clone())
+                    .or_insert_with(|| href.as_str().into());
+            }
+            self.nav.push((href, ch.title().into()));
+        }
+        self.book
+            .opf
+            .manifest
+            .push(ManifestItem::new(format!("chunk_{i}.xhtml")));
+
+        // the chapters of this chunk may be what held chunks were waiting for
+        self.held.push((i, chapters));
+        let mut held = Vec::with_capacity(self.held.len());
+        for (j, mut chapters) in std::mem::take(&mut self.held) {
+            let mut ahead = false;
+            for ch in &mut chapters {
+                ch.resolve_links(&self.targets);
+                ahead |= ch.may_link_ahead();
+            }
+            if ahead {
+                if j == i {
+                    debug!("holding chunk {i}, it may link to later chapters");
+                }
+                held.push((j, chapters));
+            } else {
+                self.write_chunk(j, &chapters)?;
+            }
+        }
+        if held.len() > MAX_HELD_CHUNKS {
+            let (j, chapters) = held.remove(0);
+            debug!("writing chunk {j} without waiting for the later chapters it may link to");
+            self.write_chunk(j, &chapters)?;
+        }
+        self.held = held;
+        self.write_resources()
+    }
+
+    fn write_chunk(&mut self, i: usize, chapters: &[Chapter]) -> io::Result<()> {
+        let (_, compressed) = self.book.zip_options();
+        self.zip
+            .start_file(format!("EPUB/chunk_{i}.xhtml"), compressed)?;
+        write_chunk(&mut self.zip, chapters, self.book.opf.language)
+    }
+
+    /// write resources added since the last time
+    fn write_resources(&mut self) -> io::Result<()> {
+        let (stored, compressed) = self.book.zip_options();
+        for (id, rsc) in std::mem::take(&mut self.book.additional_resources) {
+            if !self.written.insert(id) {
+                continue;
+            }
+            if self.written.len() == 1 {
+                self.zip.add_directory("EPUB/assets", stored)?;
+            }
+            let cover = self.book.cover.as_ref().is_some_and(|c| c.id() == id);
+            let item = write_resource(&mut self.zip, &rsc, cover, compressed)?;
+            self.book.opf.manifest.push(item);
+        }
+        Ok(())
+    }
+}
+
+/// a zip with everything that comes before the content
+fn start_zip<W: Write + Seek>(w: W, stored: SimpleFileOptions) -> io::Result<ZipWriter<W>> {
+    let mut zip = ZipWriter::new(w);
+    zip.start_file("mimetype", stored)?;
+    zip.write_all(b"application/epub+zip")?;
+    zip.add_directory("EPUB", stored)?;
+    zip.add_directory("EPUB/css", stored)?;
+    zip.add_directory("META-INF", stored)?;
+    zip.start_file("META-INF/container.xml", stored)?;
+    zip.write_all(CONTAINER_XML.as_bytes())?;
+    Ok(zip)
+}
+
+fn write_resource<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    rsc: &ResolvedImage,
+    cover: bool,
+    options: SimpleFileOptions,
+) -> io::Result<ManifestItem> {
+    zip.start_file(format!("EPUB/{}", rsc.src()), options)?;
+    let mut item = ManifestItem::new_explicit(rsc.src().to_string(), rsc.media_type);
+    if cover {
+        item.props |= ManifestProperties::COVER_IMAGE;
+    }
+    zip.write_all(&rsc.data)?;
+    Ok(item)
+}
+
+/// the table of contents, stylesheet and package document, which need everything else to be
+/// known
+fn write_end<W: Write + Seek>(
+    mut zip: ZipWriter<W>,
+    opf: OpfBuilder,
+    nav: &[(String, Box<str>)],
+    sections: &[(Box<str>, usize)],
+    (stored, compressed): (SimpleFileOptions, SimpleFileOptions),
+) -> io::Result<()> {
+    let lang = opf.language;
+    let spec = opf.finish().map_err(|e| error!("{e:?}")).unwrap();
+
+    zip.start_file("EPUB/nav.xhtml", compressed)?;
+    write_nav(&mut zip, spec.native_title(), nav, sections, lang)?;
+
+    zip.start_file("EPUB/css/epub.css", compressed)?;
+    zip.write_all(include_str!("../../epub.css").as_bytes())?;
+
+    zip.start_file("EPUB/package.opf", stored)?;
+    spec.write(&mut zip)?;
+    let mut w = zip.finish()?;
+    w.flush()?;
+    Ok(())
+}
+
 const CONTAINER_XML: &str = r#"<?xml version="1.0"?>
 <container version="1.0" xmlns="urn:oasis:names:tc:opendocument:xmlns:container">
    <rootfiles>
@@ -311,6 +550,16 @@ fn section_ranges(
     Some(ret)
 }
 
+fn split_chunks<'a, 'h>(mut chapters: &'a [Chapter<'h>], lens: &[usize]) -> Vec<&'a [Chapter<'h>]> {
+    lens.iter()
+        .map(|&len| {
+            let (chunk, rest) = chapters.split_at(len);
+            chapters = rest;
+            chunk
+        })
+        .collect()
+}
+
 fn chapter_hrefs<'h, 'a>(
     org: &[&'a [Chapter<'h>]],
 ) -> impl Iterator<Item = (String, &'a Chapter<'h>)> {
@@ -326,55 +575,55 @@ fn chapter_hrefs<'h, 'a>(
 
 fn write_entry<W: Write>(
     ol: &mut epub::xml::Element<W>,
-    href: &str,
-    ch: &Chapter,
+    (href, title): &(String, Box<str>),
 ) -> io::Result<()> {
     ol.mkel("li", [])?
-        .mkel("a", [("href", href)])?
-        .write_field(EscapeBody(ch.title()))?;
+        .mkel("a", [("href", href.as_str())])?
+        .write_field(EscapeBody(title))?;
     writeln!(ol)
 }
 
 fn write_no_sections<W: Write>(
     nav: &mut epub::xml::Element<W>,
-    org: &[&[Chapter]],
+    chapters: &[(String, Box<str>)],
 ) -> io::Result<()> {
     let mut ol = nav.mkel("ol", [])?;
-    for (href, ch) in chapter_hrefs(org) {
-        write_entry(&mut ol, &href, ch)?;
+    for entry in chapters {
+        write_entry(&mut ol, entry)?;
     }
     Ok(())
 }
 
 fn write_sections<'a, W: Write>(
     nav: &mut epub::xml::Element<W>,
-    org: &[&[Chapter]],
+    chapters: &[(String, Box<str>)],
     sections: impl Iterator<Item = (Option<&'a str>, usize)>,
 ) -> io::Result<()> {
     let mut ol = nav.mkel("ol", [])?;
-    let mut chapters = chapter_hrefs(org);
+    let mut chapters = chapters.iter();
     for (title, len) in sections {
         if let Some(title) = title {
             let mut li = ol.mkel("li", [])?;
             li.mkel("span", [])?.write_field(EscapeBody(title))?;
             let mut ol = li.mkel("ol", [])?;
 
-            for (href, ch) in (&mut chapters).take(len) {
-                write_entry(&mut ol, &href, ch)?;
+            for entry in (&mut chapters).take(len) {
+                write_entry(&mut ol, entry)?;
             }
         } else {
-            for (href, ch) in (&mut chapters).take(len) {
-                write_entry(&mut ol, &href, ch)?;
+            for entry in (&mut chapters).take(len) {
+                write_entry(&mut ol, entry)?;
             }
         }
     }
     Ok(())
 }
 
+/// `chapters` are `(href, title)`
 fn write_nav<W: Write>(
     w: W,
     title: &str,
-    org: &[&[Chapter]],
+    chapters: &[(String, Box<str>)],
     sections: &[(Box<str>, usize)],
     lang: Lang,
 ) -> io::Result<()> {
@@ -397,9 +646,9 @@ fn write_nav<W: Write>(
     let mut nav = body.mkel("nav", [("epub:type", "toc")])?;
     nav.mkel("h2", [])?.write_field(title)?;
     if let Some(sections) = section_ranges(sections) {
-        write_sections(&mut nav, org, sections)?
+        write_sections(&mut nav, chapters, sections)?
     } else {
-        write_no_sections(&mut nav, org)?
+        write_no_sections(&mut nav, chapters)?
     }
     drop(nav);
     drop(body);
@@ -442,3 +691,111 @@ fn write_chunk(w: impl Write, chunk: &[Chapter], lang: Lang) -> io::Result<()> {
     }
     doc.finish()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use zip::ZipArchive;
+
+    use super::*;
+    use crate::chapter::ChapterBuilder;
+
+    fn chapter(n: u32, link: Option<&str>) -> Chapter<'static> {
+        let mut ch = ChapterBuilder::new();
+        ch.title_set(format!("Chapter {n}"))
+            .source_set(format!("https://example.com/{n}").parse().unwrap())
+            .add_text("text");
+        if let Some(link) = link {
+            ch.add_chapter_link(link.parse().unwrap(), "link");
+        }
+        ch.finish().unwrap().swap_remove(0)
+    }
+
+    fn read(zip: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> String {
+        io::read_to_string(zip.by_name(name).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn stream() {
+        let mut b = EpubBuilder::new();
+        b.set_title("streamed")
+            .add_author("author")
+            .add_identifier(IdentifierType::Adhoc, "stream");
+        let mut out = Cursor::new(Vec::new());
+        let mut stream = b.stream(&mut out).unwrap();
+        let first = chapter(1, Some("https://example.com/3"));
+        let last = chapter(3, Some("https://other.com/"));
+        let last_id = last.id().to_string();
+        stream
+            .add_chapter(first)
+            .unwrap()
+            .add_section("Part 2")
+            .add_chapter(chapter(2, Some("https://example.com/1")))
+            .unwrap()
+            .add_chapter(last)
+            .unwrap();
+        stream.finish().unwrap();
+
+        let mut zip = ZipArchive::new(Cursor::new(out.into_inner())).unwrap();
+        // held until chapter 3 was added
+        let chunk = read(&mut zip, "EPUB/chunk_0.xhtml");
+        assert!(chunk.contains(&format!("href=\"chunk_2.xhtml#{last_id}\"")));
+        let chunk = read(&mut zip, "EPUB/chunk_2.xhtml");
+        assert!(chunk.contains("href=\"https://other.com/\""));
+        let nav = read(&mut zip, "EPUB/nav.xhtml");
+        assert!(nav.contains("<span>Part 2</span>"));
+        assert_eq!(nav.matches("<li><a").count(), 3);
+        let opf = read(&mut zip, "EPUB/package.opf");
+        let spine = &opf[opf.find("<spine").unwrap()..];
+        let at = |s: &str| spine.find(s).unwrap();
+        assert!(at("chunk_0") < at("chunk_1") && at("chunk_1") < at("chunk_2"));
+    }
+
+    #[test]
+    fn stream_releases_held() {
+        let mut b = EpubBuilder::new();
+        b.set_title("streamed")
+            .add_author("author")
+            .add_identifier(IdentifierType::Adhoc, "stream");
+        let mut out = Cursor::new(Vec::new());
+        let mut stream = b.stream(&mut out).unwrap();
+        let n = 100;
+        let mut ids = Vec::new();
+        for i in 1..=n {
+            let ch = chapter(i, Some(&format!("https://example.com/{}", i + 1)));
+            ids.push(ch.id().to_string());
+            stream.add_chapter(ch).unwrap();
+            // only the chunk linking to the chapter not added yet
+            assert!(stream.held.len() <= 1);
+        }
+        stream.finish().unwrap();
+
+        let mut zip = ZipArchive::new(Cursor::new(out.into_inner())).unwrap();
+        for i in 0..n as usize - 1 {
+            let chunk = read(&mut zip, &format!("EPUB/chunk_{i}.xhtml"));
+            let next = &ids[i + 1];
+            assert!(chunk.contains(&format!("href=\"chunk_{}.xhtml#{next}\"", i + 1)));
+        }
+        let chunk = read(&mut zip, &format!("EPUB/chunk_{}.xhtml", n - 1));
+        assert!(chunk.contains(&format!("href=\"https://example.com/{}\"", n + 1)));
+
+        // links to chapters that never come are given up on
+        let mut b = EpubBuilder::new();
+        b.set_title("streamed")
+            .add_author("author")
+            .add_identifier(IdentifierType::Adhoc, "stream");
+        let mut out = Cursor::new(Vec::new());
+        let mut stream = b.stream(&mut out).unwrap();
+        for i in 1..=n {
+            stream
+                .add_chapter(chapter(i, Some("https://example.com/missing")))
+                .unwrap();
+            assert!(stream.held.len() <= MAX_HELD_CHUNKS);
+        }
+        stream.finish().unwrap();
+        let mut zip = ZipArchive::new(Cursor::new(out.into_inner())).unwrap();
+        let chunk = read(&mut zip, "EPUB/chunk_0.xhtml");
+        assert!(chunk.contains("href=\"https://example.com/missing\""));
+    }
+}
@@ -22,6 +22,8 @@ pub struct OpfBuilder {
     pub publisher: OptSetting,
     pub date: SystemTime,
     pub include_toc: bool,
+    /// the series the book belongs to, with its position in it (starting at 1)
+    pub collection: Option<(Box<str>, usize)>,
 
     /// list of identifiers according to <http://purl.org/dc/terms/identifier>
     ///
@@ -76,6 +78,7 @@ pub struct OpfSpec {
     subtitle: OptSetting,
     publisher: OptSetting,
     date: SystemTime,
+    collection: Option<(Box<str>, usize)>,
 
     identifiers: Vec<(IdentifierType, Box<str>)>,
     contributors: Vec<(ContributorRole, StrLang)>,
@@ -94,6 +97,7 @@ impl OpfBuilder {
             subtitle: OptSetting::new(),
             publisher: OptSetting::new(),
             date: SystemTime::UNIX_EPOCH,
+            collection: None,
             manifest: Vec::new(),
             identifiers: Vec::new(),
             contributors: Vec::new(),
@@ -113,6 +117,7 @@ impl OpfBuilder {
             subtitle,
             publisher,
             date,
+            collection,
             manifest,
             identifiers,
             contributors,
@@ -235,6 +240,7 @@ impl OpfBuilder {
             subtitle,
             publisher,
             date,
+            collection,
             identifiers,
             contributors,
             manifest_nav,
@@ -384,6 +390,29 @@ impl OpfSpec {
                 metadata.mkel("dc:publisher", [])?.write_field(publisher)?;
                 metadata.write_lf()?;
             }
+            if let Some((name, position)) = &self.collection {
+                metadata
+                    .mkel(
+                        "meta",
+                        [("property", "belongs-to-collection"), ("id", "collection")],
+                    )?
+                    .write_field(name)?;
+                metadata.write_lf()?;
+                metadata
+                    .mkel(
+                        "meta",
+                        [("refines", "#collection"), ("property", "collection-type")],
+                    )?
+                    .write_field("series")?;
+                metadata.write_lf()?;
+                metadata
+                    .mkel(
+                        "meta",
+                        [("refines", "#collection"), ("property", "group-position")],
+                    )?
+                    .write_field(position)?;
+                metadata.write_lf()?;
+            }
         }
         {
             let mut manifest = pkg.mkel("manifest", [])?;
@@ -615,4 +644,24 @@ mod tests {
         builder.add_identifier(IdentifierType::Isbn13, "978-1-56619-909-4");
         builder.finish().unwrap();
     }
+
+    #[test]
+    fn collection() {
+        let mut builder = OpfBuilder {
+            title: Some("test 2".into()),
+            collection: Some(("test".into(), 2)),
+            manifest: vec![ManifestItem::try_new("nav.xhtml").unwrap()],
+            ..Default::default()
+        };
+        builder.add_identifier(IdentifierType::Url, "https://example.com/#volume-2");
+        let mut opf = Vec::new();
+        builder.finish().unwrap().write(&mut opf).unwrap();
+        let opf = String::from_utf8(opf).unwrap();
+        assert!(
+            opf.contains(r##"<meta property="belongs-to-collection" id="collection">test</meta>"##)
+        );
+        assert!(
+            opf.contains(r##"<meta refines="#collection" property="group-position">2</meta>"##)
+        );
+    }
 }
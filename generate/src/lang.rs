@@ -74,10 +74,10 @@ impl FromStr for Lang {
 ///
 /// see: <https://www.w3.org/TR/epub/#sec-alternate-script>
 // FIXME: the eq implementation is wrong
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct StrLang(StrLangInner);
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 enum StrLangInner {
     // have funny repr for iter implementation
     Single((Lang, Box<str>)),
@@ -0,0 +1,214 @@
+//! how far each content range of a spec got, so an interrupted or failed crawl can pick up
+//! where it stopped
+//!
+//! this lives in the cache database, alongside the fetch cache. Checkpoints are keyed by a hash
+//! of the spec's text, so editing the spec starts every range over
+
+use anyhow::{Context, Result};
+use rusqlite::{Connection, OptionalExtension};
+use url::Url;
+
+/// hash of a spec's text, stable between runs and versions. This is 64-bit FNV-1a
+pub fn spec_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// the page being worked on, or the last page of a range that was finished
+    pub url: Url,
+    /// every page before `url`, starting with the start of the range
+    pub chain: Vec<Url>,
+    /// chapters built from the pages in `chain`
+    pub chapters: usize,
+}
+
+/// the checkpoints of one version of a book's spec
+pub struct Checkpoints {
+    conn: Connection,
+    /// homepage of the book
+    book: String,
+    /// hex of [`spec_hash`]
+    spec: String,
+}
+
+impl Checkpoints {
+    pub fn new(conn: Connection, book: &Url, spec: u64) -> rusqlite::Result<Self> {
+        fetch::migrate(&conn)?;
+        Ok(Checkpoints {
+            conn,
+            book: book.as_str().to_owned(),
+            spec: format!("{spec:016x}"),
+        })
+    }
+
+    /// the checkpoint of content entry `entry`
+    pub fn get(&self, entry: usize) -> Result<Option<Checkpoint>> {
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT url, pages, chapters FROM range_checkpoint WHERE spec=?1 AND entry=?2",
+        )?;
+        let row: Option<(String, i64, i64)> = stmt
+            .query_row((&self.spec, entry as i64), |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+            })
+            .optional()?;
+        let Some((url, pages, chapters)) = row else {
+            return Ok(None);
+        };
+        let parse = |u: &str| Url::parse(u).with_context(|| format!("stored url {u} is invalid"));
+        let mut stmt = self.conn.prepare_cached(
+            "SELECT url FROM range_chain WHERE spec=?1 AND entry=?2 AND n < ?3 ORDER BY n",
+        )?;
+        let chain = stmt
+            .query_map((&self.spec, entry as i64, pages), |row| {
+                row.get::<_, String>(0)
+            })?
+            .map(|u| parse(&u?))
+            .collect::<Result<Vec<_>>>()?;
+        if chain.len() != pages as usize {
+            log::warn!("checkpoint of content entry {entry} is missing pages, ignoring it");
+            return Ok(None);
+        }
+        Ok(Some(Checkpoint {
+            url: parse(&url)?,
+            chain,
+            chapters: chapters as usize,
+        }))
+    }
+
+    /// replace the checkpoint, along with its whole chain. Checkpoints of earlier versions of the
+    /// spec are forgotten
+    pub fn set(&self, entry: usize, checkpoint: &Checkpoint) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "DELETE FROM range_chain WHERE spec IN
+                (SELECT spec FROM range_checkpoint WHERE book=?1 AND spec!=?2)",
+            (&self.book, &self.spec),
+        )?;
+        tx.execute(
+            "DELETE FROM range_checkpoint WHERE book=?1 AND spec!=?2",
+            (&self.book, &self.spec),
+        )?;
+        tx.execute(
+            "DELETE FROM range_chain WHERE spec=?1 AND entry=?2",
+            (&self.spec, entry as i64),
+        )?;
+        for (n, url) in checkpoint.chain.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO range_chain (spec, entry, n, url) VALUES (?1, ?2, ?3, ?4)",
+                (&self.spec, entry as i64, n as i64, url.as_str()),
+            )?;
+        }
+        tx.execute(
+            "INSERT OR REPLACE INTO range_checkpoint (spec, entry, book, url, pages, chapters)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (
+                &self.spec,
+                entry as i64,
+                &self.book,
+                checkpoint.url.as_str(),
+                checkpoint.chain.len() as i64,
+                checkpoint.chapters as i64,
+            ),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// move the checkpoint on from page `n` of the chain (0-based), `page`, to `next`, with
+    /// `chapters` built from the pages up to and including `page`
+    pub fn push(
+        &self,
+        entry: usize,
+        n: usize,
+        page: &Url,
+        next: &Url,
+        chapters: usize,
+    ) -> Result<()> {
+        let tx = self.conn.unchecked_transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO range_chain (spec, entry, n, url) VALUES (?1, ?2, ?3, ?4)",
+            (&self.spec, entry as i64, n as i64, page.as_str()),
+        )?;
+        tx.execute(
+            "INSERT OR REPLACE INTO range_checkpoint (spec, entry, book, url, pages, chapters)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            (
+                &self.spec,
+                entry as i64,
+                &self.book,
+                next.as_str(),
+                n as i64 + 1,
+                chapters as i64,
+            ),
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_hash_is_fnv() {
+        assert_eq!(spec_hash(""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(spec_hash("a"), 0xaf63_dc4c_8601_ec8c);
+        assert_ne!(spec_hash("title = \"a\""), spec_hash("title = \"b\""));
+    }
+
+    #[test]
+    fn get_and_set() {
+        let book: Url = "https://example.com/".parse().unwrap();
+        let spec = spec_hash("title = \"a\"");
+        let c = Checkpoints::new(Connection::open_in_memory().unwrap(), &book, spec).unwrap();
+        assert_eq!(c.get(0).unwrap(), None);
+        let url = |n: u32| -> Url { format!("https://example.com/{n}").parse().unwrap() };
+        let mut ck = Checkpoint {
+            url: url(1),
+            chain: Vec::new(),
+            chapters: 0,
+        };
+        c.set(0, &ck).unwrap();
+        assert_eq!(c.get(0).unwrap().as_ref(), Some(&ck));
+        ck = Checkpoint {
+            url: url(3),
+            chain: vec![url(1), url(2)],
+            chapters: 4,
+        };
+        c.set(0, &ck).unwrap();
+        assert_eq!(c.get(0).unwrap(), Some(ck));
+        assert_eq!(c.get(1).unwrap(), None);
+
+        c.push(0, 2, &url(3), &url(4), 5).unwrap();
+        let ck = c.get(0).unwrap().unwrap();
+        assert_eq!(ck.url, url(4));
+        assert_eq!(ck.chain, [url(1), url(2), url(3)]);
+        assert_eq!(ck.chapters, 5);
+
+        // starting over forgets the rest of the chain
+        let start = Checkpoint {
+            url: url(1),
+            chain: Vec::new(),
+            chapters: 0,
+        };
+        c.set(0, &start).unwrap();
+        c.push(0, 0, &url(1), &url(2), 1).unwrap();
+        assert_eq!(c.get(0).unwrap().unwrap().chain, [url(1)]);
+
+        // an edited spec does not see the checkpoints, and replaces them once it sets its own
+        let Checkpoints { conn, .. } = c;
+        let edited = Checkpoints::new(conn, &book, spec_hash("title = \"b\"")).unwrap();
+        assert_eq!(edited.get(0).unwrap(), None);
+        edited.set(1, &start).unwrap();
+        let count = |table: &str| -> i64 {
+            let sql = format!("SELECT count(*) FROM {table}");
+            edited.conn.query_row(&sql, [], |row| row.get(0)).unwrap()
+        };
+        assert_eq!(count("range_checkpoint"), 1);
+        assert_eq!(count("range_chain"), 0);
+    }
+}
@@ -1,16 +1,26 @@
 //! common rules that should rarely be overriden
 
-use std::borrow::Cow;
+use std::{borrow::Cow, ops::Range};
+
+use ahash::{HashMap, HashMapExt, HashSet, HashSetExt};
 
 use anyhow::{Context, Result};
 use ego_tree::NodeRef;
 use fetch::FetchContext;
-use generate::{Chapter, ChapterBuilder, chapter::SpanStyle, image::Image};
+use generate::{
+    Chapter, ChapterBuilder,
+    chapter::{ListKind, SpanStyle},
+    image::Image,
+};
 use log::{trace, warn};
 use regex_lite::Regex;
 use scraper::{ElementRef, Html, Node, node::Element};
+use url::Url;
 
-use crate::overrides::OverrideSet;
+use crate::{
+    def::{LinkPolicy, RulesetDef, sed::Sed},
+    overrides::OverrideSet,
+};
 
 pub trait RuleSet {
     fn title(&self, html: &Html) -> String;
@@ -43,16 +53,28 @@ impl Rules {
             "reigokai" => Self::new(il::Reigokai::new()),
             "syosetu" => Self::new(syosetu::Rule::new()),
             "shikka" => Self::new(shikka::Rule::new()),
+            "generic" => Self::new(generic::Rule::new()),
             _ => return None,
         };
 
         Some(rules)
     }
 
+    /// compile a ruleset defined in the spec
+    pub fn new_from_def(def: &RulesetDef) -> Result<Self> {
+        let rule = crate::rulesets::declarative::Rule::new(def).context("invalid ruleset")?;
+        Ok(Self::new(rule))
+    }
+
     pub fn parse<'a>(&self, html: &'a Html) -> Result<(Vec<Chapter<'a>>, Option<Cow<'a, str>>)> {
         self.parse_with_overrides(html, &OverrideSet::empty(), None)
     }
 
+    /// where the page leads, without building its chapters
+    pub fn next_chapter<'a>(&self, html: &'a Html) -> Option<Cow<'a, str>> {
+        self.inner.next_chapter(html)
+    }
+
     pub fn parse_with_overrides<'a>(
         &self,
         html: &'a Html,
@@ -66,6 +88,9 @@ impl Rules {
             self.inner.title(html)
         };
         ch.title_set(title.clone());
+        if let Some(url) = &overrides.url {
+            ch.source_set(url.clone());
+        }
         self.inner
             .parse_body(html, overrides, &mut ch)
             .with_context(|| format!("invalid chapter: {title}"))?;
@@ -84,8 +109,12 @@ impl Rules {
     }
 }
 
-pub struct ProcessConfig {
+pub struct ProcessConfig<'a> {
     pub br_is_paragraph: bool,
+    /// can be overridden by the spec
+    pub links: LinkPolicy,
+    /// hsed rules of the ruleset, applied before the overrides of the spec
+    pub rules: &'a [Sed],
 }
 
 /// basic processing of "normal" blocks
@@ -94,6 +123,10 @@ pub struct ProcessConfig {
 /// - text of `<p>` recursively, and ends paragraphs
 /// - handles styling
 /// - handles `<hr>` and similar horizontal separators
+/// - handles `<ol>`, `<ul>`, and `<li>`, including nested lists
+/// - handles `<table>` with its rows and cells, including `colspan` and `rowspan`
+/// - handles `<a>` according to the link policy
+/// - handles footnote directives (`f`) of the ruleset and overrides
 /// - converts `<br>` tags to LF for setting-specific handling
 pub fn add_basic<'a>(
     ch: &mut ChapterBuilder<'a>,
@@ -101,42 +134,135 @@ pub fn add_basic<'a>(
     overrides: &OverrideSet,
     config: &ProcessConfig,
 ) {
-    let mut enabled: Vec<_> = overrides.replacers().map(|_| 0).collect();
-    descend(ch, *el, overrides, config, &mut enabled, 1);
+    let rules: Vec<_> = config.rules.iter().chain(overrides.replacers()).collect();
+    let mut enabled: Vec<_> = rules.iter().map(|_| 0).collect();
+    let cx = Descent {
+        notes: NoteIndex::new(&el, &rules),
+        rules: &rules,
+        overrides,
+        config,
+    };
+    descend(ch, *el, &cx, &mut enabled, 1);
+}
+
+/// what stays the same while [`add_basic`] descends into an element
+struct Descent<'c, 'a> {
+    rules: &'c [&'c Sed],
+    overrides: &'c OverrideSet<'c>,
+    config: &'c ProcessConfig<'c>,
+    notes: NoteIndex<'a>,
+}
+
+/// the ids of the document, for footnote directives to look up instead of walking it every time
+#[derive(Default)]
+struct NoteIndex<'a> {
+    /// first element with each id
+    ids: HashMap<&'a str, ElementRef<'a>>,
+    /// ids of footnote bodies referenced by an element matching a footnote directive
+    targets: HashSet<&'a str>,
+}
+
+impl<'a> NoteIndex<'a> {
+    fn new(el: &ElementRef<'a>, rules: &[&Sed]) -> Self {
+        let note_rules: Vec<_> = rules.iter().filter(|r| r.is_footnote_el()).collect();
+        if note_rules.is_empty() {
+            return Self::default();
+        }
+        let root = el.ancestors().last().unwrap_or(**el);
+        let elements = || root.descendants().filter_map(ElementRef::wrap);
+        let mut index = NoteIndex {
+            ids: HashMap::new(),
+            targets: HashSet::new(),
+        };
+        for e in elements() {
+            if let Some(id) = e.value().id() {
+                index.ids.entry(id).or_insert(e);
+            }
+        }
+        for e in elements().filter(|e| note_rules.iter().any(|r| r.is_el_match(e))) {
+            if let Some(id) = index.note_target(&e).and_then(|t| t.value().id()) {
+                index.targets.insert(id);
+            }
+        }
+        index
+    }
+
+    /// element a footnote reference links to, like `<li id="fn1">` for `<sup><a href="#fn1">`
+    fn note_target(&self, el: &ElementRef<'a>) -> Option<ElementRef<'a>> {
+        let id = el
+            .descendants()
+            .filter_map(ElementRef::wrap)
+            .filter(|e| e.value().name() == "a")
+            .find_map(|a| a.attr("href")?.strip_prefix('#'))?;
+        let target = *self.ids.get(id)?;
+        // `#top` and the like
+        let is_outer = target.id() == el.id() || el.ancestors().any(|a| a.id() == target.id());
+        (!is_outer).then_some(target)
+    }
+
+    /// whether `el` is the body of a footnote referenced somewhere else
+    fn is_note_target(&self, el: &ElementRef) -> bool {
+        el.value().id().is_some_and(|id| self.targets.contains(id))
+    }
+
+    /// whether `a` links to a footnote reference matching `rule`
+    fn is_note_backlink(&self, a: &ElementRef, rule: &Sed) -> bool {
+        let Some(id) = a.attr("href").and_then(|h| h.strip_prefix('#')) else {
+            return false;
+        };
+        let Some(target) = self.ids.get(id) else {
+            return false;
+        };
+        std::iter::once(*target)
+            .chain(target.ancestors().filter_map(ElementRef::wrap))
+            .any(|e| rule.is_el_match(&e))
+    }
 }
 
-/// enabled is the level that the corresponding override was enabled at. enabled == 0 means it's
+/// enabled is the level that the corresponding rule was enabled at. enabled == 0 means it's
 /// disabled
 fn descend<'a>(
     ch: &mut ChapterBuilder<'a>,
     el: NodeRef<'a, Node>,
-    overrides: &OverrideSet,
-    config: &ProcessConfig,
+    cx: &Descent<'_, 'a>,
     enabled: &mut [u32],
     level: u32,
 ) {
+    let Descent {
+        rules,
+        overrides,
+        config,
+        notes,
+    } = cx;
+    let enabled_rules = |enabled: &[u32]| {
+        rules
+            .iter()
+            .zip(enabled)
+            .filter(|(_r, e)| **e != 0)
+            .map(|(r, _)| *r)
+            .collect::<Vec<_>>()
+    };
     match el.value() {
         scraper::Node::Document => (),
         scraper::Node::Fragment => (),
         scraper::Node::Doctype(_) => (),
         scraper::Node::Comment(_) => (),
         scraper::Node::Text(txt) => {
-            let txt = overrides
-                .replacers()
-                .zip(&*enabled)
-                .filter(|(_r, e)| **e != 0)
-                .map(|(r, _)| r)
+            let active = enabled_rules(enabled);
+            let txt = active
+                .iter()
                 .fold(Cow::from(&**txt), |acc, sed| sed.apply_text(acc));
-            ch.add_text(txt);
+            add_text_notes(ch, txt, &active);
         }
         scraper::Node::Element(e) => {
+            let el_ref = ElementRef::wrap(el).unwrap();
             // enable elements that are disabled this level
-            for (r, e) in overrides.replacers().zip(&mut *enabled) {
+            for (r, e) in rules.iter().zip(&mut *enabled) {
                 if *e != 0 {
                     debug_assert!(*e < level);
                     continue;
                 }
-                if r.is_el_match(&ElementRef::wrap(el).unwrap()) {
+                if r.is_el_match(&el_ref) {
                     trace!(target: "parsing", "enabling {r:} on {:?}", el.value().as_element().unwrap());
                     if r.is_del() {
                         // re-enable since we're skipping
@@ -150,15 +276,92 @@ fn descend<'a>(
                     *e = level;
                 }
             }
+            let note_rules = || rules.iter().filter(|r| r.is_footnote_el());
+            let is_target = notes.is_note_target(&el_ref);
+            let is_note = !is_target && note_rules().any(|r| r.is_el_match(&el_ref));
             match e.name() {
+                // already added where it's referenced
+                _ if is_target => (),
+                _ if is_note => {
+                    let body = notes.note_target(&el_ref).unwrap_or(el_ref);
+                    ch.footnote_start();
+                    for child in body.children() {
+                        descend(ch, child, cx, enabled, level + 1);
+                    }
+                    ch.footnote_finish();
+                }
                 "hr" => {
                     ch.add_separator();
                 }
                 "br" => {
                     ch.add_line_break();
                 }
-                "ol" | "ul" | "li" => {
-                    warn!(target: "parsing", "TODO: handle lists");
+                "ol" | "ul" => {
+                    let kind = if e.name() == "ol" {
+                        ListKind::Ordered
+                    } else {
+                        ListKind::Unordered
+                    };
+                    ch.list_start(kind);
+                    for child in el.children() {
+                        descend(ch, child, cx, enabled, level + 1);
+                    }
+                    ch.list_finish();
+                }
+                "li" => {
+                    ch.list_item_start();
+                    for child in el.children() {
+                        descend(ch, child, cx, enabled, level + 1);
+                    }
+                    ch.list_item_finish();
+                }
+                "table" => {
+                    ch.table_start();
+                    for child in el.children() {
+                        descend(ch, child, cx, enabled, level + 1);
+                    }
+                    ch.table_finish();
+                }
+                "tr" => {
+                    let head = el
+                        .parent()
+                        .and_then(|p| p.value().as_element())
+                        .is_some_and(|p| p.name() == "thead");
+                    ch.table_row_start(head);
+                    for child in el.children() {
+                        descend(ch, child, cx, enabled, level + 1);
+                    }
+                    ch.table_row_finish();
+                }
+                "td" | "th" => {
+                    let span = |attr| {
+                        e.attr(attr)
+                            .and_then(|s| s.trim().parse().ok())
+                            .unwrap_or(1)
+                    };
+                    ch.table_cell_start(e.name() == "th", span("colspan"), span("rowspan"));
+                    for child in el.children() {
+                        descend(ch, child, cx, enabled, level + 1);
+                    }
+                    ch.table_cell_finish();
+                }
+                // links back from the footnote body to the reference
+                "a" if note_rules().any(|r| notes.is_note_backlink(&el_ref, r)) => (),
+                "a" => {
+                    let policy = overrides.links.unwrap_or(config.links);
+                    // links only hold styled text, so anything else just keeps its content,
+                    // like an image linking to its full size version
+                    let href = link_href(e, overrides.url.as_ref())
+                        .filter(|_| policy != LinkPolicy::Drop && is_text_only(&el_ref));
+                    if href.is_some() {
+                        ch.link_start();
+                    }
+                    for child in el.children() {
+                        descend(ch, child, cx, enabled, level + 1);
+                    }
+                    if let Some(href) = href {
+                        ch.link_finish(href, policy == LinkPolicy::Internal);
+                    }
                 }
                 "img" => {
                     let Some(src) = e.attr("src") else {
@@ -190,7 +393,7 @@ fn descend<'a>(
                         ch.span_style += SpanStyle::bold();
                     }
                     for child in el.children() {
-                        descend(ch, child, overrides, config, enabled, level + 1);
+                        descend(ch, child, cx, enabled, level + 1);
                     }
                     ch.span_style_set(prev_style);
                     if e.name() == "p" {
@@ -209,6 +412,61 @@ fn descend<'a>(
     }
 }
 
+/// adds text, turning matches of text footnote directives in `rules` into footnotes
+fn add_text_notes<'a>(ch: &mut ChapterBuilder<'a>, txt: Cow<'a, str>, rules: &[&Sed]) {
+    let find = |s: &str| {
+        rules
+            .iter()
+            .filter_map(|r| r.footnote_match(s))
+            .filter(|(whole, _)| !whole.is_empty())
+            .min_by_key(|(whole, _)| whole.start)
+    };
+    if find(&txt).is_none() {
+        ch.add_text(txt);
+        return;
+    }
+    let slice = |r: Range<usize>| match &txt {
+        Cow::Borrowed(s) => Cow::Borrowed(&s[r]),
+        Cow::Owned(s) => Cow::Owned(s[r].to_owned()),
+    };
+    let mut pos = 0;
+    while let Some((whole, body)) = find(&txt[pos..]) {
+        if whole.start > 0 {
+            ch.add_text(slice(pos..pos + whole.start));
+        }
+        ch.add_footnote(slice(pos + body.start..pos + body.end));
+        pos += whole.end;
+    }
+    if pos < txt.len() {
+        ch.add_text(slice(pos..txt.len()));
+    }
+}
+
+/// absolute target of a link, if it goes to another page
+fn link_href(e: &Element, base: Option<&Url>) -> Option<Url> {
+    let href = e.attr("href")?.trim();
+    if href.is_empty() || href.starts_with('#') {
+        return None;
+    }
+    let url = match base {
+        Some(base) => base.join(href),
+        None => Url::parse(href),
+    };
+    url.ok().filter(|u| matches!(u.scheme(), "http" | "https"))
+}
+
+/// whether everything inside of `el` is text, with styles at most
+fn is_text_only(el: &ElementRef) -> bool {
+    const INLINE: &[&str] = &[
+        "abbr", "b", "bdi", "bdo", "cite", "code", "del", "em", "font", "i", "ins", "kbd", "mark",
+        "q", "rb", "rp", "rt", "ruby", "s", "samp", "small", "span", "strong", "sub", "time", "u",
+        "var", "wbr",
+    ];
+    el.descendent_elements()
+        .skip(1)
+        .all(|e| INLINE.contains(&e.value().name()))
+}
+
 pub fn is_hr(el: &ElementRef) -> bool {
     if el.value().name() == "hr" {
         return true;
@@ -264,4 +522,146 @@ mod tests {
         assert!(!is_hr(&telref!("<p>—Great Forest—</p>", "p")));
         assert!(!is_hr(&telref!("<p></p>", "p")));
     }
+
+    #[test]
+    fn lists_are_kept() {
+        let html = Html::parse_fragment(
+            "<div><ul>\n<li>HP: 10</li>\n<li>Skills<ol><li>Appraisal</li></ol></li>\n</ul></div>",
+        );
+        let el = html
+            .select(&Selector::parse("div").unwrap())
+            .next()
+            .unwrap();
+        let mut ch = ChapterBuilder::new();
+        ch.title_set("status");
+        let pcfg = ProcessConfig {
+            br_is_paragraph: false,
+            links: LinkPolicy::Internal,
+            rules: &[],
+        };
+        add_basic(&mut ch, el, &OverrideSet::empty(), &pcfg);
+        let ch = ch.finish().unwrap();
+        assert_eq!(
+            ch[0].md().to_string(),
+            "# status\n\n- HP: 10\n- Skills\n\n  1. Appraisal"
+        );
+    }
+
+    #[test]
+    fn tables_are_kept() {
+        let html = Html::parse_fragment(
+            "<div><table>\n<caption>Status</caption>\n<thead><tr><th>Stat</th><th>Value</th></tr></thead>\n<tbody>\n<tr><td>HP</td><td><b>10</b></td></tr>\n<tr><td colspan=\"2\">no skills</td></tr>\n</tbody></table></div>",
+        );
+        let el = html
+            .select(&Selector::parse("div").unwrap())
+            .next()
+            .unwrap();
+        let mut ch = ChapterBuilder::new();
+        ch.title_set("status");
+        let pcfg = ProcessConfig {
+            br_is_paragraph: false,
+            links: LinkPolicy::Internal,
+            rules: &[],
+        };
+        add_basic(&mut ch, el, &OverrideSet::empty(), &pcfg);
+        let ch = ch.finish().unwrap();
+        assert_eq!(
+            ch[0].md().to_string(),
+            "# status\n\nStatus\n\n| Stat | Value |\n| --- | --- |\n| HP | **10** |\n| no skills |  |"
+        );
+    }
+
+    #[test]
+    fn links_follow_policy() {
+        let html = Html::parse_fragment(
+            r##"<p>see <a href="/wiki?id=1">the <i>wiki</i></a>, <a href="#tl1">[1]</a></p>"##,
+        );
+        let el = html.select(&Selector::parse("p").unwrap()).next().unwrap();
+        let md = |links, url: Option<&str>| {
+            let mut overrides = OverrideSet::empty();
+            overrides.url = url.map(|u| Url::parse(u).unwrap());
+            let mut ch = ChapterBuilder::new();
+            ch.title_set("links");
+            let pcfg = ProcessConfig {
+                br_is_paragraph: false,
+                links,
+                rules: &[],
+            };
+            add_basic(&mut ch, el, &overrides, &pcfg);
+            ch.finish().unwrap()[0].md().to_string()
+        };
+        let base = Some("https://example.com/ch/1");
+        assert_eq!(
+            md(LinkPolicy::Keep, base),
+            "# links\n\nsee [the *wiki*](https://example.com/wiki?id=1), \\[1\\]"
+        );
+        assert_eq!(
+            md(LinkPolicy::Drop, base),
+            "# links\n\nsee the *wiki*, \\[1\\]"
+        );
+        // relative links can't be resolved without the page url
+        assert_eq!(
+            md(LinkPolicy::Internal, None),
+            "# links\n\nsee the *wiki*, \\[1\\]"
+        );
+    }
+
+    #[test]
+    fn link_content() {
+        fn build(html: &Html) -> ChapterBuilder<'_> {
+            let el = html.select(&Selector::parse("p").unwrap()).next().unwrap();
+            let mut overrides = OverrideSet::empty();
+            overrides.url = Some(Url::parse("https://example.com/ch/1").unwrap());
+            let mut ch = ChapterBuilder::new();
+            ch.title_set("links");
+            let pcfg = ProcessConfig {
+                br_is_paragraph: false,
+                links: LinkPolicy::Keep,
+                rules: &[],
+            };
+            add_basic(&mut ch, el, &overrides, &pcfg);
+            ch
+        }
+        // the image is kept instead of becoming an empty link
+        let html = Html::parse_fragment(r#"<p><a href="/full.jpg"><img src="/thumb.jpg"></a></p>"#);
+        assert!(build(&html).requires_resolution());
+
+        let html = Html::parse_fragment(r#"<p><a href="/kan"><ruby>漢<rt>kan</rt></ruby></a></p>"#);
+        let ch = build(&html);
+        let xml = ch.finish().unwrap()[0].xml().to_string();
+        assert!(
+            xml.contains(r#"<a href="https://example.com/kan"><ruby>漢<rt>kan</rt></ruby></a>"#),
+            "{xml}"
+        );
+    }
+
+    #[test]
+    fn footnotes() {
+        let html = Html::parse_document(concat!(
+            r##"<html><body><div><p>a pun<sup id="r1"><a href="#n1">1</a></sup> and a joke "##,
+            r##"[TLN: not funny].</p><ol><li id="n1">the <i>pun</i> <a href="#r1">↩</a></li>"##,
+            r##"</ol></div></body></html>"##,
+        ));
+        let el = html
+            .select(&Selector::parse("div").unwrap())
+            .next()
+            .unwrap();
+        let rules = [
+            Sed::new("f;sup").unwrap(),
+            Sed::new(r"f/\s*\[TLN: ([^\]]*)\]/").unwrap(),
+        ];
+        let mut ch = ChapterBuilder::new();
+        ch.title_set("notes");
+        let pcfg = ProcessConfig {
+            br_is_paragraph: false,
+            links: LinkPolicy::Internal,
+            rules: &rules,
+        };
+        add_basic(&mut ch, el, &OverrideSet::empty(), &pcfg);
+        let ch = ch.finish().unwrap();
+        assert_eq!(
+            ch[0].md().to_string(),
+            "# notes\n\na pun[^1] and a joke[^2].\n\n[^1]: the *pun*\n\n[^2]: not funny"
+        );
+    }
 }
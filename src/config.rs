@@ -0,0 +1,82 @@
+//! user-wide settings, read from `$XDG_CONFIG_HOME/wn3/config.toml` by default
+//!
+//! ```toml
+//! [crawl]
+//! delay = 30
+//! hosts = { "example.com" = 5 }
+//!
+//! [http.hosts."noc.syosetu.com"]
+//! cookies = { over18 = "yes" }
+//! ```
+//!
+//! settings in a spec win over the config file
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::def::{CrawlDef, HttpDef};
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub crawl: CrawlDef,
+    #[serde(default)]
+    pub http: HttpDef,
+}
+
+impl Config {
+    /// `$XDG_CONFIG_HOME/wn3/config.toml`, falling back to `~/.config/wn3/config.toml`
+    pub fn default_path() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|s| !s.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| Some(PathBuf::from(std::env::var_os("HOME")?).join(".config")))?;
+        Some(base.join("wn3").join("config.toml"))
+    }
+
+    /// read the config at `path`, or at the default path. Only a missing default is allowed
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => match Self::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        let f = match std::fs::read_to_string(&path) {
+            Ok(f) => f,
+            Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Config::default());
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("failed to open config {}", path.display()));
+            }
+        };
+        toml::from_str(&f).with_context(|| format!("failed to parse config {}", path.display()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load() {
+        let dir = std::env::temp_dir().join(format!("wn3-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "[crawl]\ndelay = 60\nhosts = { \"a.com\" = 1 }\n").unwrap();
+        let cfg = Config::load(Some(&path)).unwrap();
+        assert_eq!(cfg.crawl.delay, Some(60));
+        assert_eq!(cfg.crawl.hosts["a.com"], 1);
+
+        std::fs::write(&path, "[crawl]\nspeed = 60\n").unwrap();
+        assert!(Config::load(Some(&path)).is_err());
+        assert!(Config::load(Some(&dir.join("missing.toml"))).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,14 +1,23 @@
 use generate::lang::StrLang;
-use std::path::PathBuf;
+use std::{
+    collections::BTreeMap,
+    num::{NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    time::Duration,
+};
 
 use generate::lang::Lang;
 use log::warn;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::common::Rules;
+
 mod langde;
+mod ruleset;
 pub mod sed;
 mod urlsel;
+pub use ruleset::{RulesetChoice, RulesetDef};
 pub use urlsel::UrlSelection;
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
@@ -17,6 +26,9 @@ pub use urlsel::UrlSelection;
 pub struct BookDef {
     #[serde(skip)]
     pub file: Option<PathBuf>,
+    /// [`crate::checkpoint::spec_hash`] of the file
+    #[serde(skip)]
+    pub hash: u64,
 
     #[serde(deserialize_with = "langde::lang_de", default)]
     pub language: Lang,
@@ -24,7 +36,9 @@ pub struct BookDef {
     pub title: StrLang,
     #[serde(deserialize_with = "langde::strlang_de")]
     pub author: StrLang,
-    pub ruleset: Option<String>,
+    pub ruleset: Option<RulesetChoice>,
+    /// overrides the link policy of the ruleset
+    pub links: Option<LinkPolicy>,
     pub subtitle: Option<String>,
     pub homepage: Url,
     pub cover_image: Option<Url>,
@@ -34,6 +48,218 @@ pub struct BookDef {
     pub overrides: Vec<OverrideChoice>,
     #[serde(default)]
     pub sections: Vec<Section>,
+    #[serde(default)]
+    pub crawl: CrawlDef,
+    #[serde(default)]
+    pub http: HttpDef,
+    /// split the book into several epubs
+    pub volumes: Option<VolumeDef>,
+}
+
+/// how to split a book into volumes. A volume ends as soon as any of the limits is reached
+///
+/// ```toml
+/// [volumes]
+/// sections = true
+/// chapters = 100
+/// title = "{title} {n}: {section}"
+/// covers = ["https://example.com/vol1.jpg", "https://example.com/vol2.jpg"]
+/// ```
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct VolumeDef {
+    /// start a new volume with every section
+    #[serde(default)]
+    pub sections: bool,
+    /// the most chapters in a volume
+    pub chapters: Option<NonZeroUsize>,
+    /// the most megabytes of text and images in a volume, roughly. A chapter bigger than that
+    /// still gets a volume of its own
+    pub size: Option<NonZeroU64>,
+    /// title of each volume. `{title}` is the book's title, `{n}` the number of the volume
+    /// and `{section}` the section it starts in, empty before the first one
+    #[serde(default = "VolumeDef::default_title")]
+    pub title: String,
+    /// cover of each volume in order. Volumes past the end get `cover-image`
+    #[serde(default)]
+    pub covers: Vec<Url>,
+}
+
+impl VolumeDef {
+    fn default_title() -> String {
+        "{title} {n}".to_owned()
+    }
+
+    /// the title of volume `n`
+    pub fn title(&self, title: &str, n: usize, section: Option<&str>) -> String {
+        self.title
+            .replace("{title}", title)
+            .replace("{n}", &n.to_string())
+            .replace("{section}", section.unwrap_or_default())
+    }
+
+    /// the most bytes in a volume
+    pub fn max_size(&self) -> Option<u64> {
+        self.size.map(|mb| mb.get().saturating_mul(1024 * 1024))
+    }
+}
+
+/// what is sent with each request, in the spec or the config file. Hosts also cover their
+/// subdomains, and whatever the spec sets wins over the config file
+///
+/// ```toml
+/// [http]
+/// user-agent = "my-scraper/1.0"
+///
+/// [http.hosts."noc.syosetu.com"]
+/// cookies = { over18 = "yes" }
+/// headers = { Referer = "https://noc.syosetu.com/" }
+/// ```
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct HttpDef {
+    /// defaults to [`fetch::DEFAULT_USER_AGENT`]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub hosts: BTreeMap<String, HostHttpDef>,
+}
+
+/// cookies and headers for one host
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct HostHttpDef {
+    #[serde(default)]
+    pub cookies: BTreeMap<String, String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+impl HttpDef {
+    /// this, with anything unset taken from `fallback`
+    pub fn or(&self, fallback: &HttpDef) -> HttpDef {
+        let mut hosts = fallback.hosts.clone();
+        for (host, def) in &self.hosts {
+            let merged = hosts.entry(host.clone()).or_default();
+            merged.cookies.extend(def.cookies.clone());
+            merged.headers.extend(def.headers.clone());
+        }
+        HttpDef {
+            user_agent: self
+                .user_agent
+                .clone()
+                .or_else(|| fallback.user_agent.clone()),
+            hosts,
+        }
+    }
+
+    pub fn to_config(&self) -> fetch::HttpConfig {
+        let pairs = |f: fn(&HostHttpDef) -> &BTreeMap<String, String>| {
+            self.hosts
+                .iter()
+                .map(|(host, def)| {
+                    let pairs = f(def).iter().map(|(k, v)| (k.clone(), v.clone()));
+                    (host.clone(), pairs.collect())
+                })
+                .collect()
+        };
+        fetch::HttpConfig {
+            user_agent: self
+                .user_agent
+                .clone()
+                .unwrap_or_else(|| fetch::DEFAULT_USER_AGENT.to_owned()),
+            headers: pairs(|def| &def.headers),
+            cookies: pairs(|def| &def.cookies),
+        }
+    }
+}
+
+/// how politely to crawl, in the spec or the config file. Anything not set in the spec comes
+/// from the config file
+///
+/// ```toml
+/// [crawl]
+/// delay = 30
+/// robots = true
+/// hosts = { "example.com" = 5 }
+/// retries = 5
+/// backoff = 10
+/// ```
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct CrawlDef {
+    /// seconds between requests to a host, defaults to 15
+    pub delay: Option<u64>,
+    /// obey `Disallow` and `Crawl-delay` in robots.txt, defaults to false
+    pub robots: Option<bool>,
+    /// seconds between requests to particular hosts, winning over robots.txt
+    #[serde(default)]
+    pub hosts: BTreeMap<String, u64>,
+    /// times a failed request is tried again, defaults to 3
+    pub retries: Option<u32>,
+    /// seconds before the first retry, doubled for each after it. Defaults to 5
+    pub backoff: Option<u64>,
+    /// the most seconds to wait before a retry, also the longest `Retry-After` that is honored.
+    /// Defaults to 300
+    pub max_backoff: Option<u64>,
+}
+
+impl CrawlDef {
+    /// this, with anything unset taken from `fallback`
+    pub fn or(&self, fallback: &CrawlDef) -> CrawlDef {
+        let mut hosts = fallback.hosts.clone();
+        hosts.extend(self.hosts.iter().map(|(h, d)| (h.clone(), *d)));
+        CrawlDef {
+            delay: self.delay.or(fallback.delay),
+            robots: self.robots.or(fallback.robots),
+            hosts,
+            retries: self.retries.or(fallback.retries),
+            backoff: self.backoff.or(fallback.backoff),
+            max_backoff: self.max_backoff.or(fallback.max_backoff),
+        }
+    }
+
+    pub fn to_config(&self) -> fetch::CrawlConfig {
+        let default = fetch::CrawlConfig::default();
+        fetch::CrawlConfig {
+            default_delay: self
+                .delay
+                .map_or(default.default_delay, Duration::from_secs),
+            delays: self
+                .hosts
+                .iter()
+                .map(|(h, d)| (h.clone(), Duration::from_secs(*d)))
+                .collect(),
+            robots: self.robots.unwrap_or(default.robots),
+            retry: fetch::RetryPolicy {
+                attempts: self
+                    .retries
+                    .map_or(default.retry.attempts, |r| r.saturating_add(1)),
+                backoff: self
+                    .backoff
+                    .map_or(default.retry.backoff, Duration::from_secs),
+                max_backoff: self
+                    .max_backoff
+                    .map_or(default.retry.max_backoff, Duration::from_secs),
+            },
+        }
+    }
+}
+
+/// what to do with `<a>` elements in chapter text
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkPolicy {
+    /// keep links as they are
+    Keep,
+    /// only keep the text
+    Drop,
+    /// keep links, but point links to other chapters of the book inside of the book
+    #[default]
+    Internal,
 }
 
 #[derive(Debug, Deserialize, PartialEq, Eq)]
@@ -55,20 +281,134 @@ pub struct OverrideChoice {
     pub subs: Vec<sed::Sed>,
 }
 
+/// one step of the path to a value in the spec
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathSeg {
+    Key(&'static str),
+    Index(usize),
+}
+
+/// a problem found in the spec, at a path like `sections[1].start`
 #[derive(Debug)]
-#[allow(dead_code)]
-pub struct BookDefValidationError {}
+pub struct Problem {
+    pub path: Vec<PathSeg>,
+    pub message: String,
+    /// line and column (both 1-based), filled in by [`BookDefValidationError::locate`]
+    pub location: Option<(usize, usize)>,
+}
+
+impl Problem {
+    fn new(path: Vec<PathSeg>, message: impl Into<String>) -> Self {
+        Problem {
+            path,
+            message: message.into(),
+            location: None,
+        }
+    }
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some((line, col)) = self.location {
+            write!(f, "{line}:{col}: ")?;
+        }
+        for (i, seg) in self.path.iter().enumerate() {
+            match seg {
+                PathSeg::Key(k) if i == 0 => write!(f, "{k}")?,
+                PathSeg::Key(k) => write!(f, ".{k}")?,
+                PathSeg::Index(n) => write!(f, "[{n}]")?,
+            }
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+/// every problem found by [`BookDef::validate`]
+#[derive(Debug)]
+pub struct BookDefValidationError {
+    pub file: Option<PathBuf>,
+    pub problems: Vec<Problem>,
+}
+
+impl BookDefValidationError {
+    /// fill in the location of every problem from the source of the spec
+    pub fn locate(mut self, src: &str) -> Self {
+        use toml::de::{DeTable, DeValue};
+
+        let Ok(doc) = DeTable::parse(src) else {
+            return self;
+        };
+        for problem in &mut self.problems {
+            let mut span = doc.span();
+            let mut table = Some(doc.get_ref());
+            let mut array = None;
+            for seg in &problem.path {
+                let next = match (seg, table, array) {
+                    (PathSeg::Key(k), Some(t), _) => t.get(*k),
+                    (PathSeg::Index(i), _, Some(a)) => <[_]>::get(a, *i),
+                    _ => None,
+                };
+                // stop at the closest value that exists
+                let Some(next) = next else { break };
+                span = next.span();
+                (table, array) = match next.get_ref() {
+                    DeValue::Table(t) => (Some(t), None),
+                    DeValue::Array(a) => (None, Some(&**a)),
+                    _ => (None, None),
+                };
+            }
+            let before = &src[..span.start];
+            let line = before.matches('\n').count() + 1;
+            let col = before.rsplit('\n').next().unwrap_or("").chars().count() + 1;
+            problem.location = Some((line, col));
+        }
+        self
+    }
+}
 
 impl std::fmt::Display for BookDefValidationError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        std::fmt::Debug::fmt(self, f)
+        let n = self.problems.len();
+        write!(f, "{n} problem{}", if n == 1 { "" } else { "s" })?;
+        if let Some(file) = &self.file {
+            write!(f, " in {}", file.display())?;
+        }
+        for problem in &self.problems {
+            write!(f, "\n  ")?;
+            if let Some(file) = &self.file
+                && problem.location.is_some()
+            {
+                write!(f, "{}:", file.display())?;
+            }
+            write!(f, "{problem}")?;
+        }
+        Ok(())
     }
 }
 
 impl std::error::Error for BookDefValidationError {}
 
+/// whether a url from a range or a list is on the same scheme and host as `other`
+fn same_host(a: &Url, b: &Url) -> bool {
+    a.scheme() == b.scheme() && a.host_str() == b.host_str()
+}
+
 impl BookDef {
+    /// whether `url` can be reached from `content`. Any url on the host of a range might be
+    /// passed through, so only the host is compared for those
+    fn is_reachable(&self, url: &Url) -> bool {
+        self.content.iter().any(|sel| match sel {
+            UrlSelection::Range { start, end, .. } => {
+                same_host(start, url) || end.as_ref().is_some_and(|e| same_host(e, url))
+            }
+            UrlSelection::Url(u) => u == url,
+            UrlSelection::List(l) => l.contains(url),
+        })
+    }
+
     pub fn validate(&self) -> Result<(), BookDefValidationError> {
+        use PathSeg::{Index, Key};
+
         fn log_if_todo_opt(s: &Option<String>, field: &str) -> bool {
             if let Some(s) = s.as_deref() {
                 if s.eq_ignore_ascii_case("todo") {
@@ -90,15 +430,270 @@ impl BookDef {
         w |= log_if_todo_opt(&self.subtitle, "subtitle");
         w |= log_if_todo(&self.author, "author");
         w |= log_if_todo_opt(&self.translator, "translator");
+        for o in &self.overrides {
+            if let UrlSelection::Range {
+                max_chapters: Some(_),
+                ..
+            } = o.urls
+            {
+                warn!("`max-chapters` has no effect in overrides");
+                w = true;
+            }
+        }
         if w {
             match &self.file {
                 Some(file) => warn!("config file `{}` has warnings", file.display()),
                 None => warn!("book definition has warnings"),
             }
         }
-        Ok(())
+
+        let mut problems = Vec::new();
+
+        // a plain string gets the default language, so only tables are checked
+        for (field, s) in [("title", &self.title), ("author", &self.author)] {
+            if !s.no_alts() && s.for_lang(self.language).is_none() {
+                problems.push(Problem::new(
+                    vec![Key(field)],
+                    format!("has no entry for the book language `{}`", self.language),
+                ));
+            }
+        }
+
+        match &self.ruleset {
+            Some(RulesetChoice::Named(name)) if Rules::new_from_name(name).is_none() => {
+                problems.push(Problem::new(
+                    vec![Key("ruleset")],
+                    format!("unknown ruleset `{name}`"),
+                ));
+            }
+            Some(RulesetChoice::Custom(def)) => {
+                if let Err(e) = Rules::new_from_def(def) {
+                    problems.push(Problem::new(vec![Key("ruleset")], format!("{e:#}")));
+                }
+            }
+            _ => (),
+        }
+
+        for (i, sel) in self.content.iter().enumerate() {
+            if let UrlSelection::Range {
+                start,
+                end: Some(end),
+                ..
+            } = sel
+                && !same_host(start, end)
+            {
+                problems.push(Problem::new(
+                    vec![Key("content"), Index(i), Key("end")],
+                    format!("range ends on a different host than its start `{start}`"),
+                ));
+            }
+        }
+
+        for (i, section) in self.sections.iter().enumerate() {
+            if !self.is_reachable(&section.start) {
+                problems.push(Problem::new(
+                    vec![Key("sections"), Index(i), Key("start")],
+                    format!("`{}` is not in any content selection", section.start),
+                ));
+            }
+        }
+
+        for (i, o) in self.overrides.iter().enumerate() {
+            let path = |rest: &[PathSeg]| {
+                let mut p = vec![Key("overrides"), Index(i), Key("urls")];
+                p.extend_from_slice(rest);
+                p
+            };
+            let urls: Vec<(&Url, Vec<PathSeg>)> = match &o.urls {
+                UrlSelection::Range { start, end, .. } => {
+                    if let Some(end) = end
+                        && !same_host(start, end)
+                    {
+                        problems.push(Problem::new(
+                            path(&[Key("end")]),
+                            format!("range ends on a different host than its start `{start}`"),
+                        ));
+                    }
+                    std::iter::once((start, path(&[Key("start")])))
+                        .chain(end.iter().map(|e| (e, path(&[Key("end")]))))
+                        .collect()
+                }
+                UrlSelection::Url(u) => vec![(u, path(&[]))],
+                UrlSelection::List(l) => l
+                    .iter()
+                    .enumerate()
+                    .map(|(j, u)| (u, path(&[Index(j)])))
+                    .collect(),
+            };
+            for (url, path) in urls {
+                if !self.is_reachable(url) {
+                    problems.push(Problem::new(
+                        path,
+                        format!("`{url}` is not reachable from any content selection"),
+                    ));
+                }
+            }
+        }
+
+        if let Some(volumes) = &self.volumes {
+            let numbered = volumes.title.contains("{n}")
+                || (volumes.sections && volumes.title.contains("{section}"));
+            if !numbered {
+                problems.push(Problem::new(
+                    vec![Key("volumes"), Key("title")],
+                    "needs `{n}`, or `{section}` with `sections = true`, to tell volumes apart",
+                ));
+            }
+            if volumes.sections && self.sections.is_empty() {
+                problems.push(Problem::new(
+                    vec![Key("volumes"), Key("sections")],
+                    "the book has no sections",
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(BookDefValidationError {
+                file: self.file.clone(),
+                problems,
+            })
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    const SPEC: &str = r#"
+language = "ja"
+title = { en = "Title", ja = "タイトル" }
+author = { en = "Author", de = "Autor" }
+ruleset = "nonexistent"
+homepage = "https://example.com/"
+
+content = [
+    { start = "https://example.com/1", end = "https://other.com/5" },
+    { urls = ["https://list.com/1", "https://list.com/2"] },
+]
+
+[[sections]]
+title = "Arc 1"
+start = "https://example.com/3"
+
+[[sections]]
+title = "Arc 2"
+start = "https://list.com/3"
+
+[[overrides]]
+urls = ["https://list.com/2", "https://nowhere.com/"]
+title = "Bad"
+"#;
+
+    #[test]
+    fn reports_every_problem() {
+        let def: BookDef = toml::from_str(SPEC).unwrap();
+        let err = def.validate().unwrap_err().locate(SPEC);
+        let msgs: Vec<_> = err.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(
+            msgs,
+            [
+                "4:10: author: has no entry for the book language `ja`",
+                "5:11: ruleset: unknown ruleset `nonexistent`",
+                "9:46: content[0].end: range ends on a different host than its start \
+                 `https://example.com/1`",
+                "19:9: sections[1].start: `https://list.com/3` is not in any content selection",
+                "22:31: overrides[0].urls[1]: `https://nowhere.com/` is not reachable from any \
+                 content selection",
+            ]
+        );
+    }
+
+    #[test]
+    fn valid() {
+        let spec = r#"
+title = "Title"
+author = "Author"
+homepage = "https://example.com/"
+content = [{ start = "https://example.com/1", end = "https://example.com/5" }]
+sections = [{ title = "Arc", start = "https://example.com/3" }]
+"#;
+        let def: BookDef = toml::from_str(spec).unwrap();
+        def.validate().unwrap();
+    }
+
+    #[test]
+    fn volumes() {
+        let spec = r#"
+title = "Title"
+author = "Author"
+homepage = "https://example.com/"
+content = [{ start = "https://example.com/1" }]
+
+[volumes]
+sections = true
+size = 5
+title = "{title}: {section}"
+"#;
+        let def: BookDef = toml::from_str(spec).unwrap();
+        let err = def.validate().unwrap_err().locate(spec);
+        let msgs: Vec<_> = err.problems.iter().map(|p| p.to_string()).collect();
+        assert_eq!(msgs, ["8:12: volumes.sections: the book has no sections"]);
+
+        let vol = def.volumes.unwrap();
+        assert_eq!(vol.title("Title", 2, Some("Arc")), "Title: Arc");
+        assert_eq!(vol.max_size(), Some(5 * 1024 * 1024));
+        let vol: VolumeDef = toml::from_str("chapters = 50").unwrap();
+        assert_eq!(vol.title("Title", 2, None), "Title 2");
+        // `{section}` alone only tells volumes apart when they start at sections
+        let def: BookDef = toml::from_str(&spec.replace("sections = true", "")).unwrap();
+        let err = def.validate().unwrap_err();
+        assert_eq!(err.problems.len(), 1);
+        assert_eq!(
+            err.problems[0].path,
+            [PathSeg::Key("volumes"), PathSeg::Key("title")]
+        );
+    }
+
+    #[test]
+    fn crawl_falls_back() {
+        let global: CrawlDef =
+            toml::from_str("delay = 60\nrobots = true\nhosts = { \"a.com\" = 1, \"b.com\" = 2 }")
+                .unwrap();
+        let spec: CrawlDef =
+            toml::from_str("robots = false\nretries = 0\nhosts = { \"b.com\" = 5 }").unwrap();
+        let cfg = spec.or(&global).to_config();
+        assert_eq!(cfg.default_delay, Duration::from_secs(60));
+        assert!(!cfg.robots);
+        assert_eq!(cfg.delays["a.com"], Duration::from_secs(1));
+        assert_eq!(cfg.delays["b.com"], Duration::from_secs(5));
+        assert_eq!(cfg.retry.attempts, 1);
+        assert_eq!(
+            CrawlDef::default().to_config(),
+            fetch::CrawlConfig::default()
+        );
+    }
+
+    #[test]
+    fn http_merges_hosts() {
+        let global: HttpDef = toml::from_str(
+            r#"
+user-agent = "global/1"
+hosts."a.com" = { cookies = { over18 = "yes", lang = "en" }, headers = { X-A = "1" } }
+"#,
+        )
+        .unwrap();
+        let spec: HttpDef = toml::from_str(r#"hosts."a.com".cookies = { lang = "ja" }"#).unwrap();
+        let cfg = spec.or(&global).to_config();
+        assert_eq!(cfg.user_agent, "global/1");
+        let pair = |k: &str, v: &str| (k.to_owned(), v.to_owned());
+        assert_eq!(
+            cfg.cookies["a.com"],
+            [pair("lang", "ja"), pair("over18", "yes")]
+        );
+        assert_eq!(cfg.headers["a.com"], [pair("X-A", "1")]);
+        assert_eq!(HttpDef::default().to_config(), fetch::HttpConfig::default());
+    }
+}
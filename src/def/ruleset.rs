@@ -0,0 +1,157 @@
+use serde::{
+    Deserialize,
+    de::{self, Visitor, value::MapAccessDeserializer},
+};
+
+use super::{LinkPolicy, sed};
+
+/// either the name of a built-in ruleset or a ruleset defined in the spec
+#[derive(Debug, PartialEq, Eq)]
+pub enum RulesetChoice {
+    Named(String),
+    Custom(RulesetDef),
+}
+
+/// a ruleset defined entirely in the spec
+///
+/// ```toml
+/// [ruleset]
+/// title-selector = "h1.entry-title"
+/// body-selector = "div.entry-content > *"
+/// next-selector = "a[rel=next]"
+/// exclude = [";.sharedaddy", ";p/Next Chapter/"]
+/// scene-separator = ['^\s*◇([^◇]*)◇\s*$']
+/// links = "drop"
+/// footnotes = ['f/\[TLN: ([^\]]*)\]/']
+/// ```
+#[derive(Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[serde(deny_unknown_fields)]
+pub struct RulesetDef {
+    /// selector for the element the title is taken from, defaults to `head > title`
+    pub title_selector: Option<String>,
+    /// regex applied to the title. If it has a capture group, the first group is the title
+    pub title_regex: Option<String>,
+    /// selector for every top-level element in the body
+    pub body_selector: String,
+    /// selector for the link to the next chapter, the first match is used
+    pub next_selector: Option<String>,
+    /// hsed matchers (e.g. `;.sharedaddy` or `;p/Next Chapter/`) for elements to skip
+    #[serde(default)]
+    pub exclude: Vec<sed::Sed>,
+    /// regexes for scene separators. If it has a capture group, the first group is the scene
+    /// title
+    #[serde(default)]
+    pub scene_separator: Vec<String>,
+    /// what to do with links in the body, defaults to `internal`
+    #[serde(default)]
+    pub links: LinkPolicy,
+    /// hsed footnote directives (e.g. `f;sup` or `f/\[TLN: ([^\]]*)\]/`)
+    #[serde(default)]
+    pub footnotes: Vec<sed::Sed>,
+}
+
+struct RulesetChoiceVisitor;
+
+impl<'de> Deserialize<'de> for RulesetChoice {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(RulesetChoiceVisitor)
+    }
+}
+
+impl<'de> Visitor<'de> for RulesetChoiceVisitor {
+    type Value = RulesetChoice;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a ruleset name or a ruleset table")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        Ok(RulesetChoice::Named(v.to_owned()))
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        let def = RulesetDef::deserialize(MapAccessDeserializer::new(map))?;
+        if let Some(bad) = def.exclude.iter().find(|s| !s.is_matcher()) {
+            return Err(de::Error::invalid_value(
+                de::Unexpected::Str(&bad.to_string()),
+                &"an hsed matcher (no directive)",
+            ));
+        }
+        if let Some(bad) = def.footnotes.iter().find(|s| !s.is_footnote()) {
+            return Err(de::Error::invalid_value(
+                de::Unexpected::Str(&bad.to_string()),
+                &"an hsed footnote directive (`f`)",
+            ));
+        }
+        Ok(RulesetChoice::Custom(def))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq, Eq)]
+    #[serde(deny_unknown_fields)]
+    struct Test {
+        ruleset: RulesetChoice,
+    }
+
+    #[test]
+    fn de_named() {
+        let actual: Test = toml::from_str(r#"ruleset = "generic""#).unwrap();
+        assert_eq!(actual.ruleset, RulesetChoice::Named("generic".into()));
+    }
+
+    #[test]
+    fn de_table() {
+        let s = r#"
+        [ruleset]
+        title-selector = "h1"
+        body-selector = "div.content > p"
+        exclude = [";.ad", ";p/Next/"]
+        scene-separator = ['^\*+$']
+        links = "keep"
+        footnotes = ["f;sup"]
+        "#;
+        let actual: Test = toml::from_str(s).unwrap();
+        let expected = RulesetDef {
+            title_selector: Some("h1".into()),
+            title_regex: None,
+            body_selector: "div.content > p".into(),
+            next_selector: None,
+            exclude: vec![
+                sed::Sed::new(";.ad").unwrap(),
+                sed::Sed::new(";p/Next/").unwrap(),
+            ],
+            scene_separator: vec![r"^\*+$".into()],
+            links: LinkPolicy::Keep,
+            footnotes: vec![sed::Sed::new("f;sup").unwrap()],
+        };
+        assert_eq!(actual.ruleset, RulesetChoice::Custom(expected));
+    }
+
+    #[test]
+    fn de_fail() {
+        // missing body
+        toml::from_str::<Test>("ruleset.title-selector = \"h1\"").unwrap_err();
+        // unknown field
+        toml::from_str::<Test>("ruleset = { body-selector = \"p\", foo = 1 }").unwrap_err();
+        // directive in exclude
+        toml::from_str::<Test>("ruleset = { body-selector = \"p\", exclude = [\"d;p\"] }")
+            .unwrap_err();
+        // not a footnote directive
+        toml::from_str::<Test>("ruleset = { body-selector = \"p\", footnotes = [\";sup\"] }")
+            .unwrap_err();
+    }
+}
@@ -17,8 +17,17 @@
 //!
 //! `P;p.intro/TLN/`
 //! = "print the tree of elements matching selector `p.intro` with any text matching `TLN`"
+//!
+//! `f;span.note`
+//! = "turn all elements matching selector `span.note` into footnotes". If the element links to
+//! another element on the page (like `<sup><a href="#fn1">1</a></sup>`), the footnote is the
+//! linked element instead.
+//!
+//! `f/\[TLN: ([^\]]*)\]/`
+//! = "turn all text matching `\[TLN: ([^\]]*)\]` into footnotes". If there is a capture group,
+//! the footnote is the first group
 
-use std::{borrow::Cow, str::FromStr};
+use std::{borrow::Cow, ops::Range, str::FromStr};
 
 use ahash::{HashSet, HashSetExt};
 use anyhow::{Context, anyhow, bail, ensure};
@@ -40,6 +49,7 @@ enum Op {
     Print,
     PrintAll,
     Replace(Box<str>),
+    Footnote,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -89,6 +99,17 @@ impl Sed {
         matches!(self.op, Op::PrintAll | Op::Print)
     }
 
+    #[must_use]
+    pub fn is_footnote(&self) -> bool {
+        matches!(self.op, Op::Footnote)
+    }
+
+    /// footnote directive that applies to whole elements rather than text
+    #[must_use]
+    pub fn is_footnote_el(&self) -> bool {
+        self.is_footnote() && self.reg.is_none()
+    }
+
     #[must_use]
     pub fn is_matcher(&self) -> bool {
         matches!(self.op, Op::Match)
@@ -224,6 +245,19 @@ impl Sed {
         }
     }
 
+    /// finds the first footnote in `s`, returning the range of the whole match and the range of
+    /// the footnote body. Only meaningful for text footnote directives
+    #[must_use]
+    pub fn footnote_match(&self, s: &str) -> Option<(Range<usize>, Range<usize>)> {
+        if !self.is_footnote() {
+            return None;
+        }
+        let c = self.reg.as_ref()?.captures(s)?;
+        let whole = c.get(0).unwrap();
+        let body = c.get(1).unwrap_or(whole);
+        Some((whole.range(), body.range()))
+    }
+
     /// applies self to html completely. Note this is *super* expensive, since limitations with the
     /// api means we have to fully serialize and reparse
     pub fn apply_full_expensive(&self, html: &mut Html) {
@@ -235,7 +269,9 @@ impl Sed {
         match self.op {
             Op::Delete => self.apply_deletes_expensive(html),
             Op::Replace(_) => self.apply_subs_expensive(html),
-            Op::Match | Op::Print | Op::PrintAll => unreachable!("non destructive operations"),
+            Op::Match | Op::Print | Op::PrintAll | Op::Footnote => {
+                unreachable!("non destructive operations")
+            }
         }
     }
 
@@ -379,7 +415,7 @@ fn parse_sed(s: &str) -> SedParseRes {
     let mut ch = s.char_indices().peekable();
     let &(_i, opk) = ch.peek().context("empty string")?;
     ensure!(
-        "sdpP/;".contains(opk),
+        "sdpPf/;".contains(opk),
         "'{opk}' is not a valid sed operation"
     );
     let i;
@@ -461,6 +497,7 @@ fn parse_sed(s: &str) -> SedParseRes {
         's' => Op::Replace(rep.unwrap()),
         'p' => Op::Print,
         'P' => Op::PrintAll,
+        'f' => Op::Footnote,
         ';' | '/' => Op::Match,
         _ => unreachable!("invalid op character ('{opk}') should have been filtered earlier"),
     };
@@ -488,6 +525,7 @@ impl std::fmt::Display for Sed {
                 Op::Print => 'p',
                 Op::PrintAll => 'P',
                 Op::Replace(_) => 's',
+                Op::Footnote => 'f',
             };
             write!(f, "{op}")?;
         }
@@ -603,7 +641,7 @@ mod tests {
     fn parse_fail() {
         let cases = vec![
             "", "/", "s/", "s/./", "d///", "d//", "s", "d", "p/", "p//", "p;div", "P/", "ds//",
-            "sd///", "d/(/", "d /(/", "d;/./", "d;",
+            "sd///", "d/(/", "d /(/", "d;/./", "d;", "f", "f;",
         ];
         let res: Vec<_> = cases
             .into_iter()
@@ -628,6 +666,8 @@ mod tests {
             "d;div/./",
             "d;div",
             "P;div",
+            "f;sup",
+            r"f/\[TLN: ([^\]]*)\]/",
         ];
         let res: Vec<_> = cases
             .into_iter()
@@ -653,6 +693,17 @@ mod tests {
         case(" TLN", "s/^TLN//", " TLN");
     }
 
+    #[test]
+    fn footnote_match() {
+        let sed = Sed::new(r"f/\s*\[TLN: ([^\]]*)\]/").unwrap();
+        assert!(sed.is_footnote() && !sed.is_footnote_el());
+        let s = "a joke [TLN: a pun] here";
+        let (whole, body) = sed.footnote_match(s).unwrap();
+        assert_eq!(&s[whole], " [TLN: a pun]");
+        assert_eq!(&s[body], "a pun");
+        assert!(Sed::new("f;sup").unwrap().is_footnote_el());
+    }
+
     #[test]
     fn idempotent_display() {
         #[track_caller]
@@ -663,6 +714,7 @@ mod tests {
         }
         case("d;div.entry-content > p:not(:nth-of-type(4) ~ *)/enjoy/");
         case("s;div.entry-content > p:not(:nth-of-type(4) ~ *)/enjoy/goodbye/");
+        case("f;sup > a");
     }
 
     #[test]
@@ -1,3 +1,5 @@
+use std::num::NonZeroUsize;
+
 use serde::{
     Deserialize,
     de::{self, Visitor},
@@ -6,7 +8,13 @@ use url::Url;
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum UrlSelection {
-    Range { start: Url, end: Url },
+    Range {
+        start: Url,
+        /// `None` (no `end`, or `end = "latest"`) follows next links until none remain
+        end: Option<Url>,
+        /// stop after this many chapters, even if `end` was not reached
+        max_chapters: Option<NonZeroUsize>,
+    },
     Url(Url),
     List(Vec<Url>),
 }
@@ -21,6 +29,24 @@ impl UrlSelection {
     }
 }
 
+/// the end of a range, either a url or `latest`
+struct RangeEnd(Option<Url>);
+
+impl<'de> Deserialize<'de> for RangeEnd {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        if s == "latest" {
+            return Ok(RangeEnd(None));
+        }
+        Url::parse(&s).map(|u| RangeEnd(Some(u))).map_err(|_| {
+            de::Error::invalid_value(de::Unexpected::Str(&s), &"a valid url or `latest`")
+        })
+    }
+}
+
 struct UrlSelVisitor;
 
 impl<'de> Deserialize<'de> for UrlSelection {
@@ -73,11 +99,14 @@ impl<'de> Visitor<'de> for UrlSelVisitor {
         enum Field {
             Start,
             End,
+            #[serde(rename = "max-chapters")]
+            MaxChapters,
             Url,
             Urls,
         }
         let mut start = None;
-        let mut end = None;
+        let mut end: Option<RangeEnd> = None;
+        let mut max_chapters = None;
         let mut url = None;
         let mut urls = None;
         while let Some(key) = map.next_key::<Field>()? {
@@ -100,11 +129,21 @@ impl<'de> Visitor<'de> for UrlSelVisitor {
                     }
                     end = Some(map.next_value()?);
                 }
+                Field::MaxChapters => {
+                    if max_chapters.is_some() {
+                        return Err(de::Error::duplicate_field("max-chapters"));
+                    }
+                    if urls.is_some() || url.is_some() {
+                        return Err(de::Error::duplicate_field("url range"));
+                    }
+                    max_chapters = Some(map.next_value()?);
+                }
                 Field::Url => {
                     if url.is_some() {
                         return Err(de::Error::duplicate_field("url"));
                     }
-                    if urls.is_some() || start.is_some() || end.is_some() {
+                    if urls.is_some() || start.is_some() || end.is_some() || max_chapters.is_some()
+                    {
                         return Err(de::Error::duplicate_field("url range"));
                     }
                     url = Some(map.next_value()?);
@@ -113,18 +152,25 @@ impl<'de> Visitor<'de> for UrlSelVisitor {
                     if urls.is_some() {
                         return Err(de::Error::duplicate_field("urls"));
                     }
-                    if url.is_some() || start.is_some() || end.is_some() {
+                    if url.is_some() || start.is_some() || end.is_some() || max_chapters.is_some() {
                         return Err(de::Error::duplicate_field("url range"));
                     }
                     urls = Some(map.next_value()?);
                 }
             }
         }
-        match (start, end) {
-            (Some(start), Some(end)) => return Ok(UrlSelection::Range { start, end }),
-            (None, None) => (),
-            (None, Some(_)) => return Err(de::Error::missing_field("start")),
-            (Some(_), None) => return Err(de::Error::missing_field("end")),
+        match start {
+            Some(start) => {
+                return Ok(UrlSelection::Range {
+                    start,
+                    end: end.and_then(|e| e.0),
+                    max_chapters,
+                });
+            }
+            None if end.is_some() || max_chapters.is_some() => {
+                return Err(de::Error::missing_field("start"));
+            }
+            None => (),
         }
         if let Some(url) = url {
             return Ok(UrlSelection::Url(url));
@@ -164,7 +210,16 @@ mod tests {
     fn urlr(s: RangeInclusive<&str>) -> UrlSelection {
         UrlSelection::Range {
             start: Url::parse(s.start()).unwrap(),
-            end: Url::parse(s.end()).unwrap(),
+            end: Some(Url::parse(s.end()).unwrap()),
+            max_chapters: None,
+        }
+    }
+
+    fn urlr_open(s: &str, max: Option<usize>) -> UrlSelection {
+        UrlSelection::Range {
+            start: Url::parse(s).unwrap(),
+            end: None,
+            max_chapters: max.map(|m| m.try_into().unwrap()),
         }
     }
 
@@ -274,6 +329,37 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn de_open_range() {
+        let s = r#"
+        content = [
+            { start = "https://example.com/0" },
+            { start = "https://example.com/1", end = "latest", max-chapters = 10 },
+        ]
+        "#;
+        let actual: Test = toml::from_str(s).unwrap();
+        let expected = Test {
+            content: vec![
+                urlr_open("https://example.com/0", None),
+                urlr_open("https://example.com/1", Some(10)),
+            ],
+        };
+        assert_eq!(actual, expected);
+
+        // max-chapters without start
+        toml::from_str::<Test>("content = [{ max-chapters = 10 }]").unwrap_err();
+        toml::from_str::<Test>(
+            r#"content = [{ url = "https://example.com/0", max-chapters = 10 }]"#,
+        )
+        .unwrap_err();
+        toml::from_str::<Test>(
+            r#"content = [{ start = "https://example.com/0", max-chapters = 0 }]"#,
+        )
+        .unwrap_err();
+        toml::from_str::<Test>(r#"content = [{ start = "https://example.com/0", end = "x" }]"#)
+            .unwrap_err();
+    }
+
     #[test]
     fn de_fail_dup() {
         let s = r#"
@@ -1,4 +1,6 @@
+pub mod checkpoint;
 pub mod common;
+pub mod config;
 pub mod def;
 
 pub mod overrides;
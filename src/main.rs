@@ -1,16 +1,33 @@
-use std::path::PathBuf;
+use std::{
+    collections::VecDeque,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
 use ahash::HashMap;
 use anyhow::{Context, Result, bail, ensure};
-use clap::{ArgAction, Parser, ValueEnum};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
 use common::Rules;
-use def::BookDef;
-use fetch::FetchContext;
-use generate::{EpubBuilder, image::Image};
+use config::Config;
+use def::{BookDef, RulesetChoice, VolumeDef};
+use fetch::{CacheLocation, CookieJar, FetchContext, ObjectCache, Revalidate};
+use generate::{
+    Chapter, ChapterBuilder, EpubBuilder,
+    epub::EpubStream,
+    image::Image,
+    lang::{Lang, StrLang},
+};
 use log::{debug, error, info, warn};
 use scraper::Html;
+use serde::Serialize;
 use url::Url;
-use wn3::{def::Section, overrides::OverrideTracker, *};
+use wn3::{
+    checkpoint::{Checkpoint, Checkpoints},
+    def::Section,
+    overrides::OverrideTracker,
+    *,
+};
 
 mod logger;
 
@@ -20,31 +37,135 @@ const EXAMPLE_CFG: &str = include_str!("example.toml");
 
 #[derive(Parser, Debug)]
 struct Args {
+    #[arg(short, global = true, group = "verbosity", action = ArgAction::Count)]
+    verbose: u8,
+
+    /// don't print any warnings or errors
+    #[arg(short, long, global = true, group = "verbosity")]
+    quiet: bool,
+
+    /// cache database, or `:memory:` to keep nothing.
+    /// Defaults to `$XDG_CACHE_HOME/wn3/cache.db`
+    #[arg(long, global = true, env = "WN3_CACHE", value_name = "PATH")]
+    cache: Option<String>,
+
+    /// config file. Defaults to `$XDG_CONFIG_HOME/wn3/config.toml`
+    #[arg(long, global = true, env = "WN3_CONFIG", value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// build an epub from a spec
+    Build(BuildArgs),
+    /// parse and validate a spec without fetching anything
+    Check(SpecArgs),
+    /// fetch every chapter of a spec into the cache without generating anything
+    Fetch(CrawlArgs),
+    /// write each chapter as markdown
+    Dump(DumpArgs),
+    /// inspect and purge the cache
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// write example spec to stdout
+    Example,
+}
+
+#[derive(clap::Args, Debug)]
+struct SpecArgs {
     /// input toml file
-    #[arg(short_alias = 'i', alias = "spec", required_unless_present = "example")]
+    #[arg(value_name = "SPEC", required_unless_present = "spec_flag")]
     spec: Option<PathBuf>,
 
-    /// write example spec to stdout and then exit
+    /// input toml file, same as SPEC
+    #[arg(
+        short = 'i',
+        long = "spec",
+        value_name = "SPEC",
+        conflicts_with = "spec"
+    )]
+    spec_flag: Option<PathBuf>,
+}
+
+impl SpecArgs {
+    fn path(&self) -> &Path {
+        self.spec
+            .as_deref()
+            .or(self.spec_flag.as_deref())
+            .expect("spec is required")
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct CrawlArgs {
+    #[command(flatten)]
+    spec: SpecArgs,
+
+    /// don't make any web requests
     #[arg(long)]
-    example: bool,
+    offline: bool,
 
-    #[arg(short, long, default_value = "output.epub")]
-    output: PathBuf,
+    /// treat the last content entry as open-ended (`end = "latest"`) to pick up new chapters
+    #[arg(short, long)]
+    update: bool,
 
-    #[arg(short, group = "verbosity", action = ArgAction::Count)]
-    verbose: u8,
+    /// when to check cached pages for changes: `never`, `always`, or after a number of days
+    #[arg(long, default_value = "never", value_parser = parse_revalidate)]
+    revalidate: Revalidate,
 
-    /// don't print any warnings or errors
-    #[arg(short, long, group = "verbosity")]
-    quiet: bool,
+    /// threads fetching pages and images ahead of time, one host at a time each. 0 fetches
+    /// everything in order
+    #[arg(short, long, default_value_t = 4, value_name = "N")]
+    jobs: usize,
 
-    /// write each chapter as markdown to stdout
+    /// walk every range from its start, ignoring how far earlier runs of the same spec got.
+    /// Otherwise `fetch` skips the pages an earlier run got through
     #[arg(long)]
-    dump: bool,
+    restart: bool,
 
-    /// don't make any web requests
-    #[arg(long)]
-    offline: bool,
+    /// with `build`, follow the pages an earlier run of the same spec went through instead of
+    /// their next links, up to where it stopped. The pages are still parsed for their chapters
+    #[arg(long, conflicts_with = "restart")]
+    reuse_chain: bool,
+
+    /// put a placeholder chapter in place of each page that could not be built and carry on
+    /// with its next page, or with the next range if the page could not be fetched at all. The
+    /// failures are written to `--report`, and it still exits with an error
+    #[arg(short, long)]
+    keep_going: bool,
+
+    /// where `--keep-going` lists the failures, as JSON
+    #[arg(long, default_value = "failures.json", value_name = "PATH")]
+    report: PathBuf,
+}
+
+fn parse_revalidate(s: &str) -> Result<Revalidate, String> {
+    match s {
+        "never" => Ok(Revalidate::Never),
+        "always" => Ok(Revalidate::Always),
+        days => {
+            let days: u64 = days.parse().map_err(|_| {
+                format!("expected `never`, `always` or a number of days, got `{s}`")
+            })?;
+            Ok(Revalidate::OlderThan(Duration::from_secs(
+                days * 24 * 60 * 60,
+            )))
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+struct BuildArgs {
+    #[command(flatten)]
+    crawl: CrawlArgs,
+
+    /// where the epub is written. A book split into volumes is written next to it, numbered
+    /// like `output-01.epub`
+    #[arg(short, long, default_value = "output.epub")]
+    output: PathBuf,
 
     /// run `epubcheck` on the output
     #[arg(short, long)]
@@ -53,6 +174,64 @@ struct Args {
     /// compression used for zip content
     #[arg(short = 'z', long, default_value_t = Compression::Deflate)]
     compression: Compression,
+
+    /// write chapters into the epub as they are built instead of keeping all of them in memory,
+    /// for very long books
+    #[arg(long)]
+    stream: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct DumpArgs {
+    #[command(flatten)]
+    crawl: CrawlArgs,
+
+    /// write one file per chapter into this directory instead of stdout
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// list cached urls with their size, type and age
+    List {
+        /// only list urls starting with this
+        prefix: Option<String>,
+        /// only list urls on this host
+        #[arg(long)]
+        host: Option<String>,
+    },
+    /// write a cached body to stdout
+    Show {
+        url: String,
+        /// show an earlier version, as listed by `history`
+        #[arg(long)]
+        version: Option<i64>,
+    },
+    /// list earlier versions of a cached url
+    History { url: String },
+    /// make an earlier version of a cached url current again
+    Rollback { url: String, version: i64 },
+    /// remove cached urls starting with a prefix, older than some number of days, or both
+    #[command(group = clap::ArgGroup::new("what").required(true).multiple(true))]
+    Purge {
+        #[arg(group = "what")]
+        prefix: Option<String>,
+        /// remove entries fetched more than this many days ago. With a prefix, only those
+        /// under it. Entries from before fetch times were recorded are kept
+        #[arg(long, value_name = "DAYS", group = "what")]
+        older_than: Option<u64>,
+    },
+    /// shrink the cache file after purging
+    Vacuum,
+    /// write cached urls into a directory, to be imported elsewhere
+    Export {
+        dir: PathBuf,
+        /// only export urls starting with this
+        prefix: Option<String>,
+    },
+    /// read a directory written by `export`
+    Import { dir: PathBuf },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -84,140 +263,756 @@ fn main() -> Result<()> {
         log::set_max_level(log::LevelFilter::Off);
     }
 
-    if args.example {
-        println!("{EXAMPLE_CFG}");
-        return Ok(());
+    let loc = cache_location(args.cache.as_deref())?;
+    let config = Config::load(args.config.as_deref())?;
+    match &args.command {
+        Command::Build(args) => build(args, &loc, &config),
+        Command::Check(args) => {
+            let def = read_spec(args.path())?;
+            rules_for(&def)?;
+            info!("{} is valid", args.path().display());
+            Ok(())
+        }
+        Command::Fetch(args) => {
+            let (mut def, rules) = load_spec(args.spec.path())?;
+            let fetch = open_fetch(args, &loc, &config, &def)?;
+            let mut report = Report::default();
+            crawl(
+                args,
+                &loc,
+                &mut def,
+                rules,
+                fetch,
+                &mut Discard,
+                &mut report,
+            )?;
+            report.finish(&args.report)
+        }
+        Command::Dump(args) => {
+            let (mut def, rules) = load_spec(args.crawl.spec.path())?;
+            let fetch = open_fetch(&args.crawl, &loc, &config, &def)?;
+            if let Some(dir) = &args.output {
+                std::fs::create_dir_all(dir)
+                    .with_context(|| format!("could not create {}", dir.display()))?;
+            }
+            let mut out = Dump {
+                dir: args.output.as_deref(),
+                n: 0,
+            };
+            let mut report = Report::default();
+            crawl(
+                &args.crawl,
+                &loc,
+                &mut def,
+                rules,
+                fetch,
+                &mut out,
+                &mut report,
+            )?;
+            report.finish(&args.crawl.report)
+        }
+        Command::Cache(cmd) => cache(cmd, &loc),
+        Command::Example => {
+            println!("{EXAMPLE_CFG}");
+            Ok(())
+        }
     }
-
-    build(&args)
 }
 
-fn build(args: &Args) -> Result<()> {
-    let spec = args
-        .spec
-        .as_deref()
-        .expect("spec is required if build is called");
+fn read_spec(spec: &Path) -> Result<BookDef> {
     let f = std::fs::read_to_string(spec)
         .with_context(|| format!("failed to open spec {}", spec.display()))?;
-    let def = {
-        let mut def: BookDef = toml::from_str(&f).context("failed to parse spec")?;
-        def.file = Some(spec.into());
-        def
+    let mut def: BookDef = toml::from_str(&f).context("failed to parse spec")?;
+    def.file = Some(spec.into());
+    def.hash = checkpoint::spec_hash(&f);
+    def.validate()
+        .map_err(|e| e.locate(&f))
+        .context("spec invalid")?;
+    Ok(def)
+}
+
+fn rules_for(def: &BookDef) -> Result<Rules> {
+    let rules = match &def.ruleset {
+        None => Rules::new_from_name("generic").expect("generic ruleset exists"),
+        Some(RulesetChoice::Named(ruleset)) => {
+            let Some(rules) = Rules::new_from_name(ruleset) else {
+                bail!("Unknown ruleset: `{ruleset}`")
+            };
+            rules
+        }
+        Some(RulesetChoice::Custom(ruleset)) => Rules::new_from_def(ruleset)?,
     };
-    def.validate().context("spec invalid")?;
-    let rules = {
-        let Some(ruleset) = def.ruleset.as_deref() else {
-            todo!("default ruleset")
-        };
-        let Some(rules) = Rules::new_from_name(ruleset) else {
-            bail!("Unknown ruleset: `{ruleset}`")
-        };
-        rules
+    Ok(rules)
+}
+
+fn load_spec(spec: &Path) -> Result<(BookDef, Rules)> {
+    let def = read_spec(spec)?;
+    let rules = rules_for(&def)?;
+    Ok((def, rules))
+}
+
+/// `--cache`, then the default location. A `cache.db` in the working directory is still used,
+/// since that is where it used to be
+fn cache_location(arg: Option<&str>) -> Result<CacheLocation> {
+    if let Some(arg) = arg {
+        return Ok(CacheLocation::from_arg(arg));
+    }
+    let default = CacheLocation::default_path();
+    let legacy = Path::new("cache.db");
+    if legacy.is_file() {
+        if let Some(default) = &default {
+            warn!(
+                "using cache.db in the working directory, move it to {} or pass --cache",
+                default.display()
+            );
+        }
+        return Ok(CacheLocation::File(legacy.into()));
+    }
+    let Some(default) = default else {
+        bail!("could not find a cache directory, pass --cache or set WN3_CACHE")
     };
-    let mut book = generate::EpubBuilder::new();
-    let conn = rusqlite::Connection::open("cache.db")?;
+    Ok(CacheLocation::File(default))
+}
+
+fn open_fetch(
+    args: &CrawlArgs,
+    loc: &CacheLocation,
+    config: &Config,
+    def: &BookDef,
+) -> Result<FetchContext> {
     let client = ureq::AgentBuilder::new()
         .https_only(true)
-        .user_agent("wn-scraper3/0.0.1 (+https://github.com/gfaster)")
+        .redirects(0)
         .build();
-    let fetch = FetchContext::new_cfg(conn, client, args.offline).unwrap();
-    book.set_title(def.title)
-        .add_author(def.author)
-        .add_identifier(generate::epub::IdentifierType::Url, def.homepage.as_str())
-        .set_language(def.language);
-
-    let compress = match args.compression {
-        Compression::Store => generate::epub::Compression::Store,
-        Compression::Deflate => generate::epub::Compression::Deflate,
-    };
-    book.set_compression(compress);
+    let mut fetch = FetchContext::new_cfg(loc.open()?, client, args.offline)?;
+    fetch.revalidate = args.revalidate;
+    fetch.crawl = def.crawl.or(&config.crawl).to_config();
+    fetch.http = def.http.or(&config.http).to_config();
+    fetch.set_cookie_jar(CookieJar::new(loc.open()?).context("failed to open the cookie jar")?);
+    fetch.start_prefetch(args.jobs);
+    Ok(fetch)
+}
+
+fn build(args: &BuildArgs, loc: &CacheLocation, config: &Config) -> Result<()> {
+    let (mut def, rules) = load_spec(args.crawl.spec.path())?;
+    let fetch = open_fetch(&args.crawl, loc, config, &def)?;
 
-    if let Some(tl) = def.translator {
-        book.add_translator(tl);
-    }
     let mut has_failed = false;
+    let mut report = Report::default();
 
-    if let Some(cover) = def.cover_image {
-        if let Err(e) = book
-            .set_cover(Image::new(cover.as_str()), &fetch)
-            .context("could not set cover")
-        {
+    // fetched ahead so a broken cover is noticed before crawling, and left out if it is
+    let mut check_cover = |cover: &Url| match fetch.fetch(cover).context("could not set cover") {
+        Ok(_) => Some(cover.clone()),
+        Err(e) => {
             error!("{e:?}");
-            has_failed = true;
+            if args.crawl.keep_going {
+                report.failures.push(Failure::new(cover.clone(), &e, None));
+            } else {
+                has_failed = true;
+            }
+            None
+        }
+    };
+    let cover = def.cover_image.as_ref().and_then(&mut check_cover);
+    let covers = def
+        .volumes
+        .iter()
+        .flat_map(|v| &v.covers)
+        .map(|c| check_cover(c).or_else(|| cover.clone()))
+        .collect();
+
+    let mut out = Volumes::new(args, &def, fetch.clone(), cover, covers);
+    if let Err(e) = crawl(
+        &args.crawl,
+        loc,
+        &mut def,
+        rules,
+        fetch,
+        &mut out,
+        &mut report,
+    ) {
+        error!("{e:?}");
+        has_failed = true;
+    }
+    if has_failed {
+        out.abort();
+        bail!("aborting due to previous failures")
+    }
+    let written = out.finish().context("failed writing epub")?;
+    check(args, &written)?;
+
+    report.finish(&args.crawl.report)
+}
+
+fn cache(cmd: &CacheCommand, loc: &CacheLocation) -> Result<()> {
+    let cache = ObjectCache::new(loc.open()?)?;
+    match cmd {
+        CacheCommand::List { prefix, host } => {
+            for e in cache.list(prefix.as_deref().unwrap_or(""))? {
+                if let Some(host) = host
+                    && Url::parse(&e.url).ok().as_ref().and_then(Url::host_str) != Some(host)
+                {
+                    continue;
+                }
+                let age = e.fetched.map_or_else(|| "?".to_owned(), ago);
+                println!("{:>10} {:<5} {age:>8} {}", e.len, e.ty.extension(), e.url);
+            }
+        }
+        CacheCommand::Show { url, version } => {
+            use std::io::Write;
+
+            let found = match version {
+                Some(v) => cache.get_version(url, *v)?,
+                None => cache.get_bytes(url)?,
+            };
+            let Some((_, bytes)) = found else {
+                bail!("{url} is not cached")
+            };
+            std::io::stdout().write_all(&bytes)?;
+        }
+        CacheCommand::History { url } => {
+            for v in cache.history(url)? {
+                let replaced = ago(v.replaced);
+                println!(
+                    "{:>6} {:>10} {:<5} replaced {replaced}",
+                    v.id,
+                    v.len,
+                    v.ty.extension()
+                );
+            }
+        }
+        CacheCommand::Rollback { url, version } => {
+            ensure!(
+                cache.rollback(url, *version)?,
+                "{url} has no version {version}"
+            );
+            info!("rolled back {url} to version {version}");
+        }
+        CacheCommand::Purge { prefix, older_than } => {
+            let prefix = prefix.as_deref().unwrap_or("");
+            let n = match older_than {
+                Some(days) => {
+                    let cutoff = days
+                        .checked_mul(24 * 60 * 60)
+                        .and_then(|secs| {
+                            std::time::SystemTime::now().checked_sub(Duration::from_secs(secs))
+                        })
+                        .with_context(|| format!("{days} days ago is too far back"))?;
+                    cache.purge_before(prefix, cutoff)?
+                }
+                None => cache.purge(prefix)?,
+            };
+            info!("removed {n} entries");
+        }
+        CacheCommand::Vacuum => cache.vacuum()?,
+        CacheCommand::Export { dir, prefix } => {
+            let n = cache.export(prefix.as_deref().unwrap_or(""), dir)?;
+            info!("exported {n} entries to {}", dir.display());
+        }
+        CacheCommand::Import { dir } => {
+            let n = cache.import(dir)?;
+            info!("imported {n} entries from {}", dir.display());
+        }
+    }
+    Ok(())
+}
+
+/// how long ago `t` was, roughly
+fn ago(t: std::time::SystemTime) -> String {
+    let secs = t.elapsed().unwrap_or_default().as_secs();
+    match secs {
+        ..3600 => format!("{}m ago", secs / 60),
+        3600..86400 => format!("{}h ago", secs / 3600),
+        _ => format!("{}d ago", secs / 86400),
+    }
+}
+
+/// where chapters go once they are built
+trait Output<'a> {
+    fn add_section(&mut self, title: &str);
+    fn add_chapters(&mut self, chapters: Vec<Chapter<'a>>) -> Result<()>;
+
+    /// whether chapters an earlier run already built are wanted again
+    fn needs_every_chapter(&self) -> bool {
+        true
+    }
+}
+
+/// the epubs of `build`, one per volume, or just one if the spec does not ask for volumes
+struct Volumes<'c> {
+    args: &'c BuildArgs,
+    fetch: FetchContext,
+    split: Option<VolumeDef>,
+    title: StrLang,
+    author: StrLang,
+    translator: Option<String>,
+    language: Lang,
+    homepage: Url,
+    cover: Option<Url>,
+    /// cover of each volume, falling back to `cover`
+    covers: Vec<Option<Url>>,
+    /// the volume being filled. Each volume is only started with its first chapter
+    current: Option<Volume>,
+    /// volumes started so far
+    n: usize,
+    /// sections that go before the next chapter
+    sections: Vec<String>,
+    /// the latest section, for the title of the next volume
+    section: Option<String>,
+    /// a section started and volumes split at sections
+    at_section: bool,
+    /// `(part, path)` of each finished volume, moved into place once all of them are done
+    done: Vec<(PathBuf, PathBuf)>,
+}
+
+struct Volume {
+    book: VolumeBook,
+    /// written to while building, and renamed to `path` at the end
+    part: PathBuf,
+    path: PathBuf,
+    chapters: usize,
+    size: u64,
+}
+
+enum VolumeBook {
+    Whole(Box<EpubBuilder<'static>>),
+    Stream(Box<EpubStream<'static, std::fs::File>>),
+}
+
+/// `book.epub` becomes `book-01.epub`, `book-02.epub` and so on
+fn volume_path(output: &Path, n: usize) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let mut name = format!("{stem}-{n:02}");
+    if let Some(ext) = output.extension() {
+        name = format!("{name}.{}", ext.to_string_lossy());
+    }
+    output.with_file_name(name)
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    part.into()
+}
+
+impl<'c> Volumes<'c> {
+    fn new(
+        args: &'c BuildArgs,
+        def: &BookDef,
+        fetch: FetchContext,
+        cover: Option<Url>,
+        covers: Vec<Option<Url>>,
+    ) -> Self {
+        Volumes {
+            args,
+            fetch,
+            split: def.volumes.clone(),
+            title: def.title.clone(),
+            author: def.author.clone(),
+            translator: def.translator.clone(),
+            language: def.language,
+            homepage: def.homepage.clone(),
+            cover,
+            covers,
+            current: None,
+            n: 0,
+            sections: Vec::new(),
+            section: None,
+            at_section: false,
+            done: Vec::new(),
+        }
+    }
+
+    /// whether a chapter of `size` bytes goes into a new volume
+    fn is_full(&self, size: u64) -> bool {
+        let (Some(split), Some(v)) = (&self.split, &self.current) else {
+            return false;
+        };
+        (split.sections && self.at_section)
+            || split.chapters.is_some_and(|max| v.chapters >= max.get())
+            || split
+                .max_size()
+                .is_some_and(|max| v.chapters > 0 && v.size + size > max)
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.n += 1;
+        let n = self.n;
+        let mut book = EpubBuilder::new();
+        book.set_compression(match self.args.compression {
+            Compression::Store => generate::epub::Compression::Store,
+            Compression::Deflate => generate::epub::Compression::Deflate,
+        })
+        .add_author(self.author.clone())
+        .set_language(self.language);
+        if let Some(tl) = &self.translator {
+            book.add_translator(tl.clone());
+        }
+        let (path, cover) = match &self.split {
+            None => {
+                book.set_title(self.title.clone())
+                    .add_identifier(generate::epub::IdentifierType::Url, self.homepage.as_str());
+                (self.args.output.clone(), self.cover.as_ref())
+            }
+            Some(split) => {
+                info!(target: "progress", "starting volume {n}");
+                let section = self.section.as_deref();
+                let mut titles = self.title.iter();
+                let (lang, first) = titles.next().expect("a title has at least one language");
+                let mut title = StrLang::new(lang, split.title(first, n, section));
+                for (lang, alt) in titles {
+                    title.set_alt(lang, split.title(alt, n, section));
+                }
+                let native = self.title.for_lang(self.language).unwrap_or(first);
+                let mut id = self.homepage.clone();
+                id.set_fragment(Some(&format!("volume-{n}")));
+                book.set_title(title)
+                    .set_collection(native, n)
+                    .add_identifier(generate::epub::IdentifierType::Url, id.as_str());
+                let cover = match self.covers.get(n - 1) {
+                    Some(cover) => cover.as_ref(),
+                    None => self.cover.as_ref(),
+                };
+                (volume_path(&self.args.output, n), cover)
+            }
+        };
+        if let Some(cover) = cover {
+            book.set_cover(Image::new(cover.as_str()), &self.fetch)
+                .context("could not set cover")?;
+        }
+        let part = part_path(&path);
+        let book = if self.args.stream {
+            let file = std::fs::File::create(&part)
+                .with_context(|| format!("could not create {}", part.display()))?;
+            VolumeBook::Stream(Box::new(book.stream(file).context("failed writing epub")?))
+        } else {
+            VolumeBook::Whole(Box::new(book))
+        };
+        self.current = Some(Volume {
+            book,
+            part,
+            path,
+            chapters: 0,
+            size: 0,
+        });
+        self.at_section = false;
+        Ok(())
+    }
+
+    /// write out the current volume, if any
+    fn finish_volume(&mut self) -> Result<()> {
+        let Some(v) = self.current.take() else {
+            return Ok(());
+        };
+        info!(target: "progress", "writing to {}", v.path.display());
+        match v.book {
+            VolumeBook::Whole(book) => {
+                let mut file = std::fs::File::create(&v.part)
+                    .with_context(|| format!("could not open {}", v.part.display()))?;
+                book.finish(&mut file).context("could not write to file")?;
+            }
+            VolumeBook::Stream(out) => out.finish().context("could not write to file")?,
+        }
+        self.done.push((v.part, v.path));
+        Ok(())
+    }
+
+    /// write out the last volume and move every volume into place
+    fn finish(mut self) -> Result<Vec<PathBuf>> {
+        self.finish_volume()?;
+        ensure!(!self.done.is_empty(), "no chapters were built");
+        let mut written = Vec::new();
+        for (part, path) in self.done {
+            std::fs::rename(&part, &path)
+                .with_context(|| format!("could not move epub to {}", path.display()))?;
+            written.push(path);
+        }
+        Ok(written)
+    }
+
+    /// remove everything written so far
+    fn abort(mut self) {
+        let current = self.current.take().map(|v| v.part);
+        for part in self.done.into_iter().map(|(part, _)| part).chain(current) {
+            if let Err(e) = std::fs::remove_file(&part)
+                && e.kind() != std::io::ErrorKind::NotFound
+            {
+                warn!("could not remove {}: {e}", part.display());
+            }
+        }
+    }
+}
+
+impl Output<'static> for Volumes<'_> {
+    fn add_section(&mut self, title: &str) {
+        self.sections.push(title.to_owned());
+        self.section = Some(title.to_owned());
+        self.at_section = true;
+    }
+
+    fn add_chapters(&mut self, chapters: Vec<Chapter<'static>>) -> Result<()> {
+        for ch in chapters {
+            let size = (ch.size() + ch.resources_size()) as u64;
+            if self.is_full(size) {
+                self.finish_volume()?;
+            }
+            if self.current.is_none() {
+                self.start()?;
+            }
+            let v = self.current.as_mut().expect("volume was just started");
+            let sections = self.sections.drain(..);
+            match &mut v.book {
+                VolumeBook::Whole(book) => {
+                    for s in sections {
+                        book.add_section(s);
+                    }
+                    book.add_chapter(ch);
+                }
+                VolumeBook::Stream(out) => {
+                    for s in sections {
+                        out.add_section(s);
+                    }
+                    out.add_chapter(ch).context("failed writing chapters")?;
+                }
+            }
+            v.chapters += 1;
+            v.size += size;
         }
+        Ok(())
+    }
+}
+
+/// markdown to stdout, or one file per chapter
+struct Dump<'p> {
+    dir: Option<&'p Path>,
+    n: usize,
+}
+
+impl Output<'_> for Dump<'_> {
+    fn add_section(&mut self, _title: &str) {}
+
+    fn add_chapters(&mut self, chapters: Vec<Chapter<'_>>) -> Result<()> {
+        for ch in chapters {
+            self.n += 1;
+            match self.dir {
+                Some(dir) => {
+                    let path = dir.join(format!("{:04}.md", self.n));
+                    std::fs::write(&path, ch.md().to_string())
+                        .with_context(|| format!("could not write {}", path.display()))?;
+                }
+                None => println!("{}\n", ch.md()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// only fetches, for warming the cache
+struct Discard;
+
+impl Output<'_> for Discard {
+    fn add_section(&mut self, _title: &str) {}
+
+    fn add_chapters(&mut self, _chapters: Vec<Chapter<'_>>) -> Result<()> {
+        Ok(())
+    }
+
+    fn needs_every_chapter(&self) -> bool {
+        false
+    }
+}
+
+/// a page, or the cover, that could not be built
+#[derive(Serialize)]
+struct Failure {
+    url: Url,
+    /// the error followed by its causes
+    errors: Vec<String>,
+    /// the overrides in effect for the page
+    overrides: Option<serde_json::Value>,
+}
+
+impl Failure {
+    fn new(url: Url, e: &anyhow::Error, overrides: Option<&overrides::OverrideSet>) -> Self {
+        Failure {
+            url,
+            errors: e.chain().map(ToString::to_string).collect(),
+            overrides: overrides.and_then(|o| serde_json::to_value(o).ok()),
+        }
+    }
+}
+
+/// what `--keep-going` carried on past
+#[derive(Default, Serialize)]
+struct Report {
+    failures: Vec<Failure>,
+    /// chapters built so far, to number placeholders
+    #[serde(skip)]
+    chapters: usize,
+}
+
+impl Report {
+    /// write the report to `path` if anything failed, which is then an error
+    fn finish(&self, path: &Path) -> Result<()> {
+        if self.failures.is_empty() {
+            return Ok(());
+        }
+        let file = std::fs::File::create(path)
+            .with_context(|| format!("could not create {}", path.display()))?;
+        serde_json::to_writer_pretty(std::io::BufWriter::new(file), self)
+            .with_context(|| format!("could not write {}", path.display()))?;
+        let n = self.failures.len();
+        bail!(
+            "{n} page{} could not be built, see {}",
+            if n == 1 { "" } else { "s" },
+            path.display()
+        )
+    }
+
+    /// note that the page at `url` failed, and build the placeholder to put in its place
+    fn fail<'a>(
+        &mut self,
+        url: &Url,
+        e: &anyhow::Error,
+        overrides: &overrides::OverrideSet<'_>,
+    ) -> Result<Vec<Chapter<'a>>> {
+        error!("{e:?}");
+        self.failures
+            .push(Failure::new(url.clone(), e, Some(overrides)));
+        placeholder(self.chapters + 1, url, e)
     }
+}
 
-    let sections: HashMap<_, _> = def
-        .sections
+/// a chapter standing in for the page at `url`, numbered `n`
+fn placeholder<'a>(n: usize, url: &Url, e: &anyhow::Error) -> Result<Vec<Chapter<'a>>> {
+    let mut ch = ChapterBuilder::new();
+    ch.title_set(format!("Chapter {n} could not be built"))
+        .source_set(url.clone())
+        .add_text(format!("{e:#}"))
+        .paragraph_finish()
+        .add_text("Source: ")
+        .add_link(url.clone(), url.to_string());
+    ch.finish().context("could not build placeholder")
+}
+
+/// walk every content entry of `def`, sending chapters to `out`. With `--keep-going`, whatever
+/// fails goes into `report` instead
+fn crawl<'a>(
+    args: &CrawlArgs,
+    loc: &CacheLocation,
+    def: &mut BookDef,
+    rules: Rules,
+    fetch: FetchContext,
+    out: &mut impl Output<'a>,
+    report: &mut Report,
+) -> Result<()> {
+    let sections: HashMap<_, _> = std::mem::take(&mut def.sections)
         .into_iter()
         .map(|Section { title, start }| (start, title))
         .collect();
-    let mut overrides = OverrideTracker::new(def.overrides);
+    let mut overrides = OverrideTracker::new(std::mem::take(&mut def.overrides));
+    overrides.set_links(def.links);
+
+    // everything known up front, the rest is only found by following next links
+    let content = std::mem::take(&mut def.content);
+    fetch.prefetch(
+        content
+            .iter()
+            .flat_map(|entry| match entry {
+                def::UrlSelection::Range { start, .. } => std::slice::from_ref(start),
+                def::UrlSelection::Url(url) => std::slice::from_ref(url),
+                def::UrlSelection::List(list) => list.as_slice(),
+            })
+            .chain(sections.keys())
+            .cloned(),
+    );
 
     let cx = ProgCx {
         fetch,
         rules,
         sections,
-        args,
+        checkpoints: Checkpoints::new(loc.open()?, &def.homepage, def.hash)
+            .context("failed to open checkpoints")?,
+        resume: !args.restart,
+        reuse_chain: args.reuse_chain,
+        keep_going: args.keep_going,
     };
 
     info!(target: "progress", "building chapters");
-    for entry in def.content {
-        match entry {
-            def::UrlSelection::Range { start, end } => {
-                if let Err(e) = fetch_range(&cx, &mut book, start, end, &mut overrides) {
-                    error!("{e:?}");
-                    has_failed = true;
-                }
-            }
-            def::UrlSelection::Url(url) => {
-                if let Err(e) = fetch_range(&cx, &mut book, url.clone(), url, &mut overrides) {
+    let entries = content.len();
+    let mut last = None;
+    let mut has_failed = false;
+    for (i, entry) in content.into_iter().enumerate() {
+        let follows = matches!(entry, def::UrlSelection::Range { .. });
+        let mut ranges = match entry {
+            def::UrlSelection::Range {
+                start,
+                end,
+                max_chapters,
+            } => vec![(start, end, max_chapters)],
+            def::UrlSelection::Url(url) => vec![(url.clone(), Some(url), None)],
+            def::UrlSelection::List(list) => list
+                .into_iter()
+                .map(|u| (u.clone(), Some(u), None))
+                .collect(),
+        };
+        // only the last entry can grow
+        if args.update
+            && i + 1 == entries
+            && let Some(last) = ranges.last_mut()
+        {
+            last.1 = None;
+        }
+        for (start, end, max_chapters) in ranges {
+            // only ranges that follow next links are worth resuming, like the one `--update`
+            // leaves open
+            let checkpoint = (follows || end.is_none()).then_some(i);
+            let range = Range {
+                start,
+                end,
+                max_chapters,
+                checkpoint,
+            };
+            match fetch_range(&cx, out, range, &mut overrides, report) {
+                Ok(reached) => last = Some(reached),
+                // the rest would go to a book that is already broken
+                Err(e) if e.is::<WriteFailed>() => return Err(e),
+                Err(e) => {
                     error!("{e:?}");
                     has_failed = true;
                 }
             }
-            def::UrlSelection::List(list) => {
-                for url in list {
-                    if let Err(e) = fetch_range(&cx, &mut book, url.clone(), url, &mut overrides) {
-                        error!("{e:?}");
-                        has_failed = true;
-                    }
-                }
-            }
         }
     }
 
     if has_failed {
-        bail!("aborting due to previous failures")
+        bail!("some chapters could not be built")
     }
 
-    finish(book, args).context("failed writing epub")?;
+    if args.update
+        && let Some(last) = last
+    {
+        info!("the last chapter is now at {last}");
+    }
 
     Ok(())
 }
 
-fn finish(book: EpubBuilder, args: &Args) -> anyhow::Result<()> {
-    info!(target: "progress", "writing to {}", args.output.display());
-    let mut outfile = std::fs::OpenOptions::new()
-        .write(true)
-        .read(false)
-        .truncate(true)
-        .create(true)
-        .open(&args.output)
-        .with_context(|| format!("could not open {}", args.output.display()))?;
-    book.finish(&mut outfile)
-        .context("could not write to file")?;
+/// run `epubcheck` on each written epub if asked to
+fn check(args: &BuildArgs, written: &[PathBuf]) -> Result<()> {
     if args.check {
-        info!(target: "progress", "running epubcheck");
-        if let Ok(res) = generate::epubcheck::epubcheck(&args.output) {
-            res.as_result(generate::epubcheck::Severity::Error)?;
-            if let Err(e) = res.as_result(generate::epubcheck::Severity::Usage) {
-                warn!("epubcheck warnings");
-                warn!("{e}");
+        for path in written {
+            info!(target: "progress", "running epubcheck on {}", path.display());
+            if let Ok(res) = generate::epubcheck::epubcheck(path) {
+                res.as_result(generate::epubcheck::Severity::Error)?;
+                if let Err(e) = res.as_result(generate::epubcheck::Severity::Usage) {
+                    warn!("epubcheck warnings");
+                    warn!("{e}");
+                }
+            } else {
+                error!("could not run epubcheck");
+                bail!("could not run epubcheck")
             }
-        } else {
-            error!("could not run epubcheck");
-            bail!("could not run epubcheck")
         }
     } else {
         debug!("epubcheck is disabled")
@@ -225,80 +1020,230 @@ fn finish(book: EpubBuilder, args: &Args) -> anyhow::Result<()> {
     Ok(())
 }
 
-struct ProgCx<'a> {
+struct ProgCx {
     fetch: FetchContext,
     rules: Rules,
     sections: HashMap<Url, String>,
-    args: &'a Args,
+    checkpoints: Checkpoints,
+    /// pick up from checkpoints left by earlier runs
+    resume: bool,
+    /// follow the pages of earlier runs instead of their next links
+    reuse_chain: bool,
+    /// put placeholders in place of pages that fail
+    keep_going: bool,
 }
 
-fn fetch_range(
-    cx: &ProgCx,
-    book: &mut EpubBuilder<'_>,
+/// context of errors adding chapters to the output, which end the crawl even with `--keep-going`
+#[derive(Debug)]
+struct WriteFailed;
+
+impl std::fmt::Display for WriteFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        "failed adding chapters".fmt(f)
+    }
+}
+
+struct Range {
     start: Url,
-    end: Url,
+    end: Option<Url>,
+    max_chapters: Option<NonZeroUsize>,
+    /// the content entry to keep a checkpoint for
+    checkpoint: Option<usize>,
+}
+
+fn fetch_range<'a>(
+    cx: &ProgCx,
+    out: &mut impl Output<'a>,
+    range: Range,
     track: &mut OverrideTracker,
-) -> anyhow::Result<()> {
-    ensure!(
-        start.scheme() == end.scheme(),
-        "start and end must be on the same scheme"
-    );
-    ensure!(
-        start.host_str() == end.host_str(),
-        "start and end must be on the same host"
-    );
+    report: &mut Report,
+) -> anyhow::Result<Url> {
+    let Range {
+        start,
+        end,
+        max_chapters,
+        checkpoint: entry,
+    } = range;
+    if let Some(end) = &end {
+        ensure!(
+            start.scheme() == end.scheme(),
+            "start and end must be on the same scheme"
+        );
+        ensure!(
+            start.host_str() == end.host_str(),
+            "start and end must be on the same host"
+        );
+    }
+    let host = start.host_str().map(str::to_owned);
+    let mut remaining = max_chapters.map(NonZeroUsize::get);
     let mut prev = None;
+    let mut chain = Vec::new();
+    let mut chapters = 0;
     let mut curr = start;
+    // the next page of each page left to replay from an earlier run
+    let mut known = VecDeque::new();
+    let mut resumed = false;
+
+    let earlier = match entry {
+        Some(entry) if cx.resume => cx.checkpoints.get(entry).unwrap_or_else(|e| {
+            warn!("could not read checkpoint of {curr}, starting over: {e:#}");
+            None
+        }),
+        _ => None,
+    };
+    if let Some(earlier) = earlier
+        && earlier.chain.first().unwrap_or(&earlier.url) == &curr
+    {
+        // the pages are cached, but fetching them all at once is still quicker
+        cx.fetch
+            .prefetch(earlier.chain.iter().chain([&earlier.url]).cloned());
+        // `fetch` has no use for the chapters of pages it already got, unless they may change
+        if !out.needs_every_chapter() && cx.fetch.revalidate == Revalidate::Never {
+            info!(
+                "resuming at {} after {} pages from an earlier run",
+                earlier.url,
+                earlier.chain.len()
+            );
+            Checkpoint {
+                url: curr,
+                chain,
+                chapters,
+            } = earlier;
+            prev = chain.last().cloned();
+            if let Some(remaining) = &mut remaining {
+                *remaining = remaining.saturating_sub(chapters);
+            }
+            resumed = true;
+        } else if cx.reuse_chain {
+            info!(
+                "following {} pages from an earlier run up to {}",
+                earlier.chain.len(),
+                earlier.url
+            );
+            known.extend(earlier.chain.into_iter().skip(1));
+            known.push_back(earlier.url);
+            resumed = true;
+        }
+    }
+    if let Some(entry) = entry
+        && !resumed
+    {
+        let checkpoint = Checkpoint {
+            url: curr.clone(),
+            chain: Vec::new(),
+            chapters: 0,
+        };
+        if let Err(e) = cx.checkpoints.set(entry, &checkpoint) {
+            warn!("could not save checkpoint at {curr}: {e:#}");
+        }
+    }
+
     loop {
         if let Some(section) = cx.sections.get(&curr) {
-            book.add_section(section);
+            out.add_section(section);
         }
         ensure!(
             curr.scheme() == "https" || curr.scheme() == "file",
             "url {curr} does not have expected scheme"
         );
-        let (ty, val) = cx.fetch.fetch(&curr).context("failed fetching")?;
-        ensure!(ty == fetch::MediaType::Html, "{ty:?} is of wrong type");
-        let html = std::str::from_utf8(&val).context("not valid utf-8")?;
-        let html = Html::parse_document(html);
-        let html = Box::leak(Box::new(html));
         let overrides = track.with_url(&curr);
-        let (ch, next) = cx
-            .rules
-            .parse_with_overrides(html, &overrides, Some(&cx.fetch))
-            .with_context(|| format!("failed to build chapter {curr}"))?;
-        let next = if let Some(next) = next {
-            Some(Url::parse(&next).context("invalid url")?)
-        } else {
-            None
+        let load = |fresh: bool| -> anyhow::Result<_> {
+            let revalidate = if fresh {
+                Revalidate::Always
+            } else {
+                cx.fetch.revalidate
+            };
+            let (ty, html) = cx
+                .fetch
+                .fetch_text_with(&curr, revalidate)
+                .context("failed fetching")?;
+            ensure!(ty == fetch::MediaType::Html, "{ty:?} is of wrong type");
+            let html = Html::parse_document(&html);
+            // looked for apart from the chapters, so a page that cannot be built still leads on
+            let next = if let Some(next) = cx.rules.next_chapter(&html) {
+                Some(curr.join(&next).context("invalid url")?)
+            } else {
+                None
+            };
+            let ch = cx
+                .rules
+                .parse_with_overrides(&html, &overrides, Some(&cx.fetch))
+                .with_context(|| format!("failed to build chapter {curr}"))
+                // the document is dropped here, rather than kept for as long as the book
+                .map(|(ch, _)| ch.into_iter().map(Chapter::into_owned).collect::<Vec<_>>());
+            Ok((ch, next))
         };
-        if cx.args.dump {
-            for ch in &ch {
-                println!("{}\n", ch.md())
+        let page = load(false).and_then(|page| match page {
+            (_, None) if end.is_none() && known.is_empty() => {
+                // the cached copy predates any newer chapters
+                debug!("refetching {curr} to look for new chapters");
+                load(true)
             }
+            page => Ok(page),
+        });
+        // the earlier run already found where this page leads
+        let replayed = known.pop_front();
+        let (ch, next) = match page {
+            Ok((ch, next)) => (ch, replayed.clone().or(next)),
+            Err(e) if cx.keep_going && replayed.is_some() => (Err(e), replayed.clone()),
+            Err(e) if cx.keep_going => {
+                // without the page there is no next link to follow
+                let e = e.context("rest of range skipped");
+                let ch = report.fail(&curr, &e, &overrides)?;
+                report.chapters += ch.len();
+                out.add_chapters(ch).context(WriteFailed)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let mut ch = match ch {
+            Ok(ch) => ch,
+            Err(e) if cx.keep_going => report.fail(&curr, &e, &overrides)?,
+            Err(e) => return Err(e),
+        };
+        if let Some(remaining) = &mut remaining {
+            ch.truncate(*remaining);
+            *remaining -= ch.len();
         }
-        book.extend_chapters(ch);
+        chapters += ch.len();
+        report.chapters += ch.len();
+        out.add_chapters(ch).context(WriteFailed)?;
         ensure!(
             prev.is_none() || prev != next,
             "url {} was repeated",
             prev.unwrap()
         );
-        if curr == end {
+        if end.as_ref() == Some(&curr) {
+            break;
+        }
+        if remaining == Some(0) {
+            debug!("reached max-chapters at {curr}");
             break;
         }
 
         let Some(next) = next else {
-            warn!("expected more urls (up until {end}) but found no next after {curr}");
+            if let Some(end) = &end {
+                warn!("expected more urls (up until {end}) but found no next after {curr}");
+            }
             break;
         };
+        if let Some(entry) = entry
+            && replayed.is_none()
+            && let Err(e) = cx
+                .checkpoints
+                .push(entry, chain.len(), &curr, &next, chapters)
+        {
+            warn!("could not save checkpoint at {next}: {e:#}");
+        }
+        chain.push(curr.clone());
         prev = Some(curr);
         ensure!(
-            next.host_str() == end.host_str(),
+            next.host_str() == host.as_deref(),
             "tried to go to different host for next url: {next}"
         );
         curr = next
     }
-    Ok(())
+    Ok(curr)
 }
 
 #[cfg(test)]
@@ -321,14 +1266,34 @@ mod tests {
 
     #[test]
     fn example_without_spec() {
-        Args::try_parse_from("prog --example".split_whitespace()).unwrap();
-        Args::try_parse_from("prog config.toml".split_whitespace()).unwrap();
+        Args::try_parse_from("prog example".split_whitespace()).unwrap();
+        Args::try_parse_from("prog build config.toml".split_whitespace()).unwrap();
+        Args::try_parse_from("prog build".split_whitespace()).unwrap_err();
     }
 
-    #[ignore = "not working yet"]
     #[test]
     fn spec_flagged() -> Result<()> {
-        Args::try_parse_from("prog -o output.epub --spec=config.toml".split_whitespace())?;
+        let args = Args::try_parse_from(
+            "prog build -o output.epub --spec=config.toml".split_whitespace(),
+        )?;
+        let Command::Build(build) = args.command else {
+            panic!("expected build")
+        };
+        assert_eq!(build.crawl.spec.path(), Path::new("config.toml"));
+        Args::try_parse_from("prog check -i config.toml".split_whitespace())?;
+        Args::try_parse_from("prog check a.toml -i b.toml".split_whitespace()).unwrap_err();
+        Ok(())
+    }
+
+    #[test]
+    fn global_flags() -> Result<()> {
+        Args::try_parse_from("prog dump -vv --offline config.toml -o out".split_whitespace())?;
+        Args::try_parse_from("prog -q cache list https://example.com".split_whitespace())?;
+        Args::try_parse_from("prog cache purge --older-than 30".split_whitespace())?;
+        Args::try_parse_from("prog cache purge".split_whitespace()).unwrap_err();
+        Args::try_parse_from("prog fetch --revalidate 7 config.toml".split_whitespace())?;
+        Args::try_parse_from("prog fetch --revalidate soon config.toml".split_whitespace())
+            .unwrap_err();
         Ok(())
     }
 }
@@ -4,11 +4,14 @@ use ahash::{HashMap, HashMapExt, HashSet};
 use log::debug;
 use url::Url;
 
-use crate::def::{self, UrlSelection, sed};
+use crate::def::{self, LinkPolicy, UrlSelection, sed};
 
 pub struct OverrideSet<'a> {
     seds: Vec<Rc<[sed::Sed]>>,
     pub title: Option<String>,
+    pub links: Option<LinkPolicy>,
+    /// url of the page, used as the base for relative links
+    pub url: Option<Url>,
     _ph: PhantomData<&'a OverrideTracker>,
 }
 
@@ -17,6 +20,8 @@ impl OverrideSet<'_> {
         OverrideSet {
             seds: Vec::new(),
             title: None,
+            links: None,
+            url: None,
             _ph: PhantomData,
         }
     }
@@ -47,10 +52,28 @@ impl std::fmt::Debug for OverrideSet<'_> {
             d.field("title", &title);
         }
 
+        if let Some(links) = &self.links {
+            d.field("links", &links);
+        }
+
         d.finish()
     }
 }
 
+/// the same fields as the `Debug` output, for failure reports
+impl serde::Serialize for OverrideSet<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut s = serializer.serialize_struct("OverrideSet", 3)?;
+        let v: Vec<_> = self.replacers().map(|r| r.to_string()).collect();
+        s.serialize_field("seds", &v)?;
+        s.serialize_field("title", &self.title)?;
+        s.serialize_field("links", &self.links)?;
+        s.end()
+    }
+}
+
 struct OverrideChoice {
     urls: UrlSelection,
     title: Option<String>,
@@ -63,6 +86,11 @@ pub struct OverrideTracker {
 
     /// activated when url key
     unactivated: HashMap<Box<str>, Vec<OverrideChoice>>,
+
+    /// active from activation onwards (ranges without an end)
+    open: Vec<OverrideChoice>,
+
+    links: Option<LinkPolicy>,
 }
 
 impl OverrideTracker {
@@ -71,12 +99,20 @@ impl OverrideTracker {
         for entry in overrides {
             let subs: Rc<[_]> = entry.subs.into();
             match entry.urls {
-                UrlSelection::Range { start, end } => {
+                UrlSelection::Range {
+                    start,
+                    end,
+                    max_chapters,
+                } => {
                     unactivated
                         .entry(start.as_str().into())
                         .or_default()
                         .push(OverrideChoice {
-                            urls: UrlSelection::Range { start, end },
+                            urls: UrlSelection::Range {
+                                start,
+                                end,
+                                max_chapters,
+                            },
                             title: None,
                             subs,
                         });
@@ -110,9 +146,17 @@ impl OverrideTracker {
         OverrideTracker {
             active: HashMap::new(),
             unactivated,
+            open: Vec::new(),
+            links: None,
         }
     }
 
+    /// link policy for every page, overriding the ruleset
+    pub fn set_links(&mut self, links: Option<LinkPolicy>) -> &mut Self {
+        self.links = links;
+        self
+    }
+
     pub fn with_url<'a>(&'a mut self, url: &Url) -> OverrideSet<'a> {
         // PERF: unnecessary clones here
         // PERF: unnecessary remove and then add for single
@@ -120,7 +164,11 @@ impl OverrideTracker {
             for entry in new_v {
                 let k: Box<str> = match &entry.urls {
                     UrlSelection::Url(url) => url.as_str().into(),
-                    UrlSelection::Range { end, .. } => end.as_str().into(),
+                    UrlSelection::Range { end: Some(end), .. } => end.as_str().into(),
+                    UrlSelection::Range { end: None, .. } => {
+                        self.open.push(entry);
+                        continue;
+                    }
                     UrlSelection::List(_) => unreachable!(),
                 };
                 self.active.entry(k).or_default().push(entry);
@@ -129,6 +177,8 @@ impl OverrideTracker {
         let mut ret = OverrideSet {
             seds: Vec::new(),
             title: None,
+            links: self.links,
+            url: Some(url.clone()),
             _ph: PhantomData,
         };
         if let Some(ending) = self.active.remove(url.as_str()) {
@@ -145,13 +195,15 @@ impl OverrideTracker {
                     UrlSelection::Url(_) => {
                         unreachable!("single urls were removed prior")
                     }
-                    UrlSelection::Range { start: _, end: _ } => {
+                    UrlSelection::Range { .. } => {
                         ret.seds.push(entry.subs.clone());
                     }
                     UrlSelection::List(_) => unreachable!(),
                 }
             }
         }
+        ret.seds
+            .extend(self.open.iter().map(|entry| entry.subs.clone()));
         if !ret.is_empty() {
             debug!("overrides for {url}: {ret:#?}");
         }
@@ -0,0 +1,207 @@
+//! ruleset compiled from a [`RulesetDef`] in the spec
+
+use std::borrow::Cow;
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use generate::Chapter;
+use log::warn;
+use regex_lite::Regex;
+use scraper::{ElementRef, Html, Selector};
+
+use crate::{
+    common::{ProcessConfig, RuleSet, add_basic},
+    def::{LinkPolicy, RulesetDef, sed::Sed},
+    overrides::OverrideSet,
+};
+
+pub struct Rule {
+    title_sel: Selector,
+    title_reg: Option<Regex>,
+    p_sel: Selector,
+    next_sel: Option<Selector>,
+    exclude: Vec<Sed>,
+    scene_sep_reg: Vec<Regex>,
+    links: LinkPolicy,
+    footnotes: Vec<Sed>,
+}
+
+fn selector(s: &str) -> Result<Selector> {
+    Selector::parse(s).map_err(|e| anyhow!("invalid selector `{s}`: {e}"))
+}
+
+fn regex(s: &str) -> Result<Regex> {
+    Regex::new(s).with_context(|| format!("invalid regex /{s}/"))
+}
+
+impl Rule {
+    pub fn new(def: &RulesetDef) -> Result<Self> {
+        for s in &def.exclude {
+            ensure!(s.is_matcher(), "exclude `{s}` must be a matcher");
+        }
+        for s in &def.footnotes {
+            ensure!(
+                s.is_footnote(),
+                "footnote `{s}` must be a footnote directive"
+            );
+        }
+        Ok(Self {
+            title_sel: selector(def.title_selector.as_deref().unwrap_or("head > title"))
+                .context("title-selector")?,
+            title_reg: def
+                .title_regex
+                .as_deref()
+                .map(regex)
+                .transpose()
+                .context("title-regex")?,
+            p_sel: selector(&def.body_selector).context("body-selector")?,
+            next_sel: def
+                .next_selector
+                .as_deref()
+                .map(selector)
+                .transpose()
+                .context("next-selector")?,
+            exclude: def.exclude.clone(),
+            scene_sep_reg: def
+                .scene_separator
+                .iter()
+                .map(|s| regex(s))
+                .collect::<Result<_>>()
+                .context("scene-separator")?,
+            links: def.links,
+            footnotes: def.footnotes.clone(),
+        })
+    }
+
+    fn simple_exclude(&self, el: &ElementRef, overrides: &OverrideSet) -> bool {
+        if overrides.should_delete(el) {
+            return true;
+        }
+        self.exclude
+            .iter()
+            .any(|s| s.is_el_match(el) || s.contains_match(el))
+    }
+
+    fn scene_sep(&self, el: &ElementRef) -> Option<String> {
+        let txt: String = el.text().collect();
+        self.scene_sep_reg.iter().find_map(|r| {
+            let c = r.captures(&txt)?;
+            let scene = c.get(1).map_or("", |m| m.as_str().trim());
+            Some(scene.to_owned())
+        })
+    }
+}
+
+impl RuleSet for Rule {
+    fn title(&self, html: &Html) -> String {
+        let Some(title) = html.select(&self.title_sel).next() else {
+            warn!("page has no title");
+            return "Chapter".to_owned();
+        };
+        let title: String = title.text().collect();
+        let Some(reg) = &self.title_reg else {
+            return title.trim().to_owned();
+        };
+        let Some(c) = reg.captures(&title) else {
+            warn!("page `{title}` does not match title regex");
+            return title.trim().to_owned();
+        };
+        c.get(1)
+            .or_else(|| c.get(0))
+            .map_or("Chapter", |m| m.as_str().trim())
+            .to_owned()
+    }
+
+    fn next_chapter<'a>(&self, html: &'a Html) -> Option<Cow<'a, str>> {
+        let el = html.select(self.next_sel.as_ref()?).next()?;
+        el.attr("href").map(Cow::Borrowed)
+    }
+
+    fn parse_multichapter_page<'a>(&self, _html: &'a Html) -> Result<Chapter<'a>> {
+        bail!("multichapter pages are not supported by the declarative ruleset")
+    }
+
+    fn parse_body<'a>(
+        &self,
+        html: &'a Html,
+        overrides: &OverrideSet,
+        ch: &mut generate::ChapterBuilder<'a>,
+    ) -> Result<()> {
+        let pcfg = ProcessConfig {
+            br_is_paragraph: false,
+            links: self.links,
+            rules: &self.footnotes,
+        };
+        let mut empty = true;
+        for el in html.select(&self.p_sel) {
+            if self.simple_exclude(&el, overrides) {
+                continue;
+            }
+            empty = false;
+            if let Some(scene) = self.scene_sep(&el) {
+                ch.add_scene_sep(scene);
+            } else {
+                add_basic(ch, el, overrides, &pcfg)
+            }
+        }
+        ensure!(!empty, "no paragraphs");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Rules;
+
+    const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Chapter 7 | Some Translations</title></head>
+<body>
+<div class="content">
+<p>Sponsored by someone</p>
+<p>first paragraph</p>
+<p>◇ Meanwhile ◇</p>
+<p>second paragraph</p>
+<p><a href="https://example.com/6">Previous</a> <a class="next" href="https://example.com/8">Next</a></p>
+</div>
+</body>
+</html>"#;
+
+    #[test]
+    fn it_works() {
+        let def = RulesetDef {
+            title_selector: None,
+            title_regex: Some(r"^(.*) \| Some Translations$".into()),
+            body_selector: "div.content > p".into(),
+            next_selector: Some("a.next".into()),
+            exclude: vec![Sed::new(";p/^Sponsored/").unwrap(), Sed::new(";a").unwrap()],
+            scene_separator: vec![r"^◇\s*(.*?)\s*◇$".into()],
+            links: LinkPolicy::Drop,
+            footnotes: Vec::new(),
+        };
+        let rules = Rules::new_from_def(&def).unwrap();
+        let html = Html::parse_document(PAGE);
+        let (ch, next) = rules.parse(&html).unwrap();
+        let t = ch[0].md().to_string();
+        assert_eq!(
+            t,
+            "# Chapter 7\n\nfirst paragraph\n\n### ◇ Meanwhile ◇\n\n\nsecond paragraph"
+        );
+        assert_eq!(next.as_deref(), Some("https://example.com/8"));
+    }
+
+    #[test]
+    fn invalid() {
+        let def = RulesetDef {
+            title_selector: None,
+            title_regex: None,
+            body_selector: "div..content".into(),
+            next_selector: None,
+            exclude: Vec::new(),
+            scene_separator: Vec::new(),
+            links: LinkPolicy::default(),
+            footnotes: Vec::new(),
+        };
+        assert!(Rules::new_from_def(&def).is_err());
+    }
+}
@@ -0,0 +1,296 @@
+//! heuristic ruleset for sites that don't have a specialized one
+//!
+//! The main content block is guessed by text density: every block gets credit for the text
+//! directly inside of it (including the text of its `<p>` children), and blocks that are mostly
+//! links are ignored. This is nowhere near as good as a hand-written ruleset, but it's good
+//! enough for most simple blogs.
+
+use std::borrow::Cow;
+
+use anyhow::{Context, Result, bail};
+use ego_tree::NodeRef;
+use generate::Chapter;
+use log::{debug, warn};
+use regex_lite::Regex;
+use scraper::{ElementRef, Html, Node, Selector};
+
+use crate::{
+    common::{ProcessConfig, RuleSet, add_basic, is_hr},
+    def::LinkPolicy,
+    overrides::OverrideSet,
+};
+
+/// blocks with a larger proportion of link text than this are considered navigation
+const MAX_LINK_DENSITY: f32 = 0.5;
+
+pub struct Rule {
+    h1_sel: Selector,
+    title_sel: Selector,
+    rel_next_sel: Selector,
+    a_sel: Selector,
+    candidate_sel: Selector,
+    exclude_sel: Selector,
+    next_text_reg: Regex,
+    nav_text_reg: Regex,
+}
+
+impl Rule {
+    pub fn new() -> Self {
+        Self {
+            h1_sel: Selector::parse("body h1").unwrap(),
+            title_sel: Selector::parse("head > title").unwrap(),
+            rel_next_sel: Selector::parse("link[rel~=next][href], a[rel~=next][href]").unwrap(),
+            a_sel: Selector::parse("a[href]").unwrap(),
+            candidate_sel: Selector::parse("body, body :is(div,article,main,section,td)").unwrap(),
+            exclude_sel: Selector::parse(
+                "script,style,noscript,iframe,form,nav,header,footer,aside,.sharedaddy,.comments",
+            )
+            .unwrap(),
+            next_text_reg: Regex::new(
+                r"(?i)^\s*(?:next(?:\s+chapter)?|次へ|次の話)\s*(?:[>»→]+\s*)?$",
+            )
+            .unwrap(),
+            nav_text_reg: Regex::new(r"(?i)(?:next|previous|prev)\s+chapter|table of contents")
+                .unwrap(),
+        }
+    }
+
+    /// `(total, link)` text length of element in chars
+    fn text_lens(el: &ElementRef) -> (usize, usize) {
+        let mut total = 0;
+        let mut link = 0;
+        for node in el.descendants() {
+            let Node::Text(t) = node.value() else {
+                continue;
+            };
+            let len = t.trim().chars().count();
+            total += len;
+            if node
+                .ancestors()
+                .take_while(|a| a.id() != el.id())
+                .any(|a| a.value().as_element().is_some_and(|e| e.name() == "a"))
+            {
+                link += len;
+            }
+        }
+        (total, link)
+    }
+
+    fn link_density(el: &ElementRef) -> f32 {
+        let (total, link) = Self::text_lens(el);
+        if total == 0 {
+            return 0.0;
+        }
+        link as f32 / total as f32
+    }
+
+    /// text directly inside of `el`, including its paragraphs
+    fn score(&self, el: &ElementRef) -> usize {
+        if el
+            .ancestors()
+            .flat_map(ElementRef::wrap)
+            .any(|a| self.exclude_sel.matches(&a))
+        {
+            return 0;
+        }
+        if Self::link_density(el) > MAX_LINK_DENSITY {
+            return 0;
+        }
+        let mut score = 0;
+        for child in el.children() {
+            match child.value() {
+                Node::Text(t) => score += t.trim().chars().count(),
+                Node::Element(e) if e.name() == "p" => {
+                    let p = ElementRef::wrap(child).unwrap();
+                    let (total, link) = Self::text_lens(&p);
+                    score += total - link;
+                }
+                _ => (),
+            }
+        }
+        score
+    }
+
+    fn content_block<'a>(&self, html: &'a Html) -> Option<ElementRef<'a>> {
+        let (score, el) = html
+            .select(&self.candidate_sel)
+            .map(|el| (self.score(&el), el))
+            // max_by_key returns the last max, but we want the outermost
+            .reduce(|acc, x| if x.0 > acc.0 { x } else { acc })?;
+        if score == 0 {
+            return None;
+        }
+        debug!(
+            "picked {:?} as content block with score {score}",
+            el.value()
+        );
+        Some(el)
+    }
+
+    fn exclude(&self, el: &ElementRef, overrides: &OverrideSet) -> bool {
+        if overrides.should_delete(el) {
+            return true;
+        }
+        if self.exclude_sel.matches(el) {
+            return true;
+        }
+        if Self::link_density(el) > MAX_LINK_DENSITY
+            && el.text().any(|t| self.nav_text_reg.is_match(t))
+        {
+            return true;
+        }
+        false
+    }
+}
+
+impl RuleSet for Rule {
+    fn title(&self, html: &Html) -> String {
+        let text = |el: ElementRef| {
+            let t: String = el.text().collect();
+            let t = t.trim();
+            (!t.is_empty()).then(|| t.to_owned())
+        };
+        html.select(&self.h1_sel)
+            .find_map(text)
+            .or_else(|| html.select(&self.title_sel).find_map(text))
+            .unwrap_or_else(|| {
+                warn!("page has no title");
+                "Chapter".to_owned()
+            })
+    }
+
+    fn next_chapter<'a>(&self, html: &'a Html) -> Option<Cow<'a, str>> {
+        if let Some(el) = html.select(&self.rel_next_sel).next() {
+            return el.attr("href").map(Cow::Borrowed);
+        }
+        let el = html.select(&self.a_sel).find(|a| {
+            let t: String = a.text().collect();
+            self.next_text_reg.is_match(&t)
+        })?;
+        el.attr("href").map(Cow::Borrowed)
+    }
+
+    fn parse_multichapter_page<'a>(&self, _html: &'a Html) -> Result<Chapter<'a>> {
+        bail!("multichapter pages are not supported by the generic ruleset")
+    }
+
+    fn parse_body<'a>(
+        &self,
+        html: &'a Html,
+        overrides: &OverrideSet,
+        ch: &mut generate::ChapterBuilder<'a>,
+    ) -> Result<()> {
+        let block = self.content_block(html).context("no content block")?;
+        let pcfg = ProcessConfig {
+            br_is_paragraph: false,
+            links: LinkPolicy::Internal,
+            rules: &[],
+        };
+        for child in block.children() {
+            match child.value() {
+                Node::Text(t) if !t.trim().is_empty() => {
+                    ch.add_text(&**t);
+                }
+                Node::Element(_) => {
+                    let el = ElementRef::wrap(child).unwrap();
+                    // the heading is almost always the title, which we already have
+                    if el.value().name() == "h1" || self.exclude(&el, overrides) {
+                        continue;
+                    }
+                    if is_hr(&el) {
+                        ch.add_separator();
+                        continue;
+                    }
+                    add_basic(ch, el, overrides, &pcfg);
+                    if is_block(child) {
+                        ch.paragraph_finish();
+                    }
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn is_block(node: NodeRef<Node>) -> bool {
+    let Some(el) = node.value().as_element() else {
+        return false;
+    };
+    matches!(
+        el.name(),
+        "p" | "div"
+            | "blockquote"
+            | "section"
+            | "article"
+            | "figure"
+            | "h1"
+            | "h2"
+            | "h3"
+            | "h4"
+            | "h5"
+            | "h6"
+            | "pre"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::Rules;
+
+    const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head><title>Chapter 3 - Some Blog</title><link rel="next" href="https://example.com/4"></head>
+<body>
+<nav><a href="/">Home</a> <a href="/about">About</a></nav>
+<div class="sidebar"><p>Recent posts</p><a href="/1">one</a> <a href="/2">two</a></div>
+<div class="post">
+<h1>Chapter 3: The Forest</h1>
+<p>The first paragraph is long enough to count as real content for the heuristic.</p>
+<p>The second paragraph is <i>also</i> long enough to count as real content.</p>
+<p><a href="/2">Previous Chapter</a> | <a href="/4">Next Chapter</a></p>
+</div>
+<footer><p>copyright</p></footer>
+</body>
+</html>"#;
+
+    #[test]
+    fn finds_content() {
+        let html = Html::parse_document(PAGE);
+        let (ch, next) = Rules::new_from_name("generic")
+            .unwrap()
+            .parse(&html)
+            .unwrap();
+        let t = ch[0].md().to_string();
+        assert!(t.starts_with("# Chapter 3: The Forest"), "{t}");
+        assert!(t.contains("The first paragraph"), "{t}");
+        assert!(t.contains("*also*"), "{t}");
+        assert!(!t.contains("Recent posts"), "{t}");
+        assert!(!t.contains("Next Chapter"), "{t}");
+        assert!(!t.contains("copyright"), "{t}");
+        assert_eq!(next.as_deref(), Some("https://example.com/4"));
+    }
+
+    #[test]
+    fn next_from_text() {
+        let rule = Rule::new();
+        let html = Html::parse_document(
+            r#"<html><body><p>text</p><a href="/3">Previous</a> <a href="/5">Next &gt;&gt;</a></body></html>"#,
+        );
+        assert_eq!(rule.next_chapter(&html).as_deref(), Some("/5"));
+
+        let html = Html::parse_document(
+            r#"<html><body><p>text</p><a href="/3">Next time</a></body></html>"#,
+        );
+        assert_eq!(rule.next_chapter(&html), None);
+    }
+
+    #[test]
+    fn title_fallback() {
+        let rule = Rule::new();
+        let html =
+            Html::parse_document("<html><head><title> Ch. 1 </title></head><body></body></html>");
+        assert_eq!(rule.title(&html), "Ch. 1");
+    }
+}
@@ -8,17 +8,22 @@ use scraper::{ElementRef, Html, Selector};
 
 use crate::{
     common::{ProcessConfig, RuleSet, add_basic, is_hr},
+    def::{LinkPolicy, sed::Sed},
     overrides::OverrideSet,
 };
 
 pub struct IlConfig {
     pub strip_fwd_tln: bool,
+    /// turn inline notes like `(TLN: ...)` into footnotes. Off by default so books keep the
+    /// notes where they were; specs can opt in with an `f` rule in an override
+    pub tln_footnotes: bool,
 }
 
 impl Default for IlConfig {
     fn default() -> Self {
         IlConfig {
             strip_fwd_tln: true,
+            tln_footnotes: false,
         }
     }
 }
@@ -30,11 +35,16 @@ pub struct Reigokai {
     title_sel: Selector,
     title_reg: Regex,
     p_sel: Selector,
+    rules: Vec<Sed>,
     cfg: IlConfig,
 }
 
 impl Reigokai {
     pub fn new_with_config(cfg: IlConfig) -> Self {
+        let mut rules = Vec::new();
+        if cfg.tln_footnotes {
+            rules.push(Sed::new(r"f/\s*[\[(](?:TLN|TL ?[Nn]ote)\s*:\s*([^\])]+)[\])]/").unwrap());
+        }
         Self {
             next_sel: Selector::parse("#main p a:last-of-type").unwrap(),
             basic_exclude_sel: Selector::parse(".sharedaddy,p a,script").unwrap(),
@@ -45,6 +55,7 @@ impl Reigokai {
             )
             .unwrap(),
             p_sel: Selector::parse("body div.entry-content > *:is(p,hr,ol,ul)").unwrap(),
+            rules,
             cfg,
         }
     }
@@ -126,6 +137,8 @@ impl RuleSet for Reigokai {
         let first = it.clone().next().context("no paragraphs")?;
         let pcfg = ProcessConfig {
             br_is_paragraph: false,
+            links: LinkPolicy::Internal,
+            rules: &self.rules,
         };
         if self.cfg.strip_fwd_tln
             && first
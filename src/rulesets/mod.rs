@@ -1,3 +1,5 @@
+pub(crate) mod declarative;
+pub(crate) mod generic;
 pub(crate) mod il;
 pub(crate) mod shikka;
 pub(crate) mod syosetu;
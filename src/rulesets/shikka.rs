@@ -6,6 +6,7 @@ use scraper::{ElementRef, Html, Selector};
 
 use crate::{
     common::{ProcessConfig, RuleSet, add_basic},
+    def::LinkPolicy,
     overrides::OverrideSet,
 };
 
@@ -85,6 +86,8 @@ impl RuleSet for Rule {
         let _ = self.cfg;
         let pcfg = ProcessConfig {
             br_is_paragraph: false,
+            links: LinkPolicy::Internal,
+            rules: &[],
         };
         let filter = |el: &ElementRef| !self.simple_exclude(el);
         let it = html.select(&self.p_sel).filter(filter);
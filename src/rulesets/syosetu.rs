@@ -8,6 +8,7 @@ use scraper::{Html, Selector};
 
 use crate::{
     common::{ProcessConfig, RuleSet, add_basic},
+    def::LinkPolicy,
     overrides::OverrideSet,
 };
 
@@ -74,6 +75,8 @@ impl RuleSet for Rule {
             .filter(|el| !overrides.should_delete(el));
         let pcfg = ProcessConfig {
             br_is_paragraph: false,
+            links: LinkPolicy::Internal,
+            rules: &[],
         };
         let mut empty = true;
         for el in it {